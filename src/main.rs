@@ -1,25 +1,77 @@
 // === Encrypted Notes Vault ===
 // A command-line app to securely store, view, and delete encrypted notes.
 // Built using:
-// - `clap` for argument parsing
+// - `clap` for argument parsing, `clap_complete` for shell completions
 // - `serde` + `serde_json` for data storage
-// - `aes-gcm` for encryption
+// - `aes-gcm` and `chacha20poly1305` for encryption
 // - `rpassword` for silent password input
-// - `sha2` for password-based key derivation
+// - `argon2` for password-based key derivation (`sha2` only to open legacy vaults)
+// - `chrono` for note timestamps
+// - `zeroize` to wipe the password and key from memory once dropped
+// - `tempfile` for private scratch files when composing notes in `$EDITOR`
+// - `arboard` (optional) for `Read --clipboard`, `directories` for `--profile` vaults
+// - `flate2` to compress long notes before encrypting them
+// - `zxcvbn` to rate new master passwords, `regex` for `Search --regex`
+// - `serde_yaml_ng` and `toml` for YAML and TOML exports (and `toml` for `vault.toml` settings)
+// - `rustyline` and `shell-words` for the interactive `vault shell`
+// - `rmp-serde` for compact MessagePack vault files
+// - `indicatif` for progress bars during long re-encryptions and imports
+// - `similar` for the line diffs of `vault diff`
+// - `totp-lite` and `base32` for the codes of `vault totp`
+// - `rustix` (Unix) to restore the terminal when a password prompt times out
+// - `unicode-normalization` so a title matches however its accents were typed
+// - `notify` to follow changes to the vault file for `Read --watch`
 
 // ----------------- Imports -----------------
-use clap::{Parser, Subcommand}; // Command-line parser
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum}; // Command-line parser
+use clap_complete::Shell; // Completion script generation
 use serde::{Deserialize, Serialize}; // For JSON serialization
+use std::borrow::Cow;
+use std::fmt;
 use std::fs::File;
-use std::io::{self, Read, Write};
+use std::io::{self, BufRead, IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, AtomicU8, AtomicU64, Ordering};
 use aes_gcm::{Aes256Gcm, KeyInit, Nonce}; // AES-GCM cipher
-use aes_gcm::aead::{Aead, OsRng, generic_array::GenericArray}; // Cryptography helpers
+use chacha20poly1305::ChaCha20Poly1305; // ChaCha20-Poly1305 cipher
+use aes_gcm::aead::{Aead, OsRng, Payload, generic_array::GenericArray}; // Cryptography helpers
 use rand::RngCore; // Secure RNG
 use base64::{engine::general_purpose, Engine as _}; // For encoding binary data
-use sha2::{Sha256, Digest}; // SHA-256 hasher
+use argon2::{Algorithm, Argon2, Params, Version}; // Memory-hard key derivation
+use sha2::{Digest, Sha256}; // Legacy (pre-Argon2) key derivation
 use rpassword::read_password; // Secure terminal input
+use chrono::{DateTime, SecondsFormat, Utc}; // Timestamps
+use zeroize::Zeroizing; // Wipes secrets on drop
+use unicode_normalization::UnicodeNormalization; // NFC for titles
 
-const VAULT_FILE: &str = "vault.json"; // The file where encrypted notes are saved
+const VAULT_FILE: &str = "vault.json"; // Default file where encrypted notes are saved
+const VAULT_FILE_ENV: &str = "VAULT_FILE"; // Environment variable overriding the default
+const NO_EMOJI_ENV: &str = "VAULT_NO_EMOJI"; // Set (to anything non-empty) for the same effect as `--no-emoji`
+const PROFILE_EXTENSION: &str = "json"; // `--profile work` lives in `<config dir>/work.json`
+const CONFIG_FILE: &str = "vault.toml"; // Settings for new keys, next to the vault or in the config directory
+
+// Default Argon2id cost parameters (OWASP-recommended minimums); `vault.toml` can change them
+const ARGON2_MEMORY_KIB: u32 = 19 * 1024; // Memory cost in KiB
+const ARGON2_ITERATIONS: u32 = 2; // Number of passes over memory
+const ARGON2_PARALLELISM: u32 = 1; // Number of lanes
+const VAULT_VERSION: u32 = 1; // Vault file format written by this binary
+const SALT_LEN: usize = 16; // Length of the per-vault salt in bytes
+const NONCE_LEN: usize = 12; // Both ciphers take a 96-bit nonce
+const PASSWORD_SYMBOLS: &str = "!#$%&()*+,-./:;<=>?@[]^_{|}~"; // No quotes, backslash or backtick, which trip up shells
+const AMBIGUOUS_CHARS: &str = "0O1lI|"; // Dropped by `gen-password --no-ambiguous`
+const AGENT_TTL_SECS: u64 = 15 * 60; // How long `vault agent` keeps a key by default
+const AGENT_SOCKET: &str = "agent.sock"; // File name of the agent's Unix socket
+const BACKUP_TIMESTAMP: &str = "%Y%m%dT%H%M%SZ"; // `vault-20250101T120000Z.json`; sorts chronologically
+const COMPRESS_THRESHOLD: usize = 1024; // Notes shorter than this (bytes) aren't worth compressing
+const PROGRESS_THRESHOLD: usize = 100; // Bulk operations on fewer notes than this finish too fast to need a progress bar
+const HISTORY_LEN: usize = 5; // Earlier versions kept per note; the oldest is dropped beyond this
+const LOCK_WAIT_SECS: u64 = 10; // How long a writer waits for another process to release the vault
+const WATCH_POLL_SECS: u64 = 1; // `Read --watch` checks the vault this often where file change events aren't available
+const WATCH_SETTLE_MS: u64 = 200; // A save is several file events; `Read --watch` waits this long for them to end
+const BINARY_MAGIC: &[u8] = b"\x89VLT"; // Starts a MessagePack vault file; never valid UTF-8, so never mistaken for JSON
+const LOCKED_MAGIC: &[u8] = b"\x89VLK"; // Starts a locked vault file: salt, Argon2 costs, nonce, then ciphertext
+const CHECK_PLAINTEXT: &str = "vault-cli password check"; // Known sentinel encrypted into the vault header
 
 // ----------------- CLI Argument Structures -----------------
 
@@ -27,175 +79,6940 @@ const VAULT_FILE: &str = "vault.json"; // The file where encrypted notes are sav
 #[derive(Parser, Debug)]
 #[command(name = "vault", about = "Manage your encrypted notes")]
 struct Args {
+    /// Path to the vault file (defaults to $VAULT_FILE, then ./vault.json); `list` and `search`
+    /// accept it several times to show the notes of several vaults together
+    #[arg(long, global = true, value_name = "PATH")]
+    vault: Vec<PathBuf>,
+
+    /// With several --vault files, ask for one password and use it for all of them
+    #[arg(long, global = true)]
+    same_password: bool,
+
+    /// Allow the vault path to be a symbolic link, and work on the file it points to
+    #[arg(long, global = true)]
+    follow_symlinks: bool,
+
+    /// Use the named vault in the per-user config directory (see `vault profiles`)
+    #[arg(long, global = true, value_name = "NAME", conflicts_with = "vault")]
+    profile: Option<String>,
+
+    /// Read the master password from the first line of stdin instead of the terminal
+    #[arg(long, global = true, conflicts_with = "password_file")]
+    password_stdin: bool,
+
+    /// Read the master password from the first line of a file instead of the terminal
+    #[arg(long, global = true, value_name = "PATH")]
+    password_file: Option<PathBuf>,
+
+    /// Give up on a password prompt nobody answers within this many seconds (default: wait)
+    #[arg(long, global = true, alias = "password-timeout", value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+    password_confirm_timeout: Option<u64>,
+
+    /// Key file required in addition to the password (set when the vault is created)
+    #[arg(long, global = true, value_name = "PATH")]
+    key_file: Option<PathBuf>,
+
+    /// Not supported: a password on the command line shows up in process listings
+    #[arg(long, global = true, hide = true, value_name = "PASSWORD")]
+    password: Option<String>,
+
+    /// Don't delete expired notes on this run (e.g. for read-only inspection)
+    #[arg(long, global = true)]
+    no_prune: bool,
+
+    /// Don't warn about a weak master password when creating a vault (for scripted setups)
+    #[arg(long, global = true)]
+    allow_weak: bool,
+
+    /// Show what a command that changes the vault would do, without writing anything
+    #[arg(long, global = true)]
+    dry_run: bool,
+
+    /// Print only errors (and output explicitly asked for, like --raw or --json)
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Also print details such as which vault file was opened
+    #[arg(short, long, global = true)]
+    verbose: bool,
+
+    /// Report a failure on stderr as a single JSON object instead of a message, for tooling
+    #[arg(long, global = true)]
+    json_errors: bool,
+
+    /// Mark messages with plain ASCII tags like `[ok]` instead of emoji (also: VAULT_NO_EMOJI=1)
+    #[arg(long, global = true)]
+    no_emoji: bool,
+
+    /// Encoding to save the vault file in from now on (the current one is kept by default)
+    #[arg(long, global = true, value_enum, value_name = "FORMAT")]
+    vault_format: Option<VaultFormat>,
+
+    /// Refuse to store a note larger than this many bytes (overrides `max_note_size` in vault.toml)
+    #[arg(long, global = true, value_name = "BYTES")]
+    max_note_size: Option<usize>,
+
+    /// Log each read, edit and delete of a note in the vault's encrypted audit log (also `audit = true` in vault.toml)
+    #[arg(long, global = true)]
+    audit: bool,
+
+    /// Cipher for notes encrypted by this command (existing notes keep theirs by default)
+    #[arg(long, global = true, value_enum)]
+    cipher: Option<Cipher>,
+
     #[command(subcommand)]
     command: VaultCommands,
 }
 
 /// Subcommands for interacting with the vault
-#[derive(Subcommand, Debug)]
+#[derive(Subcommand, Debug, Clone)]
 enum VaultCommands {
+    /// Create an empty vault, choosing (and confirming) its master password
+    Init {
+        /// Replace an existing vault with an empty one; its notes are lost
+        #[arg(long)]
+        force: bool,
+    },
     /// Add a new encrypted note
     New {
         title: String,
-        content: String,
+        /// Note content, or `-` to read it from stdin (opens $EDITOR if omitted)
+        content: Option<String>,
+        /// Compose the content in $EDITOR
+        #[arg(long, conflicts_with = "content")]
+        editor: bool,
+        /// Type the content at the terminal (several lines, ending with Ctrl-D), keeping it out of shell history
+        #[arg(long, conflicts_with_all = ["content", "editor"])]
+        prompt: bool,
+        /// Store the bytes of this file (e.g. a key or certificate) as a binary attachment
+        #[arg(long, value_name = "PATH", conflicts_with_all = ["content", "editor", "prompt"])]
+        from_file: Option<PathBuf>,
+        /// Overwrite the content of an existing note with this title
+        #[arg(long)]
+        force: bool,
+        /// Tag the note (repeatable)
+        #[arg(long = "tag", value_name = "TAG")]
+        tags: Vec<String>,
+        /// Delete the note automatically after this long, e.g. `30m`, `12h`, `7d`, `2w`
+        #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+        expires_in: Option<chrono::TimeDelta>,
+        /// Ask for the password again whenever the note is read, even with the key already unlocked
+        #[arg(long)]
+        sensitive: bool,
+        /// The content is a base32 TOTP secret (from a 2FA setup page); `totp` prints its codes
+        #[arg(long, conflicts_with = "from_file")]
+        totp: bool,
     },
     /// List decryptable note titles
-    List,
+    List(ListArgs),
+    /// List every tag in use, with how many notes carry it
+    Tags,
+    /// Replace the content of an existing note
+    Edit {
+        /// Note title (or ID with --id)
+        title: String,
+        /// Look the note up by ID instead of title
+        #[arg(long)]
+        id: bool,
+        /// New content, or `-` to read it from stdin (opens $EDITOR if omitted)
+        content: Option<String>,
+        /// Edit the current content in $EDITOR
+        #[arg(long, conflicts_with = "content")]
+        editor: bool,
+        /// Type the new content at the terminal (several lines, ending with Ctrl-D)
+        #[arg(long, conflicts_with_all = ["content", "editor"])]
+        prompt: bool,
+        /// Add the text to the end of the note, on a new line, instead of replacing it
+        #[arg(long, conflicts_with = "editor")]
+        append: bool,
+        /// Replace the note's tags (repeatable; existing tags are kept if omitted)
+        #[arg(long = "tag", value_name = "TAG")]
+        tags: Vec<String>,
+    },
     /// Read a note by its title
     Read {
+        /// Note title (or ID with --id)
+        title: String,
+        /// Look the note up by ID instead of title
+        #[arg(long)]
+        id: bool,
+        /// Print only the content, with no decoration
+        #[arg(long)]
+        raw: bool,
+        /// Write the content to a file (owner-only permissions) instead of printing it
+        #[arg(long, value_name = "PATH", conflicts_with = "raw")]
+        out: Option<PathBuf>,
+        /// Copy the content to the system clipboard instead of printing it
+        #[arg(short, long, conflicts_with_all = ["raw", "out"])]
+        clipboard: bool,
+        /// With --clipboard, wait this many seconds and then clear the clipboard
+        #[arg(long, value_name = "SECS", requires = "clipboard")]
+        clear_after: Option<u64>,
+        /// Also print line, word and character counts (just bytes for an attachment), like `wc`
+        #[arg(long)]
+        stats: bool,
+        /// After the note, list the notes it links to with `[[title]]` and whether they exist
+        #[arg(long, conflicts_with_all = ["raw", "out", "clipboard"])]
+        resolve_links: bool,
+        /// Keep running, and print the note again each time it changes in the vault file (Ctrl-C stops)
+        #[arg(short, long, conflicts_with_all = ["out", "clipboard", "resolve_links"])]
+        watch: bool,
+    },
+    /// Print the content of several notes, each under a header with its title
+    Cat {
+        /// Note titles (or IDs with --id)
+        #[arg(required = true)]
+        titles: Vec<String>,
+        /// Look the notes up by ID instead of title
+        #[arg(long)]
+        id: bool,
+    },
+    /// List the earlier versions of a note kept by `edit`
+    History {
+        /// Note title (or ID with --id)
+        title: String,
+        /// Look the note up by ID instead of title
+        #[arg(long)]
+        id: bool,
+    },
+    /// Bring back an earlier version of a note (the current one is kept in its history)
+    Revert {
+        /// Note title (or ID with --id)
+        title: String,
+        /// Look the note up by ID instead of title
+        #[arg(long)]
+        id: bool,
+        /// Version to restore, as numbered by `history` (1 is the most recent)
+        #[arg(long, default_value_t = 1, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+        version: usize,
+    },
+    /// Show a note's metadata (dates, tags, size, cipher) without its content
+    Info {
+        /// Note title (or ID with --id)
+        title: String,
+        /// Look the note up by ID instead of title
+        #[arg(long)]
+        id: bool,
+    },
+    /// Print the current 6-digit code of a TOTP note (`new --totp`)
+    Totp {
+        /// Note title (or ID with --id)
         title: String,
+        /// Look the note up by ID instead of title
+        #[arg(long)]
+        id: bool,
     },
-    /// Delete a note by its title (if it can be decrypted)
+    /// Move a note to the trash (if it can be decrypted)
     Delete {
+        /// Note title (or ID with --id)
+        title: String,
+        /// Look the note up by ID instead of title
+        #[arg(long)]
+        id: bool,
+        /// Don't ask for confirmation
+        #[arg(short, long)]
+        yes: bool,
+    },
+    /// Bring a note back out of the trash
+    Restore {
+        /// Note title (or ID with --id)
+        title: String,
+        /// Look the note up by ID instead of title
+        #[arg(long)]
+        id: bool,
+    },
+    /// Copy a note back from a backup (or any other vault file), e.g. after a permanent delete
+    Recover {
+        /// Title of the note in the backup
+        title: String,
+        /// The backup to recover it from
+        #[arg(long, value_name = "PATH")]
+        from: PathBuf,
+        /// Replace a note that already has this title (its content goes into the history)
+        #[arg(long)]
+        force: bool,
+    },
+    /// Permanently remove every note in the trash
+    EmptyTrash {
+        /// Don't ask for confirmation
+        #[arg(short, long)]
+        yes: bool,
+    },
+    /// Delete the whole vault file and every note in it, after typing DELETE to confirm
+    Purge {
+        /// Don't ask for confirmation (only together with --force)
+        #[arg(long, requires = "force")]
+        yes: bool,
+        /// Required with --yes, so one stray flag can't delete the vault
+        #[arg(long, requires = "yes")]
+        force: bool,
+        /// Overwrite the file with zeros before deleting it
+        #[arg(long)]
+        shred: bool,
+    },
+    /// Pin a note so `list` shows it first
+    Pin {
+        /// Note title (or ID with --id)
+        title: String,
+        /// Look the note up by ID instead of title
+        #[arg(long)]
+        id: bool,
+    },
+    /// Stop showing a note first in `list`
+    Unpin {
+        /// Note title (or ID with --id)
+        title: String,
+        /// Look the note up by ID instead of title
+        #[arg(long)]
+        id: bool,
+    },
+    /// List the notes that link to a title with `[[title]]`
+    Backlinks {
+        /// The linked title (it doesn't have to exist)
+        title: String,
+    },
+    /// Show when a note was read, edited or deleted, from the log kept with `--audit`
+    Audit {
+        /// Note title (or ID with --id)
+        title: String,
+        /// Look the note up by ID instead of title; also works for notes that are trashed or gone
+        #[arg(long)]
+        id: bool,
+    },
+    /// Move a note out of the default `list`, without deleting it
+    Archive {
+        /// Note title (or ID with --id)
+        title: String,
+        /// Look the note up by ID instead of title
+        #[arg(long)]
+        id: bool,
+    },
+    /// Bring an archived note back into the default `list`
+    Unarchive {
+        /// Note title (or ID with --id)
+        title: String,
+        /// Look the note up by ID instead of title
+        #[arg(long)]
+        id: bool,
+    },
+    /// Change a note's title
+    Rename {
+        /// Current title (or ID with --id)
+        old_title: String,
+        new_title: String,
+        /// Look the note up by ID instead of title
+        #[arg(long)]
+        id: bool,
+        /// Refuse if another note already has the new title
+        #[arg(long)]
+        no_clobber: bool,
+    },
+    /// Copy a note's content and tags into a new note
+    Duplicate {
+        /// Title of the note to copy (or its ID with --id)
         title: String,
+        new_title: String,
+        /// Look the source note up by ID instead of title
+        #[arg(long)]
+        id: bool,
+        /// Overwrite a note that already has the new title
+        #[arg(long)]
+        force: bool,
+    },
+    /// Re-encrypt the whole vault under a new master password
+    ChangePassword,
+    /// Re-encrypt every note with the current cipher and key derivation, keeping the password
+    Rekey,
+    /// Write every decryptable note, unencrypted, to a JSON (or YAML/TOML) file
+    Export {
+        path: PathBuf,
+        /// File format of the export
+        #[arg(long, value_enum, default_value_t = ExportFormat::Json)]
+        format: ExportFormat,
+        /// Shorthand for `--format ndjson`
+        #[arg(long, conflicts_with = "format")]
+        ndjson: bool,
+    },
+    /// Show note counts, sizes and date range (read-only)
+    Stats {
+        /// Also break notes and content size down by tag
+        #[arg(long)]
+        per_tag: bool,
+    },
+    /// Check that every note decrypts; exits nonzero if any is corrupt (read-only)
+    Verify,
+    /// Encrypt the whole vault as one blob, hiding note count, IDs and sizes
+    Seal,
+    /// Switch a sealed vault back to individually encrypted notes
+    Unseal,
+    /// Wrap the whole vault file in an extra encryption layer, so at rest it is opaque bytes
+    Lock,
+    /// Remove the extra layer added by `lock`, leaving the normal vault file
+    Unlock,
+    /// Refuse to open the vault after too many wrong passwords in a row, or delete it with `--panic-wipe`
+    Lockout {
+        /// Wrong passwords in a row allowed before the vault stops opening; 0 turns the lockout off
+        #[arg(long, value_name = "N")]
+        max_attempts: u32,
+        /// Delete (and overwrite) the vault file on the last allowed wrong password instead
+        #[arg(long)]
+        panic_wipe: bool,
+    },
+    /// Copy the encrypted vault file to a timestamped backup (no password needed)
+    Backup {
+        /// Directory for the backup (defaults to the vault's own directory)
+        dir: Option<PathBuf>,
+        /// Afterwards, delete all but the newest N backups of this vault in that directory
+        #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+        keep: Option<u32>,
+    },
+    /// List the named vaults usable with --profile
+    Profiles,
+    /// Unlock the vault once and run commands interactively until `quit`
+    Shell,
+    /// Cache unlocked keys in a background agent so commands don't ask for the password every time
+    Agent {
+        #[command(subcommand)]
+        action: AgentAction,
+    },
+    /// Generate a random password (letters and digits by default) and print it
+    GenPassword {
+        /// Number of characters
+        #[arg(default_value_t = 20, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(4..=1024))]
+        length: usize,
+        /// Include symbols such as `!#%&*`
+        #[arg(long)]
+        symbols: bool,
+        /// Leave out look-alike characters (0 O 1 l I |)
+        #[arg(long)]
+        no_ambiguous: bool,
+        /// Also store the password as a new note with this title
+        #[arg(long, value_name = "TITLE")]
+        save_as: Option<String>,
+    },
+    /// Check that this build works here: init, add, list, read, edit and delete in a temporary vault
+    SelfTest,
+    /// Print a shell completion script, e.g. `vault completions zsh > _vault`
+    #[command(hide = true)]
+    Completions {
+        shell: Shell,
+    },
+    /// Copy the notes of another vault file into this one, re-encrypted under this vault's key
+    Merge {
+        /// The other vault file (left unchanged)
+        other: PathBuf,
+        /// What to do when a merged note's title already exists here
+        #[arg(long, value_enum, default_value_t = OnConflict::Skip)]
+        on_conflict: OnConflict,
+        /// Key file of the other vault, if it was created with one
+        #[arg(long, value_name = "PATH")]
+        other_key_file: Option<PathBuf>,
+    },
+    /// Encrypt and add notes from a JSON array of `{title, content}` objects
+    Import {
+        path: PathBuf,
+        /// Overwrite existing notes with the same title instead of skipping them
+        #[arg(long)]
+        replace_on_conflict: bool,
+    },
+    /// Find decryptable notes whose content contains a substring (or matches a regex)
+    Search {
+        query: String,
+        /// Match case exactly instead of ignoring it
+        #[arg(long, conflicts_with = "regex")]
+        case_sensitive: bool,
+        /// Treat the query as a regular expression (case-sensitive unless --ignore-case)
+        #[arg(long)]
+        regex: bool,
+        /// Ignore case when matching a --regex query
+        #[arg(short, long, requires = "regex")]
+        ignore_case: bool,
+    },
+    /// List the notes matching every given condition (tags, content, creation date)
+    Find {
+        /// Only notes carrying this tag; repeat to require several
+        #[arg(long = "tag", value_name = "TAG")]
+        tags: Vec<String>,
+        /// Only notes whose content contains this text (ignoring case)
+        #[arg(long, value_name = "TEXT")]
+        contains: Option<String>,
+        /// Only notes created before this date (YYYY-MM-DD, RFC 3339, or relative like 7d)
+        #[arg(long, value_name = "DATE", value_parser = parse_date)]
+        before: Option<DateTime<Utc>>,
+        /// Only notes created on or after this date (YYYY-MM-DD, RFC 3339, or relative like 7d)
+        #[arg(long, value_name = "DATE", value_parser = parse_date)]
+        after: Option<DateTime<Utc>>,
+    },
+    /// Show a line-by-line diff between two notes, or a note and its copy in a backup
+    Diff {
+        /// The note to compare (title, or ID with --id)
+        title_a: String,
+        /// The note to compare it with
+        #[arg(required_unless_present = "against_backup")]
+        title_b: Option<String>,
+        /// Look the notes up by ID instead of title
+        #[arg(long)]
+        id: bool,
+        /// Compare the note with the same note in this backup (or any other vault file)
+        #[arg(long, value_name = "PATH", conflicts_with = "title_b")]
+        against_backup: Option<PathBuf>,
+    },
+}
+
+impl VaultCommands {
+    /// Whether the command reads a note's content without changing the vault, which `--audit`
+    /// then logs (and saves). `read --watch` logs its reads itself, see `log_watched_read`.
+    fn audited_read(&self) -> bool {
+        matches!(self, VaultCommands::Read { watch: false, .. } | VaultCommands::Cat { .. } | VaultCommands::Totp { .. })
+    }
+
+    /// Whether the command destroys the vault or changes how it is secured, and so never takes
+    /// the key from the agent: the master password must be given again
+    fn needs_password(&self) -> bool {
+        matches!(self, VaultCommands::Purge { .. } | VaultCommands::Lockout { .. } | VaultCommands::ChangePassword)
+    }
+
+    /// Whether the command changes the vault file (and so is affected by `--dry-run`)
+    fn writes_vault(&self) -> bool {
+        matches!(
+            self,
+            VaultCommands::Init { .. }
+                | VaultCommands::New { .. }
+                | VaultCommands::Edit { .. }
+                | VaultCommands::Revert { .. }
+                | VaultCommands::Delete { .. }
+                | VaultCommands::Restore { .. }
+                | VaultCommands::Recover { .. }
+                | VaultCommands::EmptyTrash { .. }
+                | VaultCommands::Purge { .. }
+                | VaultCommands::Rename { .. }
+                | VaultCommands::Duplicate { .. }
+                | VaultCommands::Pin { .. }
+                | VaultCommands::Unpin { .. }
+                | VaultCommands::Archive { .. }
+                | VaultCommands::Unarchive { .. }
+                | VaultCommands::ChangePassword
+                | VaultCommands::Rekey
+                | VaultCommands::Import { .. }
+                | VaultCommands::Merge { .. }
+                | VaultCommands::Seal
+                | VaultCommands::Unseal
+                | VaultCommands::Lock
+                | VaultCommands::Lockout { .. }
+                | VaultCommands::Unlock
+                | VaultCommands::GenPassword { save_as: Some(_), .. }
+        )
+    }
+}
+
+/// Options of `List`
+#[derive(clap::Args, Debug, Clone)]
+struct ListArgs {
+    /// Print a JSON array instead of human-readable lines
+    #[arg(long)]
+    json: bool,
+    /// Also show notes that are in the trash
+    #[arg(long)]
+    include_trashed: bool,
+    /// Show only archived notes
+    #[arg(long, conflicts_with = "all")]
+    archived: bool,
+    /// Show archived notes as well as the others
+    #[arg(long)]
+    all: bool,
+    /// Only show notes carrying this tag
+    #[arg(long, value_name = "TAG")]
+    tag: Option<String>,
+    /// Only show notes whose title contains this text (case-insensitive)
+    #[arg(long, value_name = "TEXT")]
+    grep_title: Option<String>,
+    /// One title per line instead of a table
+    #[arg(long, conflicts_with = "json")]
+    plain: bool,
+    /// Order notes by this field instead of the order they were added in
+    #[arg(long, value_enum, value_name = "FIELD")]
+    sort: Option<SortKey>,
+    /// Reverse the order (newest or largest first when sorting by date or size)
+    #[arg(long)]
+    reverse: bool,
+    /// Show at most this many notes
+    #[arg(long, value_name = "N")]
+    limit: Option<usize>,
+    /// Skip this many notes first (after filtering and sorting)
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    offset: usize,
+    /// Also report how many notes don't decrypt with this password
+    #[arg(long)]
+    show_locked: bool,
+    /// Only notes created at or after this date (YYYY-MM-DD, RFC 3339, or relative like 7d)
+    #[arg(long, value_name = "DATE", value_parser = parse_date)]
+    since: Option<DateTime<Utc>>,
+    /// Only notes created at or before this date (YYYY-MM-DD, RFC 3339, or relative like 7d)
+    #[arg(long, value_name = "DATE", value_parser = parse_date)]
+    until: Option<DateTime<Utc>>,
+    /// Apply --since and --until to the last modification date instead
+    #[arg(long)]
+    by_updated: bool,
+    /// Only show TOTP notes (`new --totp`)
+    #[arg(long)]
+    totp: bool,
+    /// Table columns to show, in this order (default: title)
+    #[arg(long, value_enum, value_delimiter = ',', value_name = "FIELDS", conflicts_with_all = ["json", "plain"])]
+    fields: Vec<ListField>,
+}
+
+/// A column of the `List` table
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum ListField {
+    /// The note's title, marked if pinned or trashed
+    Title,
+    /// The note's ID
+    Id,
+    /// Creation date
+    Created,
+    /// Last modification date
+    Updated,
+    /// Number of tags
+    Tags,
+    /// Content size
+    Size,
+}
+
+/// Field `List --sort` orders by
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum SortKey {
+    /// Alphabetically, ignoring case
+    Title,
+    /// Creation time, oldest first
+    Created,
+    /// Last modification time, oldest first
+    Updated,
+    /// Content size, smallest first
+    Size,
+}
+
+/// File formats `Export` can write
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum ExportFormat {
+    /// A JSON array of notes (what `import` reads)
+    Json,
+    /// A YAML sequence of notes
+    Yaml,
+    /// A TOML `[[notes]]` array of tables
+    Toml,
+    /// JSON Lines: one note object per line, written as each note is decrypted
+    Ndjson,
+}
+
+/// How `Merge` handles a note whose title already exists
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum OnConflict {
+    /// Keep the existing note and drop the incoming one
+    Skip,
+    /// Add the incoming note as "<title> (merged)"
+    Rename,
+    /// Replace the existing note's content and metadata with the incoming one's
+    Overwrite,
+}
+
+/// Subcommands of `vault agent`
+#[derive(Subcommand, Debug, Clone, Copy)]
+enum AgentAction {
+    /// Start the agent in the background
+    Start {
+        /// Seconds a key stays cached after the password was entered
+        #[arg(long, value_name = "SECS", default_value_t = AGENT_TTL_SECS)]
+        ttl: u64,
+    },
+    /// Stop the agent, wiping every cached key
+    Stop,
+    /// Show whether the agent is running and how many keys it holds
+    Status,
+    /// Run the agent in the foreground (what `start` launches)
+    #[command(hide = true)]
+    Serve {
+        #[arg(long, value_name = "SECS", default_value_t = AGENT_TTL_SECS)]
+        ttl: u64,
     },
 }
 
 // ----------------- Data Structure -----------------
 
+/// AEAD algorithms a note can be encrypted with. Both take a 256-bit key and a 96-bit nonce.
+#[derive(Serialize, Deserialize, ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum Cipher {
+    /// AES-256-GCM (fast with hardware AES support)
+    #[default]
+    #[value(name = "aes-256-gcm")]
+    #[serde(rename = "aes-256-gcm")]
+    Aes256Gcm,
+    /// ChaCha20-Poly1305 (fast and constant-time without AES hardware)
+    #[value(name = "chacha20-poly1305")]
+    #[serde(rename = "chacha20-poly1305")]
+    ChaCha20Poly1305,
+}
+
+/// How a vault's key is derived from the master password
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum Kdf {
+    /// Argon2id with the vault's salt
+    #[default]
+    #[serde(rename = "argon2id")]
+    Argon2id,
+    /// Plain unsalted SHA-256 of the password, as used by the original flat-array vaults.
+    /// Kept so migrated notes open unchanged; `change-password` moves the vault to Argon2id.
+    #[serde(rename = "sha256")]
+    LegacySha256,
+}
+
+/// Argon2id cost parameters. A vault records the ones its key was derived with, so later
+/// changes to `vault.toml` only apply to new vaults and new keys (`rekey`, `change-password`).
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(default)]
+struct KdfParams {
+    memory_kib: u32, // Memory cost in KiB
+    iterations: u32, // Number of passes over memory
+    parallelism: u32, // Number of lanes
+}
+
+impl Default for KdfParams {
+    /// The built-in costs, which every vault from before they were recorded was keyed with
+    fn default() -> Self {
+        KdfParams { memory_kib: ARGON2_MEMORY_KIB, iterations: ARGON2_ITERATIONS, parallelism: ARGON2_PARALLELISM }
+    }
+}
+
+/// Contents of `vault.toml`; every setting is optional
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct Config {
+    argon2: KdfParams,
+    max_note_size: Option<usize>, // Bytes; `New`, `Edit` and `Import` refuse larger notes
+    audit: bool, // Like `--audit` on every command
+}
+
 /// Struct to store a note with encrypted content
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 struct Note {
-    title: String,
+    #[serde(default)]
+    id: String, // Stable random UUID; lets notes with the same title be told apart
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    title: String, // Plaintext title, only present on notes written by older versions
     content: String, // Encrypted base64 string
     nonce: String,   // Base64-encoded nonce for AES-GCM
+    #[serde(default)]
+    meta: String, // Encrypted base64 `NoteMeta` (empty for notes written by older versions)
+    #[serde(default)]
+    meta_nonce: String, // Base64-encoded nonce for `meta`
+    #[serde(default)]
+    cipher: Cipher, // Algorithm for both `content` and `meta`; older notes are AES-256-GCM
+    #[serde(default)]
+    compressed: bool, // `content` was deflate-compressed before encryption
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    history: Vec<NoteVersion>, // Earlier contents, newest first, at most `HISTORY_LEN`
 }
 
-// ----------------- Utility Functions -----------------
+/// An earlier content of a note, kept by `Edit` so `Revert` can bring it back.
+/// Everything about it, including when it was written, is inside the encrypted `PastVersion`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct NoteVersion {
+    data: String,  // Encrypted base64 `PastVersion` JSON
+    nonce: String, // Base64-encoded nonce for `data`
+    #[serde(default)]
+    cipher: Cipher,
+    #[serde(default)]
+    compressed: bool, // `data` was deflate-compressed before encryption
+}
 
-/// Prompt the user to enter a password silently
-fn prompt_password() -> String {
-    print!("🔑 Enter password: ");
-    io::stdout().flush().unwrap(); // Ensure prompt shows before input
-    read_password().unwrap_or_default() // Return empty if input fails
+/// The decrypted form of a `NoteVersion`
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct PastVersion {
+    content: String,
+    #[serde(default)]
+    written_at: String, // RFC 3339 time this content was written; empty for notes that predate timestamps
+    #[serde(default)]
+    replaced_at: String, // RFC 3339 time it was replaced
+    #[serde(default)]
+    binary: bool, // Like `NoteMeta.binary`
 }
 
-/// Derives a 256-bit AES key from a password using SHA-256
-fn derive_key_from_password(password: &str) -> GenericArray<u8, typenum::U32> {
-    let mut hasher = Sha256::new();
-    hasher.update(password.as_bytes());
-    let result = hasher.finalize();
-    GenericArray::clone_from_slice(&result) // Required format for AES-GCM
+/// Per-note metadata. Deliberately kept out of plaintext: it is serialized to JSON and
+/// encrypted into `Note.meta`, so the vault file doesn't reveal titles or when notes were written.
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct NoteMeta {
+    #[serde(default)]
+    title: String,
+    #[serde(default)]
+    created_at: String, // RFC 3339
+    #[serde(default)]
+    updated_at: String, // RFC 3339
+    #[serde(default)]
+    deleted_at: String, // RFC 3339 time the note was moved to the trash; empty if it isn't trashed
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    expires_at: String, // RFC 3339 time after which the note is pruned; empty if it never expires
+    #[serde(default)]
+    binary: bool, // Content is the base64 of an attachment's bytes, not text
+    #[serde(default)]
+    pinned: bool, // Listed before the other notes
+    #[serde(default)]
+    sensitive: bool, // `read` and `cat` ask for the password again before showing it
+    #[serde(default)]
+    kind: NoteKind,
+    #[serde(default)]
+    archived: bool, // Left out of `list` unless asked for; unlike the trash, never deleted
 }
 
-/// Encrypt note content and return (ciphertext_base64, nonce_base64)
-fn encrypt_note_content(content: &str, key: &GenericArray<u8, typenum::U32>) -> (String, String) {
-    let cipher = Aes256Gcm::new(key);
+/// What a note's content is, beyond text or an attachment
+#[derive(Serialize, Deserialize, Default, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum NoteKind {
+    #[default]
+    Text,
+    Totp, // A base32 TOTP secret, normalized by `totp_secret`
+}
 
-    // Generate a random 96-bit (12-byte) nonce
-    let mut nonce_bytes = [0u8; 12];
-    OsRng.fill_bytes(&mut nonce_bytes);
-    let nonce = Nonce::from_slice(&nonce_bytes);
+impl NoteKind {
+    fn is_text(&self) -> bool {
+        *self == NoteKind::Text
+    }
+}
 
-    // Encrypt the content
-    let ciphertext = cipher
-        .encrypt(nonce, content.as_bytes())
-        .expect("Encryption failed");
+impl NoteMeta {
+    /// Whether the note is sitting in the trash
+    fn trashed(&self) -> bool {
+        !self.deleted_at.is_empty()
+    }
 
-    (
-        general_purpose::STANDARD.encode(&ciphertext),
-        general_purpose::STANDARD.encode(&nonce_bytes),
-    )
+    /// Whether the note's expiry time has passed
+    fn expired(&self, now: DateTime<Utc>) -> bool {
+        DateTime::parse_from_rfc3339(&self.expires_at).is_ok_and(|expires| expires <= now)
+    }
 }
 
-/// Decrypts note content, returning the original plaintext if successful
-fn decrypt_note_content(ciphertext_b64: &str, nonce_b64: &str, key: &GenericArray<u8, typenum::U32>) -> Option<String> {
-    let cipher = Aes256Gcm::new(key);
+/// One entry of `List --json` output
+#[derive(Serialize, Debug)]
+struct NoteListing {
+    id: String,
+    title: String,
+    created_at: String,
+    updated_at: String,
+    tags: Vec<String>,
+    size: usize, // Content length in bytes
+    trashed: bool,
+    pinned: bool,
+    archived: bool,
+    totp: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    vault: Option<String>, // Vault file the note is in, when several are listed together
+}
 
-    // Decode base64 strings back into bytes
-    let ciphertext = general_purpose::STANDARD.decode(ciphertext_b64).ok()?;
-    let nonce_bytes = general_purpose::STANDARD.decode(nonce_b64).ok()?;
-    let nonce = Nonce::from_slice(&nonce_bytes);
+/// An unlocked vault whose notes `List` or `Search` show; named when several are shown together
+struct ListSource<'a> {
+    name: Option<String>,
+    vault: &'a Vault,
+    key: &'a GenericArray<u8, typenum::U32>,
+}
 
-    // Attempt decryption
-    let plaintext = cipher.decrypt(nonce, ciphertext.as_ref()).ok()?;
-    String::from_utf8(plaintext).ok()
+/// A decrypted note as written by `Export` and read by `Import`
+#[derive(Serialize, Deserialize, Debug)]
+struct PlainNote {
+    title: String,
+    content: String,
+    #[serde(default)]
+    created_at: String, // RFC 3339; empty for notes that predate timestamps
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    binary: bool, // `content` is base64, as stored in the vault
+    #[serde(default, skip_serializing_if = "NoteKind::is_text")]
+    kind: NoteKind,
 }
 
-/// Load all notes from the vault file
-fn load_notes() -> Vec<Note> {
-    if let Ok(mut file) = File::open(VAULT_FILE) {
-        let mut contents = String::new();
-        file.read_to_string(&mut contents).unwrap();
-        serde_json::from_str(&contents).unwrap_or_default()
-    } else {
-        Vec::new()
+/// TOML documents must be tables, so a TOML export nests the notes under `notes`
+#[derive(Serialize)]
+struct TomlExport<'a> {
+    notes: &'a [PlainNote],
+}
+
+/// Everything stored in the vault file: the key-derivation salt plus the notes
+#[derive(Serialize, Deserialize, Debug)]
+struct Vault {
+    #[serde(default)]
+    version: u32, // File format version (`VAULT_VERSION`); 0 for files written before versioning
+    #[serde(default)]
+    kdf: Kdf,
+    #[serde(default)]
+    argon2: KdfParams, // Costs the Argon2id key was derived with
+    salt: String, // Base64-encoded Argon2 salt, generated once per vault
+    #[serde(default)]
+    check: String, // Encrypted base64 `CHECK_PLAINTEXT`, used to verify the master password
+    #[serde(default)]
+    check_nonce: String, // Base64-encoded nonce for `check`
+    #[serde(default)]
+    layout: Layout,
+    #[serde(default)]
+    key_file: bool, // The key is derived from the password plus a key file, so both are needed
+    #[serde(default)]
+    notes: Vec<Note>, // Absent on disk for sealed vaults until unsealed by `unseal_vault`
+    #[serde(default)]
+    blob: String, // Sealed vaults: base64 AES-256-GCM encryption of the JSON `notes` array
+    #[serde(default)]
+    blob_nonce: String, // Base64-encoded nonce for `blob`
+    #[serde(skip)]
+    legacy_backup: bool, // Loaded from a flat-array file, which is copied to `<path>.bak` before the first save
+    #[serde(skip)]
+    format: VaultFormat, // Encoding the file was loaded in, and is saved in
+    #[serde(skip)]
+    locked: bool, // The file is wrapped in an outer encryption layer by `lock`
+    #[serde(skip)]
+    outer: Vec<u8>, // Locked vaults: header, nonce and ciphertext of the file, until `unlock_file` opens it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    lockout: Option<LockoutPolicy>,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    audit: String, // Base64 AES-256-GCM encryption of the JSON `AuditEntry` array written under `--audit`
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    audit_nonce: String, // Base64-encoded nonce for `audit`
+}
+
+impl Default for Vault {
+    /// An empty vault in the current format
+    fn default() -> Self {
+        Vault {
+            version: VAULT_VERSION,
+            kdf: Kdf::default(),
+            argon2: KdfParams::default(),
+            salt: String::new(),
+            check: String::new(),
+            check_nonce: String::new(),
+            layout: Layout::default(),
+            key_file: false,
+            notes: Vec::new(),
+            blob: String::new(),
+            blob_nonce: String::new(),
+            legacy_backup: false,
+            format: VaultFormat::default(),
+            locked: false,
+            outer: Vec::new(),
+            lockout: None,
+            audit: String::new(),
+            audit_nonce: String::new(),
+        }
     }
 }
 
-/// Save all notes to the vault file
-fn save_notes(notes: &[Note]) {
-    let json = serde_json::to_string_pretty(notes).unwrap();
-    let mut file = File::create(VAULT_FILE).unwrap();
-    file.write_all(json.as_bytes()).unwrap();
+/// Set by `lockout`: how many wrong passwords in a row the vault takes before it stops opening.
+/// It lives in the readable header because it applies before any key is known. `max_attempts`
+/// and `wipe` are bound into the check token (see `seal_check`), so editing them makes every
+/// password fail; `failed` is rewritten after each attempt and can't be authenticated.
+/// The counter is therefore only advisory against anyone who can write the file: they can
+/// reset it at will, or guess offline against a copy. It only slows down guessing through
+/// this program.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+struct LockoutPolicy {
+    max_attempts: u32,
+    #[serde(default)]
+    wipe: bool, // Delete the vault file at the limit (`--panic-wipe`) rather than refuse to open it
+    #[serde(default)]
+    failed: u32, // Wrong passwords since the vault last opened
 }
 
-// ----------------- Main Program -----------------
+impl LockoutPolicy {
+    /// Associated data for the check token, covering the policy but not the counter
+    fn aad(&self) -> String {
+        format!("vault-cli lockout:{}:{}", self.max_attempts, self.wipe)
+    }
+}
 
-fn main() {
-    let args = Args::parse(); // Parse command-line arguments
-    let password = prompt_password(); // Ask user for master password
-    let key = derive_key_from_password(&password); // Turn password into AES key
-    let mut notes = load_notes(); // Load existing notes from file
-
-    match args.command {
-        VaultCommands::New { title, content } => {
-            let (encrypted_content, nonce) = encrypt_note_content(&content, &key);
-            notes.push(Note {
-                title,
-                content: encrypted_content,
-                nonce,
-            });
-            save_notes(&notes);
-            println!("✅ Note added.");
-        }
+/// What a note was used for, in the audit log
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum AuditAction {
+    Read,
+    Edit,
+    Delete,
+}
+
+/// One entry of the audit log, see `Vault::audit`
+#[derive(Serialize, Deserialize, Debug)]
+struct AuditEntry {
+    note_id: String, // Kept after the note is gone, so its history stays readable with `audit --id`
+    action: AuditAction,
+    at: String, // RFC 3339 timestamp
+}
+
+/// How the vault file is encoded; told apart by `BINARY_MAGIC` when loading
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum VaultFormat {
+    /// Pretty-printed JSON, easy to inspect
+    #[default]
+    Json,
+    /// Compact binary MessagePack
+    Msgpack,
+}
+
+/// How notes are laid out in the vault file
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum Layout {
+    /// Each note is encrypted on its own inside a plaintext JSON skeleton
+    #[default]
+    #[serde(rename = "per-note")]
+    PerNote,
+    /// The whole notes array is encrypted as one blob, hiding note count, IDs and sizes
+    #[serde(rename = "sealed")]
+    Sealed,
+}
+
+/// The only plaintext written for a sealed vault
+#[derive(Serialize)]
+struct SealedVault<'a> {
+    version: u32,
+    kdf: Kdf,
+    argon2: KdfParams,
+    salt: &'a str,
+    check: &'a str,
+    check_nonce: &'a str,
+    layout: Layout,
+    key_file: bool,
+    blob: String,
+    blob_nonce: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    lockout: Option<LockoutPolicy>,
+    #[serde(skip_serializing_if = "str::is_empty")]
+    audit: &'a str,
+    #[serde(skip_serializing_if = "str::is_empty")]
+    audit_nonce: &'a str,
+}
+
+/// Just the version field, read before the rest so a newer file is refused, not half-parsed
+#[derive(Deserialize)]
+struct VaultHeader {
+    #[serde(default)]
+    version: u32,
+}
+
+// ----------------- Errors -----------------
 
-        VaultCommands::List => {
-            println!("🔐 Decryptable notes:");
-            for note in &notes {
-                if decrypt_note_content(&note.content, &note.nonce, &key).is_some() {
-                    println!("📌 {}", note.title);
+/// Everything that can go wrong while working with the vault
+#[derive(Debug)]
+enum VaultError {
+    /// Reading the password from the terminal failed
+    PasswordInput(io::Error),
+    /// The password was given as a plain `--password` argument
+    PasswordArgument,
+    /// `--password-stdin` found no password line
+    NoPasswordOnStdin,
+    /// A password is needed, but stdin isn't a terminal to ask on
+    NoTerminal,
+    /// Nobody typed a password within `--password-confirm-timeout` seconds
+    PasswordTimeout(u64),
+    /// The `--password-file` couldn't be read
+    ReadPasswordFile(PathBuf, io::Error),
+    /// Reading note content or a confirmation from stdin failed
+    ReadStdin(io::Error),
+    /// A destructive action needs confirmation but stdin isn't a terminal
+    ConfirmationRequired,
+    /// The editor couldn't be launched or its scratch file handled
+    Editor(io::Error),
+    /// The editor exited unsuccessfully
+    EditorFailed(std::process::ExitStatus),
+    /// `Read --watch` couldn't watch the vault file for changes
+    Watch(notify::Error),
+    /// The vault's key changed while `Read --watch` was following it
+    WatchedVaultRekeyed,
+    /// The vault file exists but couldn't be read
+    ReadVault(PathBuf, io::Error),
+    /// The vault file couldn't be written
+    WriteVault(PathBuf, io::Error),
+    /// `vault.toml` exists but couldn't be read
+    ReadConfig(PathBuf, io::Error),
+    /// `vault.toml` isn't valid TOML or has unknown settings
+    ParseConfig(PathBuf, toml::de::Error),
+    /// Another process held the vault lock for longer than `LOCK_WAIT_SECS`; carries its PID if known
+    Locked(PathBuf, String),
+    /// The vault file isn't valid vault JSON
+    ParseVault(PathBuf, serde_json::Error),
+    /// The vault file starts like a MessagePack vault but doesn't decode as one
+    DecodeVault(PathBuf, rmp_serde::decode::Error),
+    /// Encoding the vault as MessagePack failed
+    EncodeVault(rmp_serde::encode::Error),
+    /// Serializing notes or metadata to JSON failed
+    Serialize(serde_json::Error),
+    /// Serializing an export to YAML or TOML failed
+    SerializeExport(String),
+    /// The vault predates per-vault salts and can't be keyed
+    MissingSalt,
+    /// The stored salt isn't valid base64 or has the wrong length
+    CorruptSalt,
+    /// Argon2 rejected its parameters or inputs
+    KeyDerivation(argon2::Error),
+    /// AES-GCM encryption failed
+    Encryption,
+    /// The OS random number generator couldn't supply fresh bytes for a nonce or salt
+    Entropy(rand::Error),
+    /// The master password doesn't match the vault's check token
+    WrongPassword,
+    /// The password and key file together don't match the vault's check token
+    WrongPasswordOrKeyFile,
+    /// The vault's `lockout` limit of wrong passwords was reached earlier
+    AttemptsExhausted(u32),
+    /// The last wrong password allowed by `lockout --panic-wipe` deleted the vault file
+    VaultWiped(PathBuf),
+    /// `lock` and `lockout` don't combine: a locked file's header can't count attempts
+    LockoutLocked,
+    /// The vault was created with a key file, but none was given
+    KeyFileRequired,
+    /// A key file was given for a vault that doesn't use one
+    KeyFileNotUsed,
+    /// The key file couldn't be read, or is empty
+    ReadKeyFile(PathBuf, io::Error),
+    /// The new password and its confirmation differ
+    PasswordMismatch,
+    /// Some notes don't decrypt under the current key, so the vault can't be fully re-encrypted
+    UndecryptableNotes(usize),
+    /// Several notes share the requested title; carries their IDs
+    AmbiguousTitle(String, Vec<String>),
+    /// No exact title match, and several titles match loosely; carries those titles
+    AmbiguousMatch(String, Vec<String>),
+    /// No note matches the requested title or ID
+    NoteNotFound(String),
+    /// No note in the trash matches the requested title or ID
+    NotInTrash(String),
+    /// The note is there but doesn't decrypt under the current key
+    NoteUndecryptable,
+    /// A `Revert` target from the note's history doesn't decrypt
+    VersionUndecryptable(usize),
+    /// Another note already has this title
+    TitleExists(String),
+    /// `Revert --version` is past the end of the note's history; carries the history length
+    NoSuchVersion(String, usize, usize),
+    /// A note with this title exists and `--force` wasn't given
+    NoteExists(String),
+    /// A plaintext output file (export or `Read --out`) couldn't be written
+    WriteOutput(PathBuf, io::Error),
+    /// The file for `New --from-file` couldn't be read
+    ReadAttachment(PathBuf, io::Error),
+    /// A binary attachment's decrypted content isn't valid base64
+    CorruptAttachment(String),
+    /// The operation only makes sense for text notes (e.g. `Edit`, `Read --clipboard`)
+    BinaryNote(String),
+    /// `Totp` was given a note that doesn't hold a TOTP secret
+    NotTotp(String),
+    /// A TOTP secret isn't valid base32: (title)
+    InvalidTotpSecret(String),
+    /// A note in an import file fails validation: (file, 1-based position, reason)
+    InvalidImport(PathBuf, usize, &'static str),
+    /// A note's content is over the configured size limit: (title, size, limit)
+    NoteTooLarge(String, usize, usize),
+    /// No usable system clipboard (headless session, or built without the `clipboard` feature)
+    Clipboard(String),
+    /// No per-user config directory could be determined (e.g. `$HOME` is unset)
+    NoConfigDir,
+    /// The profile name is empty or isn't a plain file name
+    InvalidProfile(String),
+    /// The profile directory exists but couldn't be listed
+    ReadProfiles(PathBuf, io::Error),
+    /// The vault file's format version is newer than this binary understands
+    NewerVault,
+    /// A command other than `Init` was run where there is no vault yet
+    NoVault(PathBuf),
+    /// `Init` without `--force` where a vault already exists
+    VaultExists(PathBuf),
+    /// A sealed vault's blob doesn't decrypt or doesn't hold notes
+    SealedBlob,
+    /// A locked vault's outer layer doesn't decrypt
+    LockedVaultUndecryptable,
+    /// The audit log doesn't decrypt with this key
+    AuditUndecryptable,
+    /// `Lock` on a vault still keyed by legacy SHA-256, which has no salt for the outer layer
+    LockLegacy,
+    /// `Verify` found notes that are malformed or don't decrypt
+    VerifyFailed(usize),
+    /// A `self-test` step did not behave as expected
+    SelfTestFailed(&'static str),
+    /// Starting, reaching or running the key agent failed
+    Agent(io::Error),
+    /// The key agent needs Unix domain sockets
+    AgentUnsupported,
+    /// A backup couldn't be written, or old ones couldn't be listed or removed
+    Backup(PathBuf, io::Error),
+    /// `Merge` was pointed at the vault it would merge into
+    MergeIntoSelf,
+    /// `--vault` was given several times for a command other than `List` or `Search`
+    SeveralVaults,
+    /// The vault path is a symbolic link and `--follow-symlinks` wasn't given
+    VaultSymlink(PathBuf),
+    /// The import file couldn't be read
+    ReadImport(PathBuf, io::Error),
+    /// The import file isn't a JSON array of notes
+    ParseImport(PathBuf, serde_json::Error),
+    /// `Search --regex` was given a pattern that doesn't compile
+    InvalidRegex(regex::Error),
+    /// The interactive shell couldn't read from the terminal
+    Shell(String),
+}
+
+impl fmt::Display for VaultError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VaultError::PasswordInput(err) => write!(f, "Could not read password: {err}"),
+            VaultError::PasswordArgument => write!(
+                f,
+                "--password is not supported because it exposes the password to other users; use --password-stdin or --password-file."
+            ),
+            VaultError::NoPasswordOnStdin => write!(f, "--password-stdin was given but stdin is empty."),
+            VaultError::NoTerminal => write!(
+                f,
+                "A password is needed but there is no terminal to ask on; pass --password-stdin or --password-file."
+            ),
+            VaultError::PasswordTimeout(secs) => write!(f, "No password was typed within {secs}s (--password-confirm-timeout)."),
+            VaultError::ReadPasswordFile(path, err) => write!(f, "Could not read password file '{}': {err}", path.display()),
+            VaultError::ReadStdin(err) => write!(f, "Could not read from stdin: {err}"),
+            VaultError::ConfirmationRequired => {
+                write!(f, "Refusing to continue without confirmation: stdin is not a terminal (use --yes).")
+            }
+            VaultError::Editor(err) => write!(f, "Could not run editor: {err}"),
+            VaultError::EditorFailed(status) => write!(f, "Editor exited with {status}; note left unchanged."),
+            VaultError::Watch(err) => write!(f, "Could not watch the vault file for changes: {err}"),
+            VaultError::WatchedVaultRekeyed => {
+                write!(f, "The vault's password was changed while it was being watched; run `read --watch` again.")
+            }
+            VaultError::ReadVault(path, err) => write!(f, "Could not read vault '{}': {err}", path.display()),
+            VaultError::WriteVault(path, err) => write!(f, "Could not write vault '{}': {err}", path.display()),
+            VaultError::ReadConfig(path, err) => write!(f, "Could not read config file '{}': {err}", path.display()),
+            VaultError::ParseConfig(path, err) => write!(f, "Config file '{}' is invalid: {err}", path.display()),
+            VaultError::Locked(path, pid) => write!(
+                f,
+                "Vault is locked by another process{}; try again once it is done (lock file '{}').",
+                if pid.is_empty() { String::new() } else { format!(" (PID {pid})") },
+                path.display()
+            ),
+            VaultError::ParseVault(path, err) => write!(f, "Vault '{}' is not a valid vault file: {err}", path.display()),
+            VaultError::Serialize(err) => write!(f, "Could not serialize vault data: {err}"),
+            VaultError::DecodeVault(path, err) => write!(f, "Vault '{}' is not a valid binary vault file: {err}", path.display()),
+            VaultError::EncodeVault(err) => write!(f, "Could not encode the vault as MessagePack: {err}"),
+            VaultError::SerializeExport(err) => write!(f, "Could not serialize the export: {err}"),
+            VaultError::MissingSalt => write!(
+                f,
+                "This vault has no key-derivation salt (created by an older version) and cannot be opened."
+            ),
+            VaultError::CorruptSalt => write!(f, "Vault salt is corrupted."),
+            VaultError::KeyDerivation(err) => write!(f, "Key derivation failed: {err}"),
+            VaultError::Encryption => write!(f, "Encryption failed."),
+            VaultError::Entropy(err) => {
+                write!(f, "The system random number generator failed ({err}); refusing to encrypt without fresh randomness.")
+            }
+            VaultError::WrongPassword => write!(f, "Wrong password."),
+            VaultError::WrongPasswordOrKeyFile => write!(f, "Wrong password or key file."),
+            VaultError::AttemptsExhausted(max) => write!(
+                f,
+                "This vault no longer opens: {max} wrong password(s) were entered in a row. Copy a backup (see `backup`) over the vault file to get it back."
+            ),
+            VaultError::VaultWiped(path) => {
+                write!(f, "Too many wrong passwords: the vault '{}' was deleted (lockout --panic-wipe).", path.display())
+            }
+            VaultError::LockoutLocked => write!(
+                f,
+                "A locked vault file can't count wrong passwords; run `unlock` first, or turn the lockout off with `lockout --max-attempts 0`."
+            ),
+            VaultError::KeyFileRequired => write!(
+                f,
+                "This vault needs its key file as well as the password (--key-file <PATH>); it cannot be opened without both."
+            ),
+            VaultError::KeyFileNotUsed => write!(f, "This vault doesn't use a key file; leave out --key-file."),
+            VaultError::ReadKeyFile(path, err) => write!(f, "Could not read key file '{}': {err}", path.display()),
+            VaultError::PasswordMismatch => write!(f, "Passwords do not match."),
+            VaultError::UndecryptableNotes(count) => write!(
+                f,
+                "{count} note(s) could not be decrypted with the current password; nothing was changed."
+            ),
+            VaultError::AmbiguousTitle(title, ids) => {
+                write!(f, "{} notes are titled '{title}'; pick one with --id:", ids.len())?;
+                for id in ids {
+                    write!(f, "\n   {id}")?;
+                }
+                Ok(())
+            }
+            VaultError::AmbiguousMatch(query, titles) => {
+                write!(f, "No note is titled '{query}' and {} titles are close; be more specific:", titles.len())?;
+                for title in titles {
+                    write!(f, "\n   {title}")?;
                 }
+                Ok(())
+            }
+            VaultError::NoteNotFound(title) => write!(f, "Note '{title}' not found."),
+            VaultError::NotInTrash(title) => write!(f, "No note like '{title}' in the trash."),
+            VaultError::NoteUndecryptable => write!(f, "Failed to decrypt. Wrong password?"),
+            VaultError::VersionUndecryptable(version) => write!(f, "Version {version} does not decrypt."),
+            VaultError::TitleExists(title) => write!(f, "A note titled '{title}' already exists."),
+            VaultError::NoSuchVersion(title, version, count) => {
+                write!(f, "'{title}' has no version {version}; it has {count} earlier version(s) (see `history`).")
+            }
+            VaultError::NoteExists(title) => write!(f, "A note titled '{title}' already exists (use --force to overwrite)"),
+            VaultError::ReadAttachment(path, err) => write!(f, "Could not read attachment '{}': {err}", path.display()),
+            VaultError::CorruptAttachment(title) => write!(f, "Attachment '{title}' is damaged and can't be decoded."),
+            VaultError::InvalidImport(path, number, reason) => {
+                write!(f, "Note {number} in import file '{}' is invalid: {reason}; vault left unchanged.", path.display())
+            }
+            VaultError::NotTotp(title) => {
+                write!(f, "Note '{title}' is not a TOTP secret (store one with `new <TITLE> <SECRET> --totp`).")
+            }
+            VaultError::InvalidTotpSecret(title) => {
+                write!(f, "The TOTP secret for '{title}' is not valid base32 (letters A-Z and digits 2-7).")
             }
+            VaultError::NoteTooLarge(title, size, limit) => write!(
+                f,
+                "Note '{title}' is {}, over the {} limit (set by --max-note-size or `max_note_size` in vault.toml).",
+                human_size(*size),
+                human_size(*limit)
+            ),
+            VaultError::BinaryNote(title) => write!(
+                f,
+                "'{title}' is a binary attachment; save it with `read --out <PATH>` or replace it with `new --force --from-file <PATH>`."
+            ),
+            VaultError::WriteOutput(path, err) => write!(f, "Could not write '{}': {err}", path.display()),
+            VaultError::Clipboard(reason) => write!(f, "Clipboard unavailable: {reason}"),
+            VaultError::NoConfigDir => write!(f, "Could not determine your config directory for --profile."),
+            VaultError::InvalidProfile(name) => {
+                write!(f, "Invalid profile name '{name}': use a plain name without path separators.")
+            }
+            VaultError::ReadProfiles(path, err) => write!(f, "Could not list profiles in '{}': {err}", path.display()),
+            VaultError::NewerVault => write!(f, "Vault was created by a newer version of vault-cli; please upgrade."),
+            VaultError::NoVault(path) => write!(f, "No vault at '{}'; create one with `vault init` first.", path.display()),
+            VaultError::VaultExists(path) => {
+                write!(f, "A vault already exists at '{}'; pass --force to replace it with an empty one.", path.display())
+            }
+            VaultError::SealedBlob => {
+                write!(f, "Could not decrypt the sealed vault: wrong password, or the file is corrupted or tampered with.")
+            }
+            VaultError::LockedVaultUndecryptable => write!(
+                f,
+                "Could not unlock the vault file: wrong password or key file, or the file is corrupted or tampered with."
+            ),
+            VaultError::AuditUndecryptable => {
+                write!(f, "The audit log does not decrypt with this password; it was written under another one, or is corrupted.")
+            }
+            VaultError::LockLegacy => {
+                write!(f, "This vault still uses the old SHA-256 key; run `rekey` to upgrade it before locking it.")
+            }
+            VaultError::VerifyFailed(count) => write!(f, "Vault check failed: {count} note(s) are corrupt or unreadable."),
+            VaultError::SelfTestFailed(step) => {
+                write!(f, "Self-test failed at step '{step}'; this build may not work correctly on this platform.")
+            }
+            VaultError::Agent(err) => write!(f, "Key agent error: {err}"),
+            VaultError::AgentUnsupported => write!(f, "The key agent is only available on Unix-like systems."),
+            VaultError::Backup(path, err) => write!(f, "Backup failed for '{}': {err}", path.display()),
+            VaultError::MergeIntoSelf => write!(f, "Cannot merge a vault into itself."),
+            VaultError::VaultSymlink(path) => write!(
+                f,
+                "Vault path '{}' is a symbolic link; pass --follow-symlinks to use the file it points to.",
+                path.display()
+            ),
+            VaultError::SeveralVaults => {
+                write!(f, "Only `list` and `search` can work on several vaults; give a single --vault for this command.")
+            }
+            VaultError::ReadImport(path, err) => write!(f, "Could not read import file '{}': {err}", path.display()),
+            VaultError::InvalidRegex(err) => write!(f, "Invalid regular expression: {err}"),
+            VaultError::Shell(err) => write!(f, "Shell input failed: {err}"),
+            VaultError::ParseImport(path, err) => write!(
+                f,
+                "Import file '{}' is not a JSON array of {{title, content}} notes: {err}; vault left unchanged.",
+                path.display()
+            ),
+        }
+    }
+}
+
+impl VaultError {
+    /// Stable snake_case name of the error for `--json-errors`, e.g. `note_not_found`
+    fn kind(&self) -> &'static str {
+        match self {
+            VaultError::PasswordInput(..) => "password_input",
+            VaultError::PasswordArgument => "password_argument",
+            VaultError::NoPasswordOnStdin => "no_password_on_stdin",
+            VaultError::NoTerminal => "no_terminal",
+            VaultError::PasswordTimeout(_) => "password_timeout",
+            VaultError::ReadPasswordFile(..) => "read_password_file",
+            VaultError::ReadStdin(..) => "read_stdin",
+            VaultError::ConfirmationRequired => "confirmation_required",
+            VaultError::Editor(..) => "editor",
+            VaultError::EditorFailed(..) => "editor_failed",
+            VaultError::Watch(_) => "watch",
+            VaultError::WatchedVaultRekeyed => "watched_vault_rekeyed",
+            VaultError::ReadVault(..) => "read_vault",
+            VaultError::WriteVault(..) => "write_vault",
+            VaultError::ReadConfig(..) => "read_config",
+            VaultError::ParseConfig(..) => "parse_config",
+            VaultError::Locked(..) => "locked",
+            VaultError::ParseVault(..) => "parse_vault",
+            VaultError::Serialize(..) => "serialize",
+            VaultError::DecodeVault(..) => "decode_vault",
+            VaultError::EncodeVault(..) => "encode_vault",
+            VaultError::SerializeExport(..) => "serialize_export",
+            VaultError::MissingSalt => "missing_salt",
+            VaultError::CorruptSalt => "corrupt_salt",
+            VaultError::KeyDerivation(..) => "key_derivation",
+            VaultError::Encryption => "encryption",
+            VaultError::Entropy(_) => "entropy",
+            VaultError::WrongPassword => "wrong_password",
+            VaultError::WrongPasswordOrKeyFile => "wrong_password_or_key_file",
+            VaultError::AttemptsExhausted(_) => "attempts_exhausted",
+            VaultError::VaultWiped(_) => "vault_wiped",
+            VaultError::LockoutLocked => "lockout_locked",
+            VaultError::KeyFileRequired => "key_file_required",
+            VaultError::KeyFileNotUsed => "key_file_not_used",
+            VaultError::ReadKeyFile(..) => "read_key_file",
+            VaultError::PasswordMismatch => "password_mismatch",
+            VaultError::UndecryptableNotes(..) => "undecryptable_notes",
+            VaultError::AmbiguousTitle(..) => "ambiguous_title",
+            VaultError::AmbiguousMatch(..) => "ambiguous_match",
+            VaultError::NoteNotFound(..) => "note_not_found",
+            VaultError::NotInTrash(..) => "not_in_trash",
+            VaultError::NoteUndecryptable => "note_undecryptable",
+            VaultError::VersionUndecryptable(..) => "version_undecryptable",
+            VaultError::TitleExists(..) => "title_exists",
+            VaultError::NoSuchVersion(..) => "no_such_version",
+            VaultError::NoteExists(..) => "note_exists",
+            VaultError::WriteOutput(..) => "write_output",
+            VaultError::ReadAttachment(..) => "read_attachment",
+            VaultError::CorruptAttachment(..) => "corrupt_attachment",
+            VaultError::BinaryNote(..) => "binary_note",
+            VaultError::NoteTooLarge(..) => "note_too_large",
+            VaultError::NotTotp(..) => "not_totp",
+            VaultError::InvalidImport(..) => "invalid_import",
+            VaultError::InvalidTotpSecret(..) => "invalid_totp_secret",
+            VaultError::Clipboard(..) => "clipboard",
+            VaultError::NoConfigDir => "no_config_dir",
+            VaultError::InvalidProfile(..) => "invalid_profile",
+            VaultError::ReadProfiles(..) => "read_profiles",
+            VaultError::NewerVault => "newer_vault",
+            VaultError::NoVault(_) => "no_vault",
+            VaultError::VaultExists(_) => "vault_exists",
+            VaultError::SealedBlob => "sealed_blob",
+            VaultError::LockedVaultUndecryptable => "locked_vault_undecryptable",
+            VaultError::AuditUndecryptable => "audit_undecryptable",
+            VaultError::LockLegacy => "lock_legacy",
+            VaultError::VerifyFailed(..) => "verify_failed",
+            VaultError::SelfTestFailed(..) => "self_test_failed",
+            VaultError::Agent(..) => "agent",
+            VaultError::AgentUnsupported => "agent_unsupported",
+            VaultError::Backup(..) => "backup",
+            VaultError::MergeIntoSelf => "merge_into_self",
+            VaultError::SeveralVaults => "several_vaults",
+            VaultError::VaultSymlink(_) => "vault_symlink",
+            VaultError::ReadImport(..) => "read_import",
+            VaultError::ParseImport(..) => "parse_import",
+            VaultError::InvalidRegex(..) => "invalid_regex",
+            VaultError::Shell(..) => "shell",
         }
+    }
 
-        VaultCommands::Read { title } => {
-            if let Some(note) = notes.iter().find(|n| n.title == title) {
-                match decrypt_note_content(&note.content, &note.nonce, &key) {
-                    Some(decrypted) => println!("🔓 Content: {}", decrypted),
-                    None => println!("❌ Failed to decrypt. Wrong password?"),
-                }
-            } else {
-                println!("❌ Note not found.");
+    /// The error as one JSON object for `--json-errors`: its kind, message and exit code,
+    /// plus the note title or file path it concerns where there is one
+    fn to_json(&self) -> serde_json::Value {
+        let mut json = serde_json::json!({
+            "error": self.kind(),
+            "message": self.to_string(),
+            "exit_code": self.exit_code(),
+        });
+        match self {
+            VaultError::NoteNotFound(title)
+            | VaultError::NotInTrash(title)
+            | VaultError::TitleExists(title)
+            | VaultError::NoteExists(title)
+            | VaultError::CorruptAttachment(title)
+            | VaultError::BinaryNote(title)
+            | VaultError::NotTotp(title)
+            | VaultError::InvalidTotpSecret(title) => json["title"] = title.as_str().into(),
+            VaultError::NoteTooLarge(title, size, limit) => {
+                json["title"] = title.as_str().into();
+                json["size"] = (*size).into();
+                json["limit"] = (*limit).into();
+            }
+            VaultError::NoSuchVersion(title, version, _) => {
+                json["title"] = title.as_str().into();
+                json["version"] = (*version).into();
+            }
+            VaultError::AmbiguousTitle(title, ids) => {
+                json["title"] = title.as_str().into();
+                json["ids"] = ids.as_slice().into();
             }
+            VaultError::AmbiguousMatch(query, titles) => {
+                json["title"] = query.as_str().into();
+                json["candidates"] = titles.as_slice().into();
+            }
+            VaultError::ReadPasswordFile(path, _)
+            | VaultError::ReadVault(path, _)
+            | VaultError::WriteVault(path, _)
+            | VaultError::ReadConfig(path, _)
+            | VaultError::ParseConfig(path, _)
+            | VaultError::Locked(path, _)
+            | VaultError::ParseVault(path, _)
+            | VaultError::DecodeVault(path, _)
+            | VaultError::NoVault(path)
+            | VaultError::VaultWiped(path)
+            | VaultError::VaultSymlink(path)
+            | VaultError::VaultExists(path)
+            | VaultError::ReadKeyFile(path, _)
+            | VaultError::WriteOutput(path, _)
+            | VaultError::ReadAttachment(path, _)
+            | VaultError::ReadProfiles(path, _)
+            | VaultError::Backup(path, _)
+            | VaultError::ReadImport(path, _)
+            | VaultError::ParseImport(path, _) => json["path"] = path.display().to_string().into(),
+            VaultError::InvalidImport(path, number, _) => {
+                json["path"] = path.display().to_string().into();
+                json["note"] = (*number).into();
+            }
+            _ => {}
         }
+        json
+    }
 
-        VaultCommands::Delete { title } => {
-            let len_before = notes.len();
+    /// Process exit status for this error, so scripts can tell failures apart:
+    /// 2 wrong password, 3 note not found, 4 vault file I/O, 1 anything else
+    fn exit_code(&self) -> u8 {
+        match self {
+            VaultError::WrongPassword
+            | VaultError::WrongPasswordOrKeyFile
+            | VaultError::AttemptsExhausted(_)
+            | VaultError::VaultWiped(_)
+            | VaultError::KeyFileRequired
+            | VaultError::SealedBlob
+            | VaultError::LockedVaultUndecryptable
+            | VaultError::AuditUndecryptable
+            | VaultError::WatchedVaultRekeyed
+            | VaultError::NoteUndecryptable
+            | VaultError::VersionUndecryptable(_) => 2,
+            VaultError::NoteNotFound(_) | VaultError::NotInTrash(_) | VaultError::NoSuchVersion(..) => 3,
+            VaultError::ReadVault(..) | VaultError::WriteVault(..) | VaultError::Backup(..) | VaultError::NoVault(_) => 4,
+            _ => 1,
+        }
+    }
+}
 
-            // Keep only notes we *don't* want to delete
-            notes.retain(|note| {
-                if note.title == title {
-                    match decrypt_note_content(&note.content, &note.nonce, &key) {
-                        Some(_) => {
-                            println!("🗑️ Note '{}' deleted.", note.title);
-                            false // Delete this note
-                        }
-                        None => {
-                            println!("❌ Cannot delete '{}': Wrong password.", note.title);
-                            true // Keep this note
-                        }
-                    }
-                } else {
-                    true // Keep all other notes
-                }
-            });
+impl std::error::Error for VaultError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            VaultError::PasswordInput(err) | VaultError::ReadStdin(err) | VaultError::Editor(err) => Some(err),
+            VaultError::ReadVault(_, err) | VaultError::WriteVault(_, err) => Some(err),
+            VaultError::WriteOutput(_, err) | VaultError::ReadImport(_, err) | VaultError::ReadAttachment(_, err) => Some(err),
+            VaultError::ReadKeyFile(_, err) | VaultError::ReadConfig(_, err) => Some(err),
+            VaultError::Entropy(err) => Some(err),
+            VaultError::ParseConfig(_, err) => Some(err),
+            VaultError::DecodeVault(_, err) => Some(err),
+            VaultError::EncodeVault(err) => Some(err),
+            VaultError::ParseVault(_, err) | VaultError::ParseImport(_, err) | VaultError::Serialize(err) => Some(err),
+            VaultError::InvalidRegex(err) => Some(err),
+            VaultError::Watch(err) => Some(err),
+            _ => None,
+        }
+    }
+}
 
-            if notes.len() < len_before {
-                save_notes(&notes);
-            } else {
-                println!("❌ Note not found or password mismatch.");
+// ----------------- Output -----------------
+
+const QUIET: u8 = 0; // Errors only
+const NORMAL: u8 = 1; // Status messages
+const VERBOSE: u8 = 2; // Status messages plus details
+
+/// Output level, set once from `--quiet`/`--verbose` before any command runs (`self-test`
+/// silences it while each step runs)
+static VERBOSITY: AtomicU8 = AtomicU8::new(NORMAL);
+
+fn verbosity() -> u8 {
+    VERBOSITY.load(Ordering::Relaxed)
+}
+
+/// A progress bar on stderr counting up to `len` notes. It stays hidden for small jobs, under
+/// `--quiet`, and when stderr isn't a terminal, so scripts and logs never see it.
+fn progress_bar(len: usize, action: &'static str) -> indicatif::ProgressBar {
+    if len < PROGRESS_THRESHOLD || verbosity() == QUIET || !io::stderr().is_terminal() {
+        return indicatif::ProgressBar::hidden();
+    }
+    let style = indicatif::ProgressStyle::with_template("{msg} [{bar:30}] {pos}/{len} notes")
+        .expect("valid template")
+        .progress_chars("=> ");
+    indicatif::ProgressBar::new(len as u64).with_style(style).with_message(action)
+}
+
+/// Set from `--dry-run` for commands that write the vault; `save_notes` then writes nothing
+static DRY_RUN: AtomicBool = AtomicBool::new(false);
+
+fn dry_run() -> bool {
+    DRY_RUN.load(Ordering::Relaxed)
+}
+
+/// Set from `--audit` or `audit = true` in vault.toml; reads, edits and deletes are then logged
+static AUDIT: AtomicBool = AtomicBool::new(false);
+
+fn auditing() -> bool {
+    // Tests turn auditing on for their own thread only (see `tests::audited`)
+    #[cfg(test)]
+    if tests::AUDITING.get() {
+        return true;
+    }
+    AUDIT.load(Ordering::Relaxed)
+}
+
+/// Seconds a password prompt waits for input, from `--password-confirm-timeout`; 0 waits forever
+static PASSWORD_TIMEOUT: AtomicU64 = AtomicU64::new(0);
+
+/// Set from `--json-errors`; `main` then reports the final error as JSON
+static JSON_ERRORS: AtomicBool = AtomicBool::new(false);
+
+/// Set from `--no-emoji` or `VAULT_NO_EMOJI`; messages then lead with ASCII tags instead of emoji
+static NO_EMOJI: AtomicBool = AtomicBool::new(false);
+
+/// ASCII stand-ins for the symbols that lead messages, used with `--no-emoji`
+const PLAIN_SYMBOLS: &[(char, &str)] = &[
+    ('✅', "[ok]"),
+    ('❌', "[error]"),
+    ('⚠', "[warning]"),
+    ('💡', "[hint]"),
+    ('🔑', "[password]"),
+    ('🗝', "[key file]"),
+    ('📝', "[input]"),
+    ('📌', "[note]"),
+    ('📦', "[archived]"),
+    ('🔗', "[links]"),
+    ('📜', "[audit]"),
+    ('⭐', "[pinned]"),
+    ('🆔', "[id]"),
+    ('🕒', "[time]"),
+    ('🏷', "[tags]"),
+    ('📑', "[copied]"),
+    ('⌛', "[expiry]"),
+    ('📏', "[size]"),
+    ('📎', "[attachment]"),
+    ('🔓', "[unlocked]"),
+    ('🔒', "[sealed]"),
+    ('🔐', "[crypto]"),
+    ('🛡', "[lockout]"),
+    ('✏', "[updated]"),
+    ('🕘', "[history]"),
+    ('⏪', "[reverted]"),
+    ('🗑', "[deleted]"),
+    ('♻', "[restored]"),
+    ('🔥', "[purged]"),
+    ('🧪', "[self-test]"),
+    ('👀', "[watch]"),
+    ('🔢', "[totp]"),
+    ('❎', "[cancelled]"),
+    ('⏭', "[skipped]"),
+    ('🔎', "[search]"),
+    ('🔍', "[match]"),
+    ('↳', "->"),
+    ('…', "..."),
+    ('📭', "[empty]"),
+    ('📋', "[clipboard]"),
+    ('⏳', "[wait]"),
+    ('🧹', "[cleanup]"),
+    ('💾', "[saved]"),
+    ('📂', "[file]"),
+    ('⚙', "[config]"),
+    ('📁', "[profile]"),
+    ('📚', "[loaded]"),
+    ('🔁', "[rekeyed]"),
+    ('📤', "[exported]"),
+    ('📥', "[imported]"),
+    ('🔀', "[merged]"),
+    ('📊', "[stats]"),
+    ('🩺', "[check]"),
+    ('🕵', "[agent]"),
+    ('🛑', "[stopped]"),
+    ('🐚', "[shell]"),
+    ('👋', "[bye]"),
+];
+
+/// Swap the symbol leading a message (after any indentation) for its ASCII tag under `--no-emoji`.
+/// Only the leading symbol is touched, so note titles and content print as they are.
+fn decorate(message: &str) -> Cow<'_, str> {
+    if !NO_EMOJI.load(Ordering::Relaxed) {
+        return Cow::Borrowed(message);
+    }
+    let text = message.trim_start_matches(' ');
+    let indent = &message[..message.len() - text.len()];
+    let mut chars = text.chars();
+    let Some(tag) = chars.next().and_then(|symbol| PLAIN_SYMBOLS.iter().find(|(s, _)| *s == symbol)).map(|(_, tag)| tag)
+    else {
+        return Cow::Borrowed(message);
+    };
+    let rest = chars.as_str();
+    let rest = rest.strip_prefix('\u{fe0f}').unwrap_or(rest); // Emoji presentation selector, as in "⚠️"
+    Cow::Owned(format!("{indent}{tag}{rest}"))
+}
+
+/// Print a status message to stdout unless `--quiet`, marked `[dry-run]` when nothing is written
+macro_rules! say {
+    ($($arg:tt)*) => {
+        if verbosity() >= NORMAL {
+            if dry_run() {
+                print!("[dry-run] ");
+            }
+            println!("{}", decorate(&format!($($arg)*)));
+        }
+    };
+}
+
+/// Print extra detail to stdout, only with `--verbose`
+macro_rules! detail {
+    ($($arg:tt)*) => {
+        if verbosity() >= VERBOSE {
+            println!("{}", decorate(&format!($($arg)*)));
+        }
+    };
+}
+
+/// Print a warning to stderr unless `--quiet`
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        if verbosity() >= NORMAL {
+            eprintln!("{}", decorate(&format!($($arg)*)));
+        }
+    };
+}
+
+/// Print an error to stderr, whatever the verbosity
+macro_rules! error {
+    ($($arg:tt)*) => {
+        eprintln!("{}", decorate(&format!($($arg)*)))
+    };
+}
+
+// ----------------- Utility Functions -----------------
+
+/// Prompt the user to enter a password silently.
+/// The prompt goes to stderr so stdout stays clean for piping.
+fn prompt_password(prompt: &str) -> Result<Zeroizing<String>, VaultError> {
+    #[cfg(test)]
+    if let Some(answer) = tests::scripted_answer(VaultError::NoTerminal) {
+        return answer.map(Zeroizing::new);
+    }
+    // With stdin redirected (cron, CI, a script, `< /dev/null`) the prompt may go unseen, so
+    // fail now instead of waiting on the terminal forever, like `confirm`
+    if !io::stdin().is_terminal() {
+        return Err(VaultError::NoTerminal);
+    }
+    eprint!("{}", decorate(prompt));
+    io::stderr().flush().map_err(VaultError::PasswordInput)?; // Ensure prompt shows before input
+    let timeout = PASSWORD_TIMEOUT.load(Ordering::Relaxed);
+    if timeout == 0 {
+        return read_password().map(Zeroizing::new).map_err(VaultError::PasswordInput);
+    }
+
+    // `read_password` can't be interrupted, so it runs on a thread that is abandoned on timeout.
+    // It turned echo off; the settings saved here turn it back on. The abandoned thread goes on
+    // reading the terminal until the process exits, so a timeout must end the process: callers
+    // return the error, and `vault shell` leaves on it rather than lose its next line.
+    #[cfg(unix)]
+    let terminal = File::open("/dev/tty")
+        .ok()
+        .and_then(|tty| rustix::termios::tcgetattr(&tty).ok().map(|settings| (tty, settings)));
+    let (sender, receiver) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        let _ = sender.send(read_password().map(Zeroizing::new));
+    });
+    match receiver.recv_timeout(std::time::Duration::from_secs(timeout)) {
+        Ok(password) => password.map_err(VaultError::PasswordInput),
+        Err(_) => {
+            #[cfg(unix)]
+            if let Some((tty, settings)) = terminal {
+                let _ = rustix::termios::tcsetattr(&tty, rustix::termios::OptionalActions::Now, &settings);
+            }
+            eprintln!();
+            Err(VaultError::PasswordTimeout(timeout))
+        }
+    }
+}
+
+/// Remove one trailing line ending (`\n` or `\r\n`) in place
+fn strip_line_ending(line: &mut String) {
+    if line.ends_with('\n') {
+        line.pop();
+        if line.ends_with('\r') {
+            line.pop();
+        }
+    }
+}
+
+/// Get the master password from `--password-stdin`, `--password-file` or, by default, the terminal.
+/// Only the first line is used, so stdin can carry note content (`-`) after the password.
+fn read_master_password(args: &Args) -> Result<Zeroizing<String>, VaultError> {
+    if args.password_stdin {
+        let mut line = Zeroizing::new(String::new());
+        if io::stdin().read_line(&mut line).map_err(VaultError::ReadStdin)? == 0 {
+            return Err(VaultError::NoPasswordOnStdin);
+        }
+        strip_line_ending(&mut line);
+        return Ok(line);
+    }
+    if let Some(path) = &args.password_file {
+        let file_err = |err| VaultError::ReadPasswordFile(path.clone(), err);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(path).map_err(file_err)?.permissions().mode();
+            if mode & 0o004 != 0 {
+                warn!("⚠️ Password file '{}' is readable by every user; restrict it with `chmod 600`.", path.display());
             }
         }
+        let contents = Zeroizing::new(std::fs::read_to_string(path).map_err(file_err)?);
+        return Ok(Zeroizing::new(contents.lines().next().unwrap_or_default().to_string()));
+    }
+    prompt_password("🔑 Enter password: ")
+}
+
+/// `read_master_password` for a vault being created. A typo here would lock the new vault
+/// for good, so a password typed at the prompt is asked for twice.
+fn read_new_master_password(args: &Args) -> Result<Zeroizing<String>, VaultError> {
+    let password = read_master_password(args)?;
+    let explicit = args.password_stdin || args.password_file.is_some();
+    if !explicit && prompt_password("🔑 Confirm password: ")? != password {
+        return Err(VaultError::PasswordMismatch);
+    }
+    Ok(password)
+}
+
+/// Warn (without refusing it) when zxcvbn rates a new vault's master password as guessable
+fn warn_if_weak(password: &str) {
+    let estimate = zxcvbn::zxcvbn(password, &[]);
+    if estimate.score() >= zxcvbn::Score::Three {
+        return;
+    }
+    warn!("⚠️ This master password is weak; a long passphrase of several random words is much harder to crack.");
+    if let Some(feedback) = estimate.feedback() {
+        if let Some(warning) = feedback.warning() {
+            warn!("   {}", warning);
+        }
+        for suggestion in feedback.suggestions() {
+            warn!("   💡 {}", suggestion);
+        }
+    }
+}
+
+/// Ask a yes/no question on the terminal; only `y`/`yes` counts as agreement.
+/// Fails instead of hanging when stdin isn't a terminal.
+fn confirm(question: &str) -> Result<bool, VaultError> {
+    let answer = ask(&format!("{question} [y/N] "))?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+/// Ask on the terminal for `phrase` to be typed exactly, for actions too drastic for a y/N answer
+fn confirm_phrase(question: &str, phrase: &str) -> Result<bool, VaultError> {
+    let answer = ask(&format!("{question} "))?;
+    Ok(answer.trim_end_matches(['\r', '\n']) == phrase)
+}
+
+/// Print `prompt` to stderr and read one line of the answer from the terminal
+fn ask(prompt: &str) -> Result<String, VaultError> {
+    #[cfg(test)]
+    if let Some(answer) = tests::scripted_answer(VaultError::ConfirmationRequired) {
+        return answer;
+    }
+    if !io::stdin().is_terminal() {
+        return Err(VaultError::ConfirmationRequired);
+    }
+    eprint!("{prompt}");
+    io::stderr().flush().map_err(VaultError::ReadStdin)?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer).map_err(VaultError::ReadStdin)?;
+    Ok(answer)
+}
+
+/// Ask for the master password again before a sensitive note is shown, whatever unlocked the
+/// vault (a typed password, `--password-file` or the agent); it must give the same `key`
+fn confirm_password(vault: &Vault, key: &GenericArray<u8, typenum::U32>, title: &str, args: &Args) -> Result<(), VaultError> {
+    let password = prompt_password(&format!("🔐 '{title}' is sensitive; enter the password again to show it: "))?;
+    let key_file = args.key_file.as_deref().map(read_key_file).transpose()?;
+    let again = derive_vault_key(vault, &password, key_file.as_ref().map(|digest| digest.as_slice()))?;
+    if again.as_slice() != key.as_slice() {
+        return Err(if vault.key_file { VaultError::WrongPasswordOrKeyFile } else { VaultError::WrongPassword });
+    }
+    Ok(())
+}
+
+/// Resolve a content argument: `-` means read the whole of stdin, verbatim
+fn read_content_arg(content: String) -> Result<String, VaultError> {
+    if content != "-" {
+        return Ok(content);
+    }
+    let mut buffer = String::new();
+    io::stdin().read_to_string(&mut buffer).map_err(VaultError::ReadStdin)?;
+    Ok(buffer)
+}
+
+/// Open `$EDITOR` (or a platform default) on a private temp file pre-filled with `initial`,
+/// returning whatever the user saved. The temp file is removed on drop, even on error.
+fn compose_in_editor(initial: &str) -> Result<String, VaultError> {
+    let mut file = tempfile::Builder::new()
+        .prefix("vault-")
+        .suffix(".txt")
+        .tempfile() // Created with owner-only permissions
+        .map_err(VaultError::Editor)?;
+    file.write_all(initial.as_bytes()).map_err(VaultError::Editor)?;
+    file.flush().map_err(VaultError::Editor)?;
+
+    let default_editor = if cfg!(windows) { "notepad" } else { "vi" };
+    let editor = std::env::var("EDITOR")
+        .ok()
+        .filter(|e| !e.trim().is_empty())
+        .unwrap_or_else(|| default_editor.to_string());
+    // Allow editors with arguments, e.g. EDITOR="code --wait"
+    let mut parts = editor.split_whitespace();
+    let program = parts.next().unwrap_or(default_editor);
+
+    let status = std::process::Command::new(program)
+        .args(parts)
+        .arg(file.path())
+        .status()
+        .map_err(VaultError::Editor)?;
+    if !status.success() {
+        return Err(VaultError::EditorFailed(status));
+    }
+    std::fs::read_to_string(file.path()).map_err(VaultError::Editor)
+}
+
+/// Get the content for `New`/`Edit`: from the argument, from stdin for `-`, typed at the
+/// terminal with `prompt`, or from `$EDITOR` (starting from `initial`) when asked or when no
+/// content was given
+fn resolve_content(content: Option<String>, editor: bool, prompt: bool, initial: &str) -> Result<String, VaultError> {
+    match content {
+        _ if prompt => read_content_prompt(),
+        Some(content) if !editor => read_content_arg(content),
+        _ => compose_in_editor(initial),
+    }
+}
+
+/// Read note content typed at the terminal, any number of lines, until EOF (Ctrl-D).
+/// Unlike a content argument it never appears in argv or shell history.
+fn read_content_prompt() -> Result<String, VaultError> {
+    if io::stdin().is_terminal() {
+        eprintln!("{}", decorate("📝 Type the note content, then press Ctrl-D on an empty line:"));
+    }
+    let mut buffer = String::new();
+    io::stdin().read_to_string(&mut buffer).map_err(VaultError::ReadStdin)?;
+    Ok(buffer)
+}
+
+/// Create (or truncate) a file only the current user can read, for plaintext output
+fn create_private_file(path: &Path) -> io::Result<File> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)
+}
+
+/// Puts note `name`'s `text` on the system clipboard. With `clear_after`, blocks for that long
+/// and then clears the clipboard, unless something else has been copied in the meantime.
+#[cfg(feature = "clipboard")]
+fn copy_to_clipboard(name: &str, text: &str, clear_after: Option<u64>) -> Result<(), VaultError> {
+    let mut clipboard = arboard::Clipboard::new().map_err(clipboard_error)?;
+    copy_to(&mut clipboard, name, text, clear_after)
+}
+
+/// `copy_to_clipboard` on `clipboard`
+#[cfg(feature = "clipboard")]
+fn copy_to(clipboard: &mut impl Clipboard, name: &str, text: &str, clear_after: Option<u64>) -> Result<(), VaultError> {
+    clipboard.set_text(text)?;
+    say!("📋 Copied '{}' to clipboard.", name);
+    if let Some(secs) = clear_after {
+        // On X11 the content is only served while this process is alive, so sleeping also keeps it pasteable
+        say!("⏳ Clearing the clipboard in {secs}s (Ctrl-C to leave it)...");
+        std::thread::sleep(std::time::Duration::from_secs(secs));
+        if clipboard.get_text().is_ok_and(|current| current == text) {
+            clipboard.clear()?;
+            say!("🧹 Clipboard cleared.");
+        }
+    }
+    Ok(())
+}
+
+/// What `copy_to_clipboard` needs from a clipboard: the system one (`arboard`), or a stand-in in tests
+#[cfg(feature = "clipboard")]
+trait Clipboard {
+    fn set_text(&mut self, text: &str) -> Result<(), VaultError>;
+    fn get_text(&mut self) -> Result<String, VaultError>;
+    fn clear(&mut self) -> Result<(), VaultError>;
+}
+
+#[cfg(feature = "clipboard")]
+fn clipboard_error(err: arboard::Error) -> VaultError {
+    VaultError::Clipboard(err.to_string())
+}
+
+#[cfg(feature = "clipboard")]
+impl Clipboard for arboard::Clipboard {
+    fn set_text(&mut self, text: &str) -> Result<(), VaultError> {
+        arboard::Clipboard::set_text(self, text).map_err(clipboard_error)
+    }
+    fn get_text(&mut self) -> Result<String, VaultError> {
+        arboard::Clipboard::get_text(self).map_err(clipboard_error)
+    }
+    fn clear(&mut self) -> Result<(), VaultError> {
+        arboard::Clipboard::clear(self).map_err(clipboard_error)
+    }
+}
+
+#[cfg(not(feature = "clipboard"))]
+fn copy_to_clipboard(_name: &str, _text: &str, _clear_after: Option<u64>) -> Result<(), VaultError> {
+    Err(VaultError::Clipboard("this build has no clipboard support (enable the `clipboard` feature)".to_string()))
+}
+
+/// Directory holding profile vaults: `~/.config/vault` on Linux (honouring `$XDG_CONFIG_HOME`),
+/// the platform's application config directory elsewhere
+fn profile_dir() -> Result<PathBuf, VaultError> {
+    directories::ProjectDirs::from("", "", "vault")
+        .map(|dirs| dirs.config_dir().to_path_buf())
+        .ok_or(VaultError::NoConfigDir)
+}
+
+/// Read `vault.toml` from the vault's directory or, failing that, the config directory.
+/// Without either, the built-in defaults apply.
+fn load_config(vault_path: &Path) -> Result<Config, VaultError> {
+    let next_to_vault = vault_path.parent().unwrap_or(Path::new("")).join(CONFIG_FILE);
+    let candidates = [Some(next_to_vault), profile_dir().ok().map(|dir| dir.join(CONFIG_FILE))];
+    for path in candidates.into_iter().flatten() {
+        match std::fs::read_to_string(&path) {
+            Ok(text) => {
+                detail!("⚙️ Using settings from '{}'.", path.display());
+                return toml::from_str(&text).map_err(|err| VaultError::ParseConfig(path, err));
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(VaultError::ReadConfig(path, err)),
+        }
+    }
+    Ok(Config::default())
+}
+
+/// Vault file for profile `name`. Names must be plain file names so a profile can't point
+/// outside the profile directory.
+fn profile_path(name: &str) -> Result<PathBuf, VaultError> {
+    let plain = !name.is_empty()
+        && name != "."
+        && name != ".."
+        && !name.contains(['/', '\\']);
+    if !plain {
+        return Err(VaultError::InvalidProfile(name.to_string()));
+    }
+    Ok(profile_dir()?.join(format!("{name}.{PROFILE_EXTENSION}")))
+}
+
+/// Pick the vault file: `--profile`, then `--vault`, then `$VAULT_FILE`, then `./vault.json`
+/// (clap rejects `--profile` together with `--vault`)
+fn resolve_vault_path(flag: Option<PathBuf>, profile: Option<&str>) -> Result<PathBuf, VaultError> {
+    if let Some(name) = profile {
+        return profile_path(name);
+    }
+    let path = flag
+        .or_else(|| {
+            std::env::var_os(VAULT_FILE_ENV)
+                .filter(|v| !v.is_empty())
+                .map(PathBuf::from)
+        })
+        .unwrap_or_else(|| PathBuf::from(VAULT_FILE));
+
+    // Resolve relative paths against the current directory
+    Ok(std::path::absolute(&path).unwrap_or(path))
+}
+
+/// Refuse a vault path that is a symbolic link, so nothing is read or written through a link
+/// by accident. With `follow` the link's target is used instead, and saving replaces the
+/// target, so the link keeps pointing at the vault.
+fn check_symlink(path: PathBuf, follow: bool) -> Result<PathBuf, VaultError> {
+    if !std::fs::symlink_metadata(&path).is_ok_and(|metadata| metadata.file_type().is_symlink()) {
+        return Ok(path);
+    }
+    if !follow {
+        return Err(VaultError::VaultSymlink(path));
+    }
+    if let Ok(target) = std::fs::canonicalize(&path) {
+        return Ok(target);
+    }
+    // A dangling link (e.g. before `init`): its target, relative to the link's directory
+    match std::fs::read_link(&path) {
+        Ok(target) => Ok(path.parent().map(|dir| dir.join(&target)).unwrap_or(target)),
+        Err(_) => Ok(path),
+    }
+}
+
+/// Names of the profile vaults in `dir`, sorted
+fn list_profiles(dir: &Path) -> Result<Vec<String>, VaultError> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(VaultError::ReadProfiles(dir.to_path_buf(), err)),
+    };
+    let mut names = Vec::new();
+    for entry in entries {
+        let path = entry.map_err(|err| VaultError::ReadProfiles(dir.to_path_buf(), err))?.path();
+        if path.is_file()
+            && path.extension().is_some_and(|ext| ext == PROFILE_EXTENSION)
+            && let Some(name) = path.file_stem().and_then(|stem| stem.to_str())
+            && !is_backup_name(name)
+        {
+            names.push(name.to_string());
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+/// Whether the file stem `name` is a backup's (`<stem>-<timestamp>[-<counter>]`), as `backup`
+/// writes them next to a profile vault when no directory is given
+fn is_backup_name(name: &str) -> bool {
+    name.match_indices('-').any(|(at, _)| backup_sort_key(&name[at + 1..]).is_some())
+}
+
+/// Fill `bytes` from the OS RNG (in tests, from the RNG `tests::with_rng` put in its place).
+/// An RNG that can't deliver is an error, never a silently weaker salt or nonce.
+fn fill_random(bytes: &mut [u8]) -> Result<(), VaultError> {
+    #[cfg(test)]
+    if let Some(result) = tests::fill_from_test_rng(bytes) {
+        return result;
+    }
+    OsRng.try_fill_bytes(bytes).map_err(VaultError::Entropy)
+}
+
+/// Generate a fresh random salt for a new vault
+fn generate_salt() -> Result<[u8; SALT_LEN], VaultError> {
+    let mut salt = [0u8; SALT_LEN];
+    fill_random(&mut salt)?;
+    Ok(salt)
+}
+
+/// Derives a 256-bit AES key from a password and salt using Argon2id with the given costs.
+/// With a key file, its digest (see `read_key_file`) is appended to the password first.
+/// The key is wiped from memory when dropped.
+fn derive_key_from_password(
+    password: &str,
+    salt: &[u8],
+    params: KdfParams,
+    key_file: Option<&[u8]>,
+) -> Result<Zeroizing<GenericArray<u8, typenum::U32>>, VaultError> {
+    let params = Params::new(params.memory_kib, params.iterations, params.parallelism, Some(32))
+        .map_err(VaultError::KeyDerivation)?;
+    let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, params);
+
+    let mut secret = Zeroizing::new(password.as_bytes().to_vec());
+    secret.extend_from_slice(key_file.unwrap_or_default());
+    let mut key = Zeroizing::new(GenericArray::default()); // Required format for AES-GCM
+    argon2
+        .hash_password_into(&secret, salt, key.as_mut_slice())
+        .map_err(VaultError::KeyDerivation)?;
+    Ok(key)
+}
+
+/// SHA-256 of a key file's contents, so a file of any size adds a fixed 32 bytes to the password
+fn read_key_file(path: &Path) -> Result<Zeroizing<Vec<u8>>, VaultError> {
+    let contents = Zeroizing::new(std::fs::read(path).map_err(|err| VaultError::ReadKeyFile(path.to_path_buf(), err))?);
+    if contents.is_empty() {
+        return Err(VaultError::ReadKeyFile(path.to_path_buf(), io::Error::other("the file is empty")));
+    }
+    Ok(Zeroizing::new(Sha256::digest(&*contents).to_vec()))
+}
+
+/// Derives `vault`'s key from `password` (and `key_file`, a `read_key_file` digest) with the
+/// vault's own key-derivation scheme. A key file must be given exactly when the vault uses one.
+fn derive_vault_key(
+    vault: &Vault,
+    password: &str,
+    key_file: Option<&[u8]>,
+) -> Result<Zeroizing<GenericArray<u8, typenum::U32>>, VaultError> {
+    match (vault.key_file, key_file.is_some()) {
+        (true, false) => return Err(VaultError::KeyFileRequired),
+        (false, true) if !vault.locked => return Err(VaultError::KeyFileNotUsed), // A locked file's flag is only known once unlocked
+        _ => {}
+    }
+    match vault.kdf {
+        Kdf::Argon2id => {
+            // Notes without a salt can't have been keyed by Argon2, so don't pretend
+            if vault.salt.is_empty() {
+                return Err(VaultError::MissingSalt);
+            }
+            match general_purpose::STANDARD.decode(&vault.salt) {
+                Ok(salt) if salt.len() == SALT_LEN => derive_key_from_password(password, &salt, vault.argon2, key_file),
+                _ => Err(VaultError::CorruptSalt),
+            }
+        }
+        Kdf::LegacySha256 => Ok(derive_legacy_key(password)),
+    }
+}
+
+/// Derives the key of a vault that still uses the original unsalted SHA-256 scheme
+fn derive_legacy_key(password: &str) -> Zeroizing<GenericArray<u8, typenum::U32>> {
+    Zeroizing::new(Sha256::digest(password.as_bytes()))
+}
+
+/// Encrypt text with `cipher` and return (ciphertext_base64, nonce_base64)
+fn encrypt_note_content(content: &str, cipher: Cipher, key: &GenericArray<u8, typenum::U32>) -> Result<(String, String), VaultError> {
+    encrypt_bytes(content.as_bytes(), cipher, key)
+}
+
+/// Encrypt raw bytes with `cipher` and return (ciphertext_base64, nonce_base64)
+fn encrypt_bytes(plaintext: &[u8], cipher: Cipher, key: &GenericArray<u8, typenum::U32>) -> Result<(String, String), VaultError> {
+    // Generate a random 96-bit (12-byte) nonce
+    let mut nonce_bytes = [0u8; NONCE_LEN];
+    fill_random(&mut nonce_bytes)?;
+    let nonce = Nonce::from_slice(&nonce_bytes);
+
+    // Encrypt the content
+    let ciphertext = match cipher {
+        Cipher::Aes256Gcm => Aes256Gcm::new(key).encrypt(nonce, plaintext),
+        Cipher::ChaCha20Poly1305 => ChaCha20Poly1305::new(key).encrypt(nonce, plaintext),
+    }
+    .map_err(|_| VaultError::Encryption)?;
+
+    Ok((
+        general_purpose::STANDARD.encode(&ciphertext),
+        general_purpose::STANDARD.encode(nonce_bytes),
+    ))
+}
+
+/// Decrypts text with `cipher`, returning the original plaintext if successful
+fn decrypt_note_content(ciphertext_b64: &str, nonce_b64: &str, cipher: Cipher, key: &GenericArray<u8, typenum::U32>) -> Option<String> {
+    String::from_utf8(decrypt_bytes(ciphertext_b64, nonce_b64, cipher, key)?).ok()
+}
+
+/// Decrypts raw bytes with `cipher`; `None` for a wrong key or a damaged ciphertext or nonce
+fn decrypt_bytes(ciphertext_b64: &str, nonce_b64: &str, cipher: Cipher, key: &GenericArray<u8, typenum::U32>) -> Option<Vec<u8>> {
+    // Decode base64 strings back into bytes
+    let ciphertext = general_purpose::STANDARD.decode(ciphertext_b64).ok()?;
+    let nonce_bytes = general_purpose::STANDARD.decode(nonce_b64).ok()?;
+    // `Nonce::from_slice` panics on any other length, e.g. in a corrupted or tampered vault file
+    if nonce_bytes.len() != NONCE_LEN {
+        return None;
+    }
+    let nonce = Nonce::from_slice(&nonce_bytes);
+
+    // Attempt decryption
+    match cipher {
+        Cipher::Aes256Gcm => Aes256Gcm::new(key).decrypt(nonce, ciphertext.as_ref()),
+        Cipher::ChaCha20Poly1305 => ChaCha20Poly1305::new(key).decrypt(nonce, ciphertext.as_ref()),
+    }
+    .ok()
+}
+
+/// Encrypt a note body, deflating it first when it is at least `COMPRESS_THRESHOLD` bytes and
+/// compression actually saves space. Returns (ciphertext_base64, nonce_base64, compressed).
+fn encrypt_body(content: &str, cipher: Cipher, key: &GenericArray<u8, typenum::U32>) -> Result<(String, String, bool), VaultError> {
+    if content.len() >= COMPRESS_THRESHOLD {
+        let mut encoder = flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+        let deflated = encoder.write_all(content.as_bytes()).and_then(|()| encoder.finish());
+        if let Ok(deflated) = deflated
+            && deflated.len() < content.len()
+        {
+            let (ciphertext, nonce) = encrypt_bytes(&deflated, cipher, key)?;
+            return Ok((ciphertext, nonce, true));
+        }
+    }
+    let (ciphertext, nonce) = encrypt_note_content(content, cipher, key)?;
+    Ok((ciphertext, nonce, false))
+}
+
+/// Decrypt a note body, inflating it if it was stored compressed
+fn decrypt_body(note: &Note, key: &GenericArray<u8, typenum::U32>) -> Option<String> {
+    decrypt_stored(&note.content, &note.nonce, note.cipher, note.compressed, key)
+}
+
+/// Reverse `encrypt_body`: decrypt, then inflate if `compressed`
+fn decrypt_stored(ciphertext: &str, nonce: &str, cipher: Cipher, compressed: bool, key: &GenericArray<u8, typenum::U32>) -> Option<String> {
+    if !compressed {
+        return decrypt_note_content(ciphertext, nonce, cipher, key);
+    }
+    let deflated = decrypt_bytes(ciphertext, nonce, cipher, key)?;
+    let mut content = String::new();
+    flate2::read::DeflateDecoder::new(deflated.as_slice()).read_to_string(&mut content).ok()?;
+    Some(content)
+}
+
+/// Encrypt note metadata and return (ciphertext_base64, nonce_base64)
+fn encrypt_note_meta(meta: &NoteMeta, cipher: Cipher, key: &GenericArray<u8, typenum::U32>) -> Result<(String, String), VaultError> {
+    let json = serde_json::to_string(meta).map_err(VaultError::Serialize)?;
+    encrypt_note_content(&json, cipher, key)
+}
+
+/// Decrypts a note's metadata. Notes from older versions keep their title in plaintext
+/// and have no timestamps, so those fields are filled in from the note or left empty.
+fn decrypt_note_meta(note: &Note, key: &GenericArray<u8, typenum::U32>) -> Option<NoteMeta> {
+    let mut meta = if note.meta.is_empty() {
+        NoteMeta::default()
+    } else {
+        let json = decrypt_note_content(&note.meta, &note.meta_nonce, note.cipher, key)?;
+        serde_json::from_str(&json).ok()?
+    };
+    if meta.title.is_empty() {
+        meta.title = note.title.clone();
+    }
+    Some(meta)
+}
+
+/// Generate a fresh ID for a new note
+fn new_note_id() -> String {
+    uuid::Uuid::new_v4().to_string()
+}
+
+/// Encrypt content and metadata into a note with the given ID, each with a fresh nonce
+fn seal_note(id: &str, content: &str, meta: &NoteMeta, cipher: Cipher, key: &GenericArray<u8, typenum::U32>) -> Result<Note, VaultError> {
+    let (content, nonce, compressed) = encrypt_body(content, cipher, key)?;
+    let (meta, meta_nonce) = encrypt_note_meta(meta, cipher, key)?;
+    Ok(Note {
+        id: id.to_string(),
+        title: String::new(), // Titles live in the encrypted metadata
+        content,
+        nonce,
+        meta,
+        meta_nonce,
+        cipher,
+        compressed,
+        history: Vec::new(),
+    })
+}
+
+/// Every nonce in a vault file under its key: the check token, audit log and sealed blob, and every
+/// note and earlier version. Random 96-bit nonces make a repeat astronomically unlikely, but a
+/// repeated nonce under the same key breaks AES-GCM (it leaks the XOR of both plaintexts and
+/// allows forgeries), so the invariant "every nonce in a vault file is unique" is enforced rather
+/// than assumed: everything sealed into a vault is drawn through `fresh`.
+struct UsedNonces(std::collections::HashSet<String>);
+
+impl UsedNonces {
+    /// The nonces of `vault`'s header with those of `notes`, the vault's notes as the caller has them
+    fn of(vault: &Vault, notes: &[Note]) -> Self {
+        let header = [&vault.check_nonce, &vault.audit_nonce, &vault.blob_nonce];
+        let notes = notes.iter().flat_map(|note| {
+            [&note.nonce, &note.meta_nonce].into_iter().chain(note.history.iter().map(|version| &version.nonce))
+        });
+        UsedNonces(header.into_iter().chain(notes).filter(|nonce| !nonce.is_empty()).cloned().collect())
+    }
+
+    /// Run `seal` until none of the `nonces` it drew is already used or repeated, then record them
+    fn fresh<T>(&mut self, mut seal: impl FnMut() -> Result<T, VaultError>, nonces: impl Fn(&T) -> Vec<String>) -> Result<T, VaultError> {
+        loop {
+            let sealed = seal()?;
+            let drawn = nonces(&sealed);
+            let distinct: std::collections::HashSet<&String> = drawn.iter().collect();
+            if distinct.len() == drawn.len() && !drawn.iter().any(|nonce| self.0.contains(nonce)) {
+                self.0.extend(drawn);
+                return Ok(sealed);
+            }
+            warn!("⚠️ Drew a nonce that is already in use; encrypting again with a new one.");
+        }
+    }
+
+    /// A raw nonce that isn't used yet, for the seals that build their own (see `seal_check`)
+    fn draw(&mut self) -> Result<[u8; NONCE_LEN], VaultError> {
+        self.fresh(
+            || {
+                let mut nonce = [0u8; NONCE_LEN];
+                fill_random(&mut nonce)?;
+                Ok(nonce)
+            },
+            |nonce| vec![general_purpose::STANDARD.encode(nonce)],
+        )
+    }
+
+    /// Whether `nonce` (base64, as stored) is already used
+    #[cfg(test)]
+    fn contains(&self, nonce: &str) -> bool {
+        self.0.contains(nonce)
+    }
+}
+
+/// `seal_note` with nonces drawn fresh from `used`
+fn seal_note_fresh(used: &mut UsedNonces, id: &str, content: &str, meta: &NoteMeta, cipher: Cipher, key: &GenericArray<u8, typenum::U32>) -> Result<Note, VaultError> {
+    used.fresh(|| seal_note(id, content, meta, cipher, key), |note| vec![note.nonce.clone(), note.meta_nonce.clone()])
+}
+
+/// `encrypt_note_meta` with a nonce drawn fresh from `used`, for a note whose metadata alone changes
+fn seal_meta_fresh(used: &mut UsedNonces, meta: &NoteMeta, cipher: Cipher, key: &GenericArray<u8, typenum::U32>) -> Result<(String, String), VaultError> {
+    used.fresh(|| encrypt_note_meta(meta, cipher, key), |(_, nonce)| vec![nonce.clone()])
+}
+
+/// `seal_version` with a nonce drawn fresh from `used`
+fn seal_version_fresh(used: &mut UsedNonces, past: &PastVersion, cipher: Cipher, key: &GenericArray<u8, typenum::U32>) -> Result<NoteVersion, VaultError> {
+    used.fresh(|| seal_version(past, cipher, key), |version| vec![version.nonce.clone()])
+}
+
+/// Encrypt an earlier version of a note, compressing it like a note body when that helps
+fn seal_version(past: &PastVersion, cipher: Cipher, key: &GenericArray<u8, typenum::U32>) -> Result<NoteVersion, VaultError> {
+    let json = Zeroizing::new(serde_json::to_string(past).map_err(VaultError::Serialize)?);
+    let (data, nonce, compressed) = encrypt_body(&json, cipher, key)?;
+    Ok(NoteVersion { data, nonce, cipher, compressed })
+}
+
+/// Decrypt an earlier version of a note
+fn open_version(version: &NoteVersion, key: &GenericArray<u8, typenum::U32>) -> Option<PastVersion> {
+    let json = Zeroizing::new(decrypt_stored(&version.data, &version.nonce, version.cipher, version.compressed, key)?);
+    serde_json::from_str(&json).ok()
+}
+
+/// Replace `note` with `content` and `meta`, keeping its history. With `previous`
+/// (what the note held until now), that becomes the newest history entry; beyond
+/// `HISTORY_LEN` the oldest is dropped. New nonces are drawn from `used`.
+fn revise_note(
+    used: &mut UsedNonces,
+    note: &mut Note,
+    previous: Option<PastVersion>,
+    content: &str,
+    meta: &NoteMeta,
+    cipher: Cipher,
+    key: &GenericArray<u8, typenum::U32>,
+) -> Result<(), VaultError> {
+    let mut revised = seal_note_fresh(used, &note.id, content, meta, cipher, key)?;
+    revised.history = std::mem::take(&mut note.history);
+    if let Some(previous) = previous {
+        revised.history.insert(0, seal_version_fresh(used, &previous, cipher, key)?);
+        revised.history.truncate(HISTORY_LEN);
+    }
+    *note = revised;
+    Ok(())
+}
+
+/// Check whether `content` contains `query`, returning the first matching line as a snippet
+fn search_content<'a>(content: &'a str, query: &str, case_sensitive: bool) -> Option<&'a str> {
+    first_match(content, |text| {
+        if case_sensitive {
+            text.contains(query)
+        } else {
+            text.to_lowercase().contains(&query.to_lowercase())
+        }
+    })
+}
+
+/// Check whether `content` matches `pattern`, returning the first matching line as a snippet
+fn search_content_regex<'a>(content: &'a str, pattern: &regex::Regex) -> Option<&'a str> {
+    first_match(content, |text| pattern.is_match(text))
+}
+
+/// The first line of `content` that `matches`, if `content` as a whole matches at all
+fn first_match(content: &str, matches: impl Fn(&str) -> bool) -> Option<&str> {
+    if !matches(content) {
+        return None;
+    }
+    // A query spanning several lines has no single matching line; fall back to the first one
+    Some(content.lines().find(|line| matches(line)).unwrap_or_else(|| content.lines().next().unwrap_or("")))
+}
+
+/// Decrypt a note's content and metadata together; `None` unless both open under `key`
+fn open_note(note: &Note, key: &GenericArray<u8, typenum::U32>) -> Option<(String, NoteMeta)> {
+    let content = decrypt_body(note, key)?;
+    Some((content, decrypt_note_meta(note, key)?))
+}
+
+/// The bytes a decrypted note stands for: the text itself, or a binary attachment's decoded base64
+fn note_bytes(content: &str, meta: &NoteMeta) -> Result<Zeroizing<Vec<u8>>, VaultError> {
+    if !meta.binary {
+        return Ok(Zeroizing::new(content.as_bytes().to_vec()));
+    }
+    general_purpose::STANDARD
+        .decode(content)
+        .map(Zeroizing::new)
+        .map_err(|_| VaultError::CorruptAttachment(meta.title.clone()))
+}
+
+/// Size of a decrypted note's content in bytes, counting an attachment by its decoded length
+fn content_size(content: &str, meta: &NoteMeta) -> usize {
+    note_bytes(content, meta).map_or(content.len(), |bytes| bytes.len())
+}
+
+/// The largest note `New`, `Edit` and `Import` may store, in bytes: `--max-note-size`, else the
+/// config file's `max_note_size`; `None` for no limit
+fn note_size_limit(args: &Args, vault_path: &Path) -> Result<Option<usize>, VaultError> {
+    match args.max_note_size {
+        Some(limit) => Ok(Some(limit)),
+        None => Ok(load_config(vault_path)?.max_note_size),
+    }
+}
+
+/// The vault's audit log, decrypted; empty if nothing was logged yet
+fn open_audit_log(vault: &Vault, key: &GenericArray<u8, typenum::U32>) -> Result<Vec<AuditEntry>, VaultError> {
+    if vault.audit.is_empty() {
+        return Ok(Vec::new());
+    }
+    let json = decrypt_note_content(&vault.audit, &vault.audit_nonce, Cipher::Aes256Gcm, key)
+        .ok_or(VaultError::AuditUndecryptable)?;
+    serde_json::from_str(&json).map_err(|_| VaultError::AuditUndecryptable)
+}
+
+/// Encrypt `log` under `key` as `vault`'s audit log
+fn seal_audit_log(used: &mut UsedNonces, vault: &mut Vault, log: &[AuditEntry], key: &GenericArray<u8, typenum::U32>) -> Result<(), VaultError> {
+    let json = serde_json::to_string(log).map_err(VaultError::Serialize)?;
+    (vault.audit, vault.audit_nonce) = used.fresh(|| encrypt_note_content(&json, Cipher::Aes256Gcm, key), |(_, nonce)| vec![nonce.clone()])?;
+    Ok(())
+}
+
+/// Under `--audit`, add an entry for the note `note_id` to the audit log, to be written by the
+/// caller's next save. Returns whether anything was logged.
+fn log_access(vault: &mut Vault, key: &GenericArray<u8, typenum::U32>, note_id: &str, action: AuditAction) -> Result<bool, VaultError> {
+    if !auditing() {
+        return Ok(false);
+    }
+    let mut log = open_audit_log(vault, key)?;
+    log.push(AuditEntry { note_id: note_id.to_string(), action, at: now_rfc3339() });
+    seal_audit_log(&mut UsedNonces::of(vault, &vault.notes), vault, &log, key)?;
+    Ok(true)
+}
+
+/// Refuse a note of `size` bytes over `limit`, before anything is encrypted
+fn check_note_size(title: &str, size: usize, limit: Option<usize>) -> Result<(), VaultError> {
+    match limit {
+        Some(limit) if size > limit => Err(VaultError::NoteTooLarge(title.to_string(), size, limit)),
+        _ => Ok(()),
+    }
+}
+
+/// A TOTP secret as stored: base32 in upper case, without the spaces and `=` padding setup
+/// pages often show. Errors unless it decodes to at least one byte.
+fn totp_secret(title: &str, secret: &str) -> Result<String, VaultError> {
+    let secret: String = secret.chars().filter(|c| !c.is_whitespace() && *c != '=').collect::<String>().to_uppercase();
+    match base32::decode(base32::Alphabet::Rfc4648 { padding: false }, &secret) {
+        Some(bytes) if !bytes.is_empty() => Ok(secret),
+        _ => Err(VaultError::InvalidTotpSecret(title.to_string())),
+    }
+}
+
+/// The 6-digit TOTP code (RFC 6238: SHA-1, 30-second steps) for a secret from `totp_secret`
+/// at `time` seconds since the Unix epoch
+fn totp_code(secret: &str, time: u64) -> Option<String> {
+    let key = Zeroizing::new(base32::decode(base32::Alphabet::Rfc4648 { padding: false }, secret)?);
+    Some(totp_lite::totp_custom::<totp_lite::Sha1>(totp_lite::DEFAULT_STEP, 6, &key, time))
+}
+
+/// A title in Unicode NFC, the form titles are stored in. The same accented title can be typed
+/// as different code points (`é` or `e` + U+0301); after this they are the same string.
+fn normalize_title(title: &str) -> String {
+    title.nfc().collect()
+}
+
+/// Whether `title` contains `text`, ignoring case, with both normalized first so a query typed
+/// in another Unicode form (e.g. a decomposed "é") still matches
+fn title_contains(title: &str, text: &str) -> bool {
+    normalize_title(title).to_lowercase().contains(&normalize_title(text).to_lowercase())
+}
+
+/// Whether two titles are equal once normalized, so older titles stored in another form match too
+fn same_title(a: &str, b: &str) -> bool {
+    a.nfc().eq(b.nfc())
+}
+
+/// The titles a note links to with `[[title]]`, trimmed and normalized, each once, in order of appearance
+fn note_links(content: &str) -> Vec<String> {
+    let pattern = regex::Regex::new(r"\[\[([^\[\]\n]+)\]\]").expect("valid link pattern");
+    let mut links: Vec<String> = Vec::new();
+    for capture in pattern.captures_iter(content) {
+        let title = normalize_title(capture[1].trim());
+        if !title.is_empty() && !links.contains(&title) {
+            links.push(title);
+        }
+    }
+    links
+}
+
+/// Find the note the user asked for, by ID or by title. Titles are encrypted, so every
+/// note's metadata is decrypted and compared in turn; notes under a different key never match.
+/// A title shared by several notes is an error listing their IDs rather than a guess.
+/// Titles are compared in NFC (`same_title`).
+/// Only live notes are searched, or only trashed ones when `in_trash` is set.
+fn find_note(
+    notes: &[Note],
+    query: &str,
+    by_id: bool,
+    in_trash: bool,
+    key: &GenericArray<u8, typenum::U32>,
+) -> Result<Option<usize>, VaultError> {
+    let matches: Vec<usize> = notes
+        .iter()
+        .enumerate()
+        .filter(|(_, note)| {
+            decrypt_note_meta(note, key).is_some_and(|meta| {
+                meta.trashed() == in_trash && if by_id { note.id == query } else { same_title(&meta.title, query) }
+            })
+        })
+        .map(|(index, _)| index)
+        .collect();
+    match matches.as_slice() {
+        [] => Ok(None),
+        [index] => Ok(Some(*index)),
+        _ => Err(VaultError::AmbiguousTitle(
+            query.to_string(),
+            matches.iter().map(|&index| notes[index].id.clone()).collect(),
+        )),
+    }
+}
+
+/// Like `find_note`, but when no live note has exactly this title, fall back to a
+/// case-insensitive match: equal titles first, then titles starting with the query, then
+/// titles containing it. A single best candidate is used; several are an error listing them.
+fn find_note_fuzzy(notes: &[Note], query: &str, by_id: bool, key: &GenericArray<u8, typenum::U32>) -> Result<Option<usize>, VaultError> {
+    if by_id {
+        return find_note(notes, query, true, false, key);
+    }
+    if let Some(index) = find_note(notes, query, false, false, key)? {
+        return Ok(Some(index));
+    }
+
+    let needle = normalize_title(query).to_lowercase();
+    let mut candidates: Vec<(u8, usize, String)> = notes
+        .iter()
+        .enumerate()
+        .filter_map(|(index, note)| {
+            let meta = decrypt_note_meta(note, key).filter(|meta| !meta.trashed())?;
+            let title = normalize_title(&meta.title).to_lowercase();
+            let rank = if title == needle {
+                0
+            } else if title.starts_with(&needle) {
+                1
+            } else if title.contains(&needle) {
+                2
+            } else {
+                return None;
+            };
+            Some((rank, index, meta.title))
+        })
+        .collect();
+    let Some(best) = candidates.iter().map(|(rank, _, _)| *rank).min() else {
+        return Ok(None);
+    };
+    candidates.retain(|(rank, _, _)| *rank == best);
+    match candidates.as_slice() {
+        [(_, index, title)] => {
+            warn!("🔍 No note is titled '{}'; using '{}'.", query, title);
+            Ok(Some(*index))
+        }
+        _ => Err(VaultError::AmbiguousMatch(
+            query.to_string(),
+            candidates.into_iter().map(|(_, _, title)| title).collect(),
+        )),
+    }
+}
+
+/// Check the derived key against the vault's check token (always AES-256-GCM).
+/// Vaults written before the token existed have nothing to check against and always pass.
+fn verify_key(vault: &Vault, key: &GenericArray<u8, typenum::U32>) -> bool {
+    let Some(lockout) = vault.lockout else {
+        return vault.check.is_empty()
+            || decrypt_note_content(&vault.check, &vault.check_nonce, Cipher::Aes256Gcm, key).as_deref()
+                == Some(CHECK_PLAINTEXT);
+    };
+    // `lockout` always writes a token, so a missing one means the file was tampered with
+    let (Ok(check), Ok(nonce)) =
+        (general_purpose::STANDARD.decode(&vault.check), general_purpose::STANDARD.decode(&vault.check_nonce))
+    else {
+        return false;
+    };
+    nonce.len() == NONCE_LEN
+        && Aes256Gcm::new(key)
+            .decrypt(Nonce::from_slice(&nonce), Payload { msg: &check, aad: lockout.aad().as_bytes() })
+            .is_ok_and(|plaintext| plaintext == CHECK_PLAINTEXT.as_bytes())
+}
+
+/// A fresh check token for `key`. Under a lockout its policy is bound in as associated data,
+/// so raising the limit or removing it in the file makes `verify_key` reject every password.
+fn seal_check(used: &mut UsedNonces, lockout: Option<LockoutPolicy>, key: &GenericArray<u8, typenum::U32>) -> Result<(String, String), VaultError> {
+    let Some(lockout) = lockout else {
+        return used.fresh(|| encrypt_note_content(CHECK_PLAINTEXT, Cipher::Aes256Gcm, key), |(_, nonce)| vec![nonce.clone()]);
+    };
+    let nonce = used.draw()?;
+    let check = Aes256Gcm::new(key)
+        .encrypt(Nonce::from_slice(&nonce), Payload { msg: CHECK_PLAINTEXT.as_bytes(), aad: lockout.aad().as_bytes() })
+        .map_err(|_| VaultError::Encryption)?;
+    Ok((general_purpose::STANDARD.encode(check), general_purpose::STANDARD.encode(nonce)))
+}
+
+/// Refuse to try any password on a vault whose lockout limit has been reached
+fn check_attempts(vault: &Vault) -> Result<(), VaultError> {
+    match vault.lockout {
+        Some(lockout) if lockout.failed >= lockout.max_attempts => Err(VaultError::AttemptsExhausted(lockout.max_attempts)),
+        _ => Ok(()),
+    }
+}
+
+/// The error for a wrong password on the vault at `path`. Under a lockout the attempt is first
+/// counted in the file, even in a dry run (which would otherwise allow free guesses); the last
+/// one allowed deletes the file instead if `--panic-wipe` was set. `held` says this process
+/// already holds the vault's lock.
+fn wrong_password(path: &Path, vault: &mut Vault, held: bool) -> VaultError {
+    let wrong = if vault.key_file { VaultError::WrongPasswordOrKeyFile } else { VaultError::WrongPassword };
+    if vault.lockout.is_none() {
+        return wrong;
+    }
+    let record = |lockout: &mut LockoutPolicy| lockout.failed += 1;
+    let Some(lockout) = update_attempts(path, held, record, "record the wrong password in") else {
+        return wrong;
+    };
+    vault.lockout = Some(lockout);
+    let left = lockout.max_attempts.saturating_sub(lockout.failed);
+    match left {
+        0 if lockout.wipe => return lockout_wipe(path),
+        0 => warn!("🛡️ That was the last attempt; the vault won't open again."),
+        _ if lockout.wipe => warn!("🛡️ {} attempt(s) left before the vault is deleted.", left),
+        _ => warn!("🛡️ {} attempt(s) left before the vault stops opening.", left),
+    }
+    wrong
+}
+
+/// Overwrite and delete the vault file at `path` once its last allowed attempt is used up
+fn lockout_wipe(path: &Path) -> VaultError {
+    if let Some(file) = open_for_shredding(path)
+        && let Err(err) = shred(file)
+    {
+        warn!("⚠️ Could not overwrite '{}' before deleting it: {}", path.display(), err);
+    }
+    match std::fs::remove_file(path) {
+        Ok(()) => VaultError::VaultWiped(path.to_path_buf()),
+        Err(err) => VaultError::WriteVault(path.to_path_buf(), err),
+    }
+}
+
+/// After the right password, report and clear any wrong ones counted by the lockout.
+/// `held` says this process already holds the vault's lock.
+fn reset_attempts(path: &Path, vault: &mut Vault, held: bool) {
+    let Some(lockout) = &mut vault.lockout else { return };
+    if lockout.failed == 0 {
+        return;
+    }
+    warn!("🛡️ {} wrong password(s) were entered since this vault was last opened.", lockout.failed);
+    lockout.failed = 0;
+    if !dry_run() {
+        update_attempts(path, held, |lockout| lockout.failed = 0, "reset the wrong-password count in");
+    }
+}
+
+/// Change only the lockout counter of the vault file at `path`, under the vault's lock (taken
+/// here unless `held`) and on a fresh reload, so a note another process just saved isn't
+/// overwritten. Returns the policy as saved; if the lock or the file can't be had, warns that
+/// it could not `what` the vault and writes nothing.
+fn update_attempts(path: &Path, held: bool, update: impl FnOnce(&mut LockoutPolicy), what: &str) -> Option<LockoutPolicy> {
+    let saved = (|| {
+        let _lock = if held { None } else { lock_vault(path, true)? };
+        let mut current = load_notes(path)?;
+        let Some(lockout) = &mut current.lockout else { return Ok(None) }; // e.g. a locked file, or the policy was removed
+        update(lockout);
+        write_header(path, &current)?;
+        Ok(current.lockout)
+    })();
+    saved.unwrap_or_else(|err: VaultError| {
+        warn!("⚠️ Could not {} '{}': {}", what, path.display(), err);
+        None
+    })
+}
+
+/// Whether a stored ciphertext/nonce pair is valid base64 with a nonce of the right length
+fn well_formed(ciphertext_b64: &str, nonce_b64: &str) -> bool {
+    general_purpose::STANDARD.decode(ciphertext_b64).is_ok()
+        && general_purpose::STANDARD.decode(nonce_b64).is_ok_and(|nonce| nonce.len() == NONCE_LEN)
+}
+
+/// Size in bytes of a base64-encoded ciphertext (0 if it isn't valid base64)
+fn encrypted_len(b64: &str) -> usize {
+    general_purpose::STANDARD.decode(b64).map_or(0, |bytes| bytes.len())
+}
+
+/// Tidy user-supplied tags: trim whitespace, drop empties and duplicates, keep order
+fn normalize_tags(tags: Vec<String>) -> Vec<String> {
+    let mut normalized: Vec<String> = Vec::new();
+    for tag in tags {
+        let tag = tag.trim();
+        if !tag.is_empty() && !normalized.iter().any(|t| t == tag) {
+            normalized.push(tag.to_string());
+        }
+    }
+    normalized
+}
+
+/// A uniformly random index below `n` (at most 256), drawn through `fill_random`. Bytes past the
+/// largest multiple of `n` are drawn again, since reducing them would favor the low indices.
+fn random_index(n: usize) -> Result<usize, VaultError> {
+    debug_assert!((1..=256).contains(&n));
+    let limit = 256 - 256 % n;
+    loop {
+        let mut byte = [0u8; 1];
+        fill_random(&mut byte)?;
+        if usize::from(byte[0]) < limit {
+            return Ok(usize::from(byte[0]) % n);
+        }
+    }
+}
+
+/// Random password of `length` characters drawn from the OS RNG, containing at least one character
+/// of every enabled class (lowercase, uppercase, digits and optionally symbols)
+fn generate_password(length: usize, symbols: bool, no_ambiguous: bool) -> Result<Zeroizing<String>, VaultError> {
+    let mut classes = vec!["abcdefghijklmnopqrstuvwxyz", "ABCDEFGHIJKLMNOPQRSTUVWXYZ", "0123456789"];
+    if symbols {
+        classes.push(PASSWORD_SYMBOLS);
+    }
+    let classes: Vec<Vec<char>> = classes
+        .iter()
+        .map(|class| class.chars().filter(|c| !no_ambiguous || !AMBIGUOUS_CHARS.contains(*c)).collect())
+        .collect();
+    let alphabet: Vec<char> = classes.concat();
+
+    // Draw uniformly from the whole alphabet and retry until every class shows up,
+    // which keeps each accepted password uniform among the valid ones
+    loop {
+        let password: Zeroizing<String> =
+            Zeroizing::new((0..length).map(|_| random_index(alphabet.len()).map(|i| alphabet[i])).collect::<Result<_, _>>()?);
+        if classes.iter().all(|class| password.chars().any(|c| class.contains(&c))) {
+            return Ok(password);
+        }
+    }
+}
+
+/// Whether to color output: only on a terminal, and never when `NO_COLOR` is set (https://no-color.org)
+fn use_color() -> bool {
+    io::stdout().is_terminal() && std::env::var_os("NO_COLOR").is_none_or(|value| value.is_empty())
+}
+
+/// A unified line diff turning `old` into `new`, with three lines of context; `None` if they're equal.
+/// With `color`, removed lines are red, added ones green and hunk headers cyan.
+fn render_diff(old: &str, new: &str, old_name: &str, new_name: &str, color: bool) -> Option<String> {
+    if old == new {
+        return None;
+    }
+    let diff = similar::TextDiff::from_lines(old, new);
+    let unified = diff.unified_diff().context_radius(3).header(old_name, new_name).to_string();
+    if !color {
+        return Some(unified);
+    }
+    let painted: Vec<String> = unified
+        .lines()
+        .map(|line| match line.as_bytes().first() {
+            _ if line.starts_with("---") || line.starts_with("+++") => format!("\x1b[1m{line}\x1b[0m"),
+            Some(b'-') => format!("\x1b[31m{line}\x1b[0m"),
+            Some(b'+') => format!("\x1b[32m{line}\x1b[0m"),
+            Some(b'@') => format!("\x1b[36m{line}\x1b[0m"),
+            _ => line.to_string(),
+        })
+        .collect();
+    Some(painted.join("\n") + "\n")
+}
+
+/// Lay `rows` out in columns under `headers`, two spaces apart; `numeric[i]` right-aligns
+/// column `i`. With `color`, the header is bold and the first column cyan.
+fn render_table(headers: &[&str], rows: &[Vec<String>], numeric: &[bool], color: bool) -> String {
+    // Emoji (such as the pinned marker) take two terminal columns
+    let width = |text: &str| text.chars().map(|c| if c == '⭐' || c >= '\u{1f300}' { 2 } else { 1 }).sum::<usize>();
+    let mut widths: Vec<usize> = headers.iter().map(|header| width(header)).collect();
+    for row in rows {
+        for (column, cell) in row.iter().enumerate() {
+            widths[column] = widths[column].max(width(cell));
+        }
+    }
+    let paint = |text: String, code: &str| if color { format!("\x1b[{code}m{text}\x1b[0m") } else { text };
+    let line = |cells: Vec<&str>, header: bool| {
+        let padded: Vec<String> = cells
+            .iter()
+            .enumerate()
+            .map(|(column, cell)| {
+                let pad = " ".repeat(widths[column] - width(cell));
+                let text = if numeric[column] { format!("{pad}{cell}") } else { format!("{cell}{pad}") };
+                if !header && column == 0 { paint(text, "36") } else { text }
+            })
+            .collect();
+        let text = padded.join("  ").trim_end().to_string();
+        if header { paint(text, "1") } else { text }
+    };
+
+    let mut lines = vec![line(headers.to_vec(), true)];
+    lines.extend(rows.iter().map(|row| line(row.iter().map(String::as_str).collect(), false)));
+    lines.join("\n")
+}
+
+/// Byte count for humans: `512 B`, `1.5 KiB`, `2.0 MiB`
+fn human_size(bytes: usize) -> String {
+    match bytes {
+        0..1024 => format!("{bytes} B"),
+        1024..1_048_576 => format!("{:.1} KiB", bytes as f64 / 1024.0),
+        _ => format!("{:.1} MiB", bytes as f64 / 1_048_576.0),
+    }
+}
+
+/// A timestamp for display, or `-` for notes that predate timestamps
+fn or_dash(timestamp: &str) -> &str {
+    if timestamp.is_empty() { "-" } else { timestamp }
+}
+
+/// Parse a duration like `45s`, `30m`, `12h`, `7d` or `2w` (for clap)
+fn parse_duration(text: &str) -> Result<chrono::TimeDelta, String> {
+    let split = text.find(|c: char| !c.is_ascii_digit()).unwrap_or(text.len());
+    let (number, unit) = text.split_at(split);
+    let number: i64 = number.parse().map_err(|_| format!("'{text}' should be a number followed by s, m, h, d or w"))?;
+    let duration = match unit {
+        "s" => chrono::TimeDelta::try_seconds(number),
+        "m" => chrono::TimeDelta::try_minutes(number),
+        "h" => chrono::TimeDelta::try_hours(number),
+        "d" => chrono::TimeDelta::try_days(number),
+        "w" => chrono::TimeDelta::try_weeks(number),
+        _ => return Err(format!("unknown unit in '{text}'; use s, m, h, d or w")),
+    };
+    // Capped at a century so adding it to the current time can't overflow
+    duration
+        .filter(|d| *d > chrono::TimeDelta::zero() && *d <= chrono::TimeDelta::days(36_500))
+        .ok_or_else(|| format!("'{text}' is out of range (1s to 100 years)"))
+}
+
+/// Parse a date given as `YYYY-MM-DD` (midnight UTC), a full RFC 3339 timestamp, or a
+/// `parse_duration` span meaning that long ago, like `7d` (for clap)
+fn parse_date(text: &str) -> Result<DateTime<Utc>, String> {
+    if let Ok(date) = chrono::NaiveDate::parse_from_str(text, "%Y-%m-%d") {
+        return Ok(date.and_time(chrono::NaiveTime::MIN).and_utc());
+    }
+    if let Ok(ago) = parse_duration(text) {
+        return Ok(Utc::now() - ago);
+    }
+    DateTime::parse_from_rfc3339(text).map(|time| time.with_timezone(&Utc)).map_err(|_| {
+        format!("'{text}' is not a date; use YYYY-MM-DD, an RFC 3339 timestamp like 2025-01-31T12:00:00Z, or a span like 7d")
+    })
+}
+
+/// Current time as an RFC 3339 string (UTC, second precision)
+fn now_rfc3339() -> String {
+    Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+/// Load the vault (salt + notes) from the vault file at `path`
+fn load_notes(path: &Path) -> Result<Vault, VaultError> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vault::default()),
+        Err(err) => return Err(VaultError::ReadVault(path.to_path_buf(), err)),
+    };
+    let mut contents = Vec::new();
+    file.read_to_end(&mut contents)
+        .map_err(|err| VaultError::ReadVault(path.to_path_buf(), err))?;
+
+    // Only the outer header of a locked vault is readable; the rest waits for the key
+    if let Some(header) = contents.strip_prefix(LOCKED_MAGIC) {
+        let truncated = || VaultError::ReadVault(path.to_path_buf(), io::Error::new(io::ErrorKind::InvalidData, "truncated locked vault"));
+        let (salt, costs) = header.split_at_checked(SALT_LEN).ok_or_else(truncated)?;
+        let cost = |i: usize| costs.get(i * 4..i * 4 + 4).map(|b| u32::from_le_bytes(b.try_into().unwrap()));
+        let (Some(memory_kib), Some(iterations), Some(parallelism)) = (cost(0), cost(1), cost(2)) else {
+            return Err(truncated());
+        };
+        return Ok(Vault {
+            argon2: KdfParams { memory_kib, iterations, parallelism },
+            salt: general_purpose::STANDARD.encode(salt),
+            locked: true,
+            outer: contents,
+            ..Vault::default()
+        });
+    }
+    parse_vault(path, &contents)
+}
+
+/// Parse the bytes of an unlocked vault file, JSON or MessagePack
+fn parse_vault(path: &Path, contents: &[u8]) -> Result<Vault, VaultError> {
+    if let Some(encoded) = contents.strip_prefix(BINARY_MAGIC) {
+        let decode_err = |err| VaultError::DecodeVault(path.to_path_buf(), err);
+        let header: VaultHeader = rmp_serde::from_slice(encoded).map_err(decode_err)?;
+        if header.version > VAULT_VERSION {
+            return Err(VaultError::NewerVault);
+        }
+        let mut vault: Vault = rmp_serde::from_slice(encoded).map_err(decode_err)?;
+        vault.format = VaultFormat::Msgpack;
+        return Ok(vault);
+    }
+    let contents = std::str::from_utf8(contents).map_err(|_| {
+        VaultError::ReadVault(path.to_path_buf(), io::Error::new(io::ErrorKind::InvalidData, "neither JSON nor a binary vault"))
+    })?;
+
+    // The original format was a bare array of notes keyed by plain SHA-256, with no salt.
+    // Wrap the notes as they are (content and nonces untouched) in a current vault that
+    // remembers the old key derivation; the first save backs the original file up.
+    if let Ok(notes) = serde_json::from_str::<Vec<Note>>(contents) {
+        if notes.is_empty() {
+            return Ok(Vault::default());
+        }
+        return Ok(Vault {
+            kdf: Kdf::LegacySha256,
+            notes,
+            legacy_backup: true,
+            ..Vault::default()
+        });
+    }
+    let parse_err = |err| VaultError::ParseVault(path.to_path_buf(), err);
+    let header: VaultHeader = serde_json::from_str(contents).map_err(parse_err)?;
+    if header.version > VAULT_VERSION {
+        return Err(VaultError::NewerVault);
+    }
+    let mut vault: Vault = serde_json::from_str(contents).map_err(parse_err)?;
+
+    // Upgrade older formats in memory; the next save writes the current version.
+    // Version 0 differs from 1 only by the missing `version` field.
+    vault.version = VAULT_VERSION;
+    Ok(vault)
+}
+
+/// Copy the vault file byte for byte to `<dir>/<stem>-<timestamp>.json` and return that path.
+/// The copy goes through a temporary file, so a half-written backup never carries the final name.
+fn backup_vault(vault_path: &Path, dir: &Path) -> Result<PathBuf, VaultError> {
+    let stem = vault_path.file_stem().unwrap_or_default().to_string_lossy();
+    let timestamp = Utc::now().format(BACKUP_TIMESTAMP);
+    let mut backup = dir.join(format!("{stem}-{timestamp}.json"));
+    // Several backups within one second get a counter rather than overwriting each other
+    for counter in 2.. {
+        if !backup.exists() {
+            break;
+        }
+        backup = dir.join(format!("{stem}-{timestamp}-{counter}.json"));
+    }
+
+    let tmp = temp_path(&backup);
+    let copied = std::fs::create_dir_all(dir)
+        .and_then(|()| std::fs::copy(vault_path, &tmp))
+        .and_then(|_| std::fs::rename(&tmp, &backup));
+    if let Err(err) = copied {
+        let _ = std::fs::remove_file(&tmp);
+        return Err(VaultError::Backup(backup, err));
+    }
+    Ok(backup)
+}
+
+/// For the part of a backup's file name between `<stem>-` and `.json` (`<timestamp>` or
+/// `<timestamp>-<counter>`), a key that sorts backups oldest first; `None` for any other text
+fn backup_sort_key(rest: &str) -> Option<String> {
+    let timestamp_len = Utc::now().format(BACKUP_TIMESTAMP).to_string().len();
+    // Checked, since the cut may fall inside a multi-byte character of an unrelated name
+    let (timestamp, counter) = rest.split_at_checked(timestamp_len)?;
+    chrono::NaiveDateTime::parse_from_str(timestamp, BACKUP_TIMESTAMP).ok()?;
+    let counter: u32 = match counter {
+        "" => 1,
+        counter => counter.strip_prefix('-')?.parse().ok()?,
+    };
+    Some(format!("{timestamp}-{counter:010}"))
+}
+
+/// Delete all but the `keep` newest backups of `vault_path` in `dir`, returning how many went.
+/// Only files named like `backup_vault` output are considered.
+fn prune_backups(vault_path: &Path, dir: &Path, keep: usize) -> Result<usize, VaultError> {
+    let stem = vault_path.file_stem().unwrap_or_default().to_string_lossy().into_owned();
+    let list_err = |err| VaultError::Backup(dir.to_path_buf(), err);
+
+    let mut backups: Vec<(String, PathBuf)> = Vec::new();
+    for entry in std::fs::read_dir(dir).map_err(list_err)? {
+        let path = entry.map_err(list_err)?.path();
+        let Some(name) = path.file_name().and_then(|name| name.to_str()) else { continue };
+        let Some(rest) = name.strip_prefix(&stem).and_then(|rest| rest.strip_prefix('-')) else { continue };
+        if let Some(key) = rest.strip_suffix(".json").and_then(backup_sort_key) {
+            backups.push((key, path));
+        }
+    }
+    backups.sort();
+    let excess = backups.len().saturating_sub(keep);
+    for (_, path) in &backups[..excess] {
+        std::fs::remove_file(path).map_err(|err| VaultError::Backup(path.clone(), err))?;
+    }
+    Ok(excess)
+}
+
+/// Decrypt a sealed vault's blob into `vault.notes` (no-op for per-note vaults)
+fn unseal_vault(vault: &mut Vault, key: &GenericArray<u8, typenum::U32>) -> Result<(), VaultError> {
+    if vault.layout != Layout::Sealed {
+        return Ok(());
+    }
+    let notes = decrypt_note_content(&vault.blob, &vault.blob_nonce, Cipher::Aes256Gcm, key)
+        .map(Zeroizing::new)
+        .ok_or(VaultError::SealedBlob)?;
+    vault.notes = serde_json::from_str(&notes).map_err(|_| VaultError::SealedBlob)?;
+    vault.blob.clear();
+    vault.blob_nonce.clear();
+    Ok(())
+}
+
+/// Peel a locked vault's outer layer with `key`, replacing the placeholder from `load_notes`
+/// with the vault inside (no-op for vaults that aren't locked)
+fn unlock_file(vault: &mut Vault, key: &GenericArray<u8, typenum::U32>, path: &Path) -> Result<(), VaultError> {
+    if vault.outer.is_empty() {
+        return Ok(());
+    }
+    let header_len = LOCKED_MAGIC.len() + SALT_LEN + 3 * size_of::<u32>(); // Magic, salt, Argon2 costs
+    let (header, rest) = vault.outer.split_at_checked(header_len).ok_or(VaultError::LockedVaultUndecryptable)?;
+    let (nonce, ciphertext) = rest.split_at_checked(NONCE_LEN).ok_or(VaultError::LockedVaultUndecryptable)?;
+    let contents = Aes256Gcm::new(key)
+        .decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad: header })
+        .map(Zeroizing::new)
+        .map_err(|_| VaultError::LockedVaultUndecryptable)?;
+    *vault = parse_vault(path, &contents)?;
+    vault.locked = true;
+    Ok(())
+}
+
+/// Wrap the bytes of a vault file in the outer layer of a locked vault. The header (salt and
+/// Argon2 costs, all needed to derive `key`) stays readable and is authenticated with the rest.
+fn lock_file(used: &mut UsedNonces, vault: &Vault, contents: &[u8], key: &GenericArray<u8, typenum::U32>) -> Result<Vec<u8>, VaultError> {
+    if vault.kdf == Kdf::LegacySha256 {
+        return Err(VaultError::LockLegacy);
+    }
+    let salt = general_purpose::STANDARD.decode(&vault.salt).map_err(|_| VaultError::CorruptSalt)?;
+    let mut bytes = LOCKED_MAGIC.to_vec();
+    bytes.extend(salt);
+    for cost in [vault.argon2.memory_kib, vault.argon2.iterations, vault.argon2.parallelism] {
+        bytes.extend(cost.to_le_bytes());
+    }
+    let nonce = used.draw()?;
+    let ciphertext = Aes256Gcm::new(key)
+        .encrypt(Nonce::from_slice(&nonce), Payload { msg: contents, aad: &bytes })
+        .map_err(|_| VaultError::Encryption)?;
+    bytes.extend(nonce);
+    bytes.extend(ciphertext);
+    Ok(bytes)
+}
+
+/// Path of the temporary file a vault is written to before being renamed into place
+fn temp_path(path: &Path) -> PathBuf {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    PathBuf::from(tmp)
+}
+
+/// Path of the lock file guarding `path` against concurrent writers
+fn lock_path(path: &Path) -> PathBuf {
+    let mut lock = path.as_os_str().to_owned();
+    lock.push(".lock");
+    PathBuf::from(lock)
+}
+
+/// Take the advisory lock on `<vault>.lock`, held until the returned file is dropped.
+/// With `wait`, retries for up to `LOCK_WAIT_SECS` and then fails with `Locked`; without it,
+/// gives up at once and returns `None`. The OS drops the lock when its holder exits, so a lock
+/// file left behind by a crashed process is simply taken over.
+fn lock_vault(path: &Path, wait: bool) -> Result<Option<File>, VaultError> {
+    let lock = lock_path(path);
+    let lock_err = |err| VaultError::WriteVault(lock.clone(), err);
+    if let Some(parent) = path.parent()
+        && !parent.as_os_str().is_empty()
+    {
+        std::fs::create_dir_all(parent).map_err(lock_err)?;
+    }
+    let mut options = std::fs::OpenOptions::new();
+    options.read(true).write(true).create(true).truncate(false); // The holder's PID stays readable
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(&lock).map_err(lock_err)?;
+
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(LOCK_WAIT_SECS);
+    let mut announced = false;
+    loop {
+        match file.try_lock() {
+            Ok(()) => break,
+            Err(std::fs::TryLockError::WouldBlock) if !wait => return Ok(None),
+            Err(std::fs::TryLockError::WouldBlock) if std::time::Instant::now() < deadline => {
+                if !announced {
+                    warn!("⏳ Waiting for another process to release '{}'...", path.display());
+                    announced = true;
+                }
+                std::thread::sleep(std::time::Duration::from_millis(100));
+            }
+            Err(std::fs::TryLockError::WouldBlock) => {
+                let mut pid = String::new();
+                let _ = file.read_to_string(&mut pid);
+                return Err(VaultError::Locked(lock, pid.trim().to_string()));
+            }
+            Err(std::fs::TryLockError::Error(err)) => return Err(lock_err(err)),
+        }
+    }
+    // Record who holds the lock, for the error message another process shows
+    file.set_len(0)
+        .and_then(|()| file.write_all(std::process::id().to_string().as_bytes()))
+        .map_err(lock_err)?;
+    Ok(Some(file))
+}
+
+/// Save the vault (salt + notes) to the vault file at `path`; a sealed vault's notes are
+/// encrypted into a single blob with `key` first.
+/// The data goes to `<path>.tmp` first, is fsynced, then renamed over the real file,
+/// so a crash mid-write leaves the previous vault intact instead of a truncated one.
+fn save_notes(path: &Path, vault: &Vault, key: &GenericArray<u8, typenum::U32>) -> Result<(), VaultError> {
+    let mut used = UsedNonces::of(vault, &vault.notes);
+    let bytes = match vault.layout {
+        Layout::PerNote => encode_vault(vault, vault.format)?,
+        Layout::Sealed => {
+            let notes = Zeroizing::new(serde_json::to_string(&vault.notes).map_err(VaultError::Serialize)?);
+            let (blob, blob_nonce) = used.fresh(|| encrypt_note_content(&notes, Cipher::Aes256Gcm, key), |(_, nonce)| vec![nonce.clone()])?;
+            let sealed = SealedVault {
+                version: vault.version,
+                kdf: vault.kdf,
+                argon2: vault.argon2,
+                salt: &vault.salt,
+                check: &vault.check,
+                check_nonce: &vault.check_nonce,
+                layout: vault.layout,
+                key_file: vault.key_file,
+                blob,
+                blob_nonce,
+                lockout: vault.lockout,
+                audit: &vault.audit,
+                audit_nonce: &vault.audit_nonce,
+            };
+            encode_vault(&sealed, vault.format)?
+        }
+    };
+    let bytes = if vault.locked { lock_file(&mut used, vault, &Zeroizing::new(bytes), key)? } else { bytes };
+    // Serializing (and sealing) above still runs, so a dry run fails where a real one would
+    if dry_run() {
+        say!("💾 Would save {} note(s) to '{}'; nothing was written.", vault.notes.len(), path.display());
+        return Ok(());
+    }
+    write_vault_file(path, vault, &bytes)?;
+    detail!("💾 Saved {} note(s) to '{}'.", vault.notes.len(), path.display());
+    Ok(())
+}
+
+/// Rewrite the vault file at `path` with the header of `vault` as loaded, before it is
+/// unlocked; used by the lockout to update its counter without a key. Ignores `--dry-run`.
+fn write_header(path: &Path, vault: &Vault) -> Result<(), VaultError> {
+    write_vault_file(path, vault, &encode_vault(vault, vault.format)?)
+}
+
+/// Replace the vault file at `path` with `bytes`, by way of a fsynced temp file and a rename
+fn write_vault_file(path: &Path, vault: &Vault, bytes: &[u8]) -> Result<(), VaultError> {
+    let write_err = |err| VaultError::WriteVault(path.to_path_buf(), err);
+    let tmp = temp_path(path);
+
+    // Keep the pre-migration file, once; an existing backup is never overwritten
+    if vault.legacy_backup {
+        let mut backup = path.as_os_str().to_owned();
+        backup.push(".bak");
+        let backup = PathBuf::from(backup);
+        if !backup.exists() {
+            std::fs::copy(path, &backup).map_err(write_err)?;
+        }
+    }
+
+    // A profile's config directory doesn't exist until its first save
+    if let Some(parent) = path.parent()
+        && !parent.as_os_str().is_empty()
+    {
+        std::fs::create_dir_all(parent).map_err(write_err)?;
+    }
+    // The replacement below is always owner-only; say so if the old file wasn't
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        if let Ok(metadata) = std::fs::metadata(path)
+            && metadata.permissions().mode() & 0o077 != 0
+        {
+            warn!(
+                "⚠️ Vault file '{}' was readable by other users (mode {:o}); restricting it to 0600.",
+                path.display(),
+                metadata.permissions().mode() & 0o777
+            );
+        }
+    }
+    let written = create_private_file(&tmp).and_then(|mut file| {
+        // A stale temp file keeps its old mode when reopened, so set it explicitly
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+        }
+        file.write_all(bytes)?;
+        file.sync_all() // Make sure the bytes are on disk before they replace the vault
+    });
+    // Held open across the rename, so the replaced file's bytes can still be overwritten afterwards
+    let old = open_for_shredding(path);
+    if let Err(err) = written.and_then(|()| std::fs::rename(&tmp, path)) {
+        let _ = std::fs::remove_file(&tmp); // Best effort; the original vault is untouched
+        return Err(write_err(err));
+    }
+    if let Some(old) = old
+        && let Err(err) = shred(old)
+    {
+        warn!("⚠️ Could not overwrite the previous contents of '{}': {}", path.display(), err);
+    }
+    Ok(())
+}
+
+/// The bytes of a vault file holding `vault` (a `Vault` or `SealedVault`) in `format`.
+/// MessagePack keeps field names, so fields missing from older files still get their defaults.
+fn encode_vault(vault: &impl Serialize, format: VaultFormat) -> Result<Vec<u8>, VaultError> {
+    match format {
+        VaultFormat::Json => serde_json::to_vec_pretty(vault).map_err(VaultError::Serialize),
+        VaultFormat::Msgpack => {
+            let mut bytes = BINARY_MAGIC.to_vec();
+            bytes.extend(rmp_serde::to_vec_named(vault).map_err(VaultError::EncodeVault)?);
+            Ok(bytes)
+        }
+    }
+}
+
+/// Open the vault file about to be replaced, for `shred`. `None` if there is none, or if other
+/// hard links still point at it (wiping it would then destroy their contents too).
+fn open_for_shredding(path: &Path) -> Option<File> {
+    let file = std::fs::OpenOptions::new().write(true).open(path).ok()?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        if file.metadata().ok()?.nlink() > 1 {
+            return None;
+        }
+    }
+    Some(file)
+}
+
+/// Overwrite a replaced vault file with zeros and sync it, so ciphertext of deleted or changed
+/// notes doesn't linger in its old disk blocks. Journaling, copy-on-write filesystems and SSD
+/// wear levelling may still keep copies elsewhere; this only covers what a plain rewrite leaves.
+fn shred(mut file: File) -> io::Result<()> {
+    let len = file.metadata()?.len();
+    let zeros = [0u8; 64 * 1024];
+    let mut written = 0;
+    while written < len {
+        let chunk = zeros.len().min((len - written) as usize);
+        file.write_all(&zeros[..chunk])?;
+        written += chunk as u64;
+    }
+    file.sync_all()
+}
+
+// ----------------- Key Agent -----------------
+//
+// `vault agent serve` listens on a Unix socket that only the owner can open and keeps derived
+// keys, per vault file, for a fixed time after they were handed over. Each connection carries
+// one request line and gets one reply line:
+//   GET <vault-id>          -> KEY <base64 key> | NONE
+//   PUT <vault-id> <key>    -> OK
+//   STATUS                  -> OK <cached keys> <ttl secs>
+//   STOP                    -> OK, then the agent wipes its keys and exits
+// `<vault-id>` is the base64 of the vault's absolute path.
+
+/// Where the agent listens: `$XDG_RUNTIME_DIR/vault/` if set, else the profile directory
+fn agent_socket_path() -> Result<PathBuf, VaultError> {
+    let dirs = directories::ProjectDirs::from("", "", "vault").ok_or(VaultError::NoConfigDir)?;
+    Ok(dirs.runtime_dir().unwrap_or(dirs.config_dir()).join(AGENT_SOCKET))
+}
+
+/// Identifies a vault file towards the agent
+fn agent_vault_id(vault_path: &Path) -> String {
+    general_purpose::STANDARD.encode(vault_path.as_os_str().as_encoded_bytes())
+}
+
+/// Send one request to a running agent; `None` if there is no agent or it didn't answer
+#[cfg(unix)]
+fn agent_request(request: &str) -> Option<Zeroizing<String>> {
+    use std::os::unix::net::UnixStream;
+    let mut stream = UnixStream::connect(agent_socket_path().ok()?).ok()?;
+    stream.set_read_timeout(Some(std::time::Duration::from_secs(2))).ok()?;
+    stream.write_all(request.as_bytes()).ok()?;
+    stream.write_all(b"\n").ok()?;
+    let mut reply = Zeroizing::new(String::new());
+    io::BufReader::new(stream).read_line(&mut reply).ok()?;
+    strip_line_ending(&mut reply);
+    Some(reply)
+}
+
+#[cfg(not(unix))]
+fn agent_request(_request: &str) -> Option<Zeroizing<String>> {
+    None
+}
+
+/// The key the agent holds for this vault, if an agent is running and still has it
+fn agent_get_key(vault_path: &Path) -> Option<Zeroizing<GenericArray<u8, typenum::U32>>> {
+    let reply = agent_request(&format!("GET {}", agent_vault_id(vault_path)))?;
+    let bytes = Zeroizing::new(general_purpose::STANDARD.decode(reply.strip_prefix("KEY ")?).ok()?);
+    (bytes.len() == 32).then(|| Zeroizing::new(GenericArray::clone_from_slice(&bytes)))
+}
+
+/// Hand an unlocked key to the agent, if one is running
+fn agent_put_key(vault_path: &Path, key: &GenericArray<u8, typenum::U32>) {
+    let request = Zeroizing::new(format!("PUT {} {}", agent_vault_id(vault_path), general_purpose::STANDARD.encode(key)));
+    let _ = agent_request(&request); // Best effort: without an agent the next command just prompts
+}
+
+/// Serve agent requests until `STOP`. Keys are evicted (and wiped, via `Zeroizing`) `ttl`
+/// after they were stored.
+#[cfg(unix)]
+fn agent_serve(ttl: std::time::Duration) -> Result<(), VaultError> {
+    use std::collections::HashMap;
+    use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+    use std::os::unix::net::UnixListener;
+    use std::sync::{Arc, Mutex};
+    use std::time::Instant;
+
+    type Cache = HashMap<String, (Zeroizing<GenericArray<u8, typenum::U32>>, Instant)>;
+
+    let path = agent_socket_path()?;
+    if let Some(dir) = path.parent() {
+        std::fs::DirBuilder::new().recursive(true).mode(0o700).create(dir).map_err(VaultError::Agent)?;
+    }
+    let _ = std::fs::remove_file(&path); // A socket left behind by an agent that was killed
+    let listener = UnixListener::bind(&path).map_err(VaultError::Agent)?;
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600)).map_err(VaultError::Agent)?;
+
+    let cache: Arc<Mutex<Cache>> = Arc::default();
+    let evictor = Arc::clone(&cache);
+    std::thread::spawn(move || loop {
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        evictor.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).retain(|_, (_, stored)| stored.elapsed() < ttl);
+    });
+
+    for stream in listener.incoming() {
+        let Ok(mut stream) = stream else { continue };
+        let _ = stream.set_read_timeout(Some(std::time::Duration::from_secs(2)));
+        let mut line = Zeroizing::new(String::new());
+        if io::BufReader::new(&stream).read_line(&mut line).is_err() {
+            continue;
+        }
+        let mut cache = cache.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let reply = match words.as_slice() {
+            ["GET", id] => match cache.get(*id) {
+                Some((key, stored)) if stored.elapsed() < ttl => {
+                    Zeroizing::new(format!("KEY {}", general_purpose::STANDARD.encode(key.as_slice())))
+                }
+                _ => Zeroizing::new("NONE".to_string()),
+            },
+            ["PUT", id, key] => match general_purpose::STANDARD.decode(key).map(Zeroizing::new) {
+                Ok(bytes) if bytes.len() == 32 => {
+                    cache.insert(id.to_string(), (Zeroizing::new(GenericArray::clone_from_slice(&bytes)), Instant::now()));
+                    Zeroizing::new("OK".to_string())
+                }
+                _ => Zeroizing::new("ERR bad key".to_string()),
+            },
+            ["STATUS"] => Zeroizing::new(format!("OK {} {}", cache.len(), ttl.as_secs())),
+            ["STOP"] => {
+                cache.clear();
+                let _ = stream.write_all(b"OK\n");
+                let _ = std::fs::remove_file(&path);
+                return Ok(());
+            }
+            _ => Zeroizing::new("ERR unknown request".to_string()),
+        };
+        let _ = stream.write_all(format!("{}\n", *reply).as_bytes());
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn agent_serve(_ttl: std::time::Duration) -> Result<(), VaultError> {
+    Err(VaultError::AgentUnsupported)
+}
+
+/// Run a `vault agent` subcommand
+fn run_agent(action: AgentAction) -> Result<(), VaultError> {
+    match action {
+        AgentAction::Start { ttl } => {
+            if agent_request("STATUS").is_some() {
+                say!("🕵️ The agent is already running.");
+                return Ok(());
+            }
+            if !cfg!(unix) {
+                return Err(VaultError::AgentUnsupported);
+            }
+            let mut command = std::process::Command::new(std::env::current_exe().map_err(VaultError::Agent)?);
+            command
+                .args(["agent", "serve", "--ttl", &ttl.to_string()])
+                .stdin(std::process::Stdio::null())
+                .stdout(std::process::Stdio::null())
+                .stderr(std::process::Stdio::null());
+            #[cfg(unix)]
+            {
+                // Own process group, so Ctrl-C in this terminal doesn't take the agent down
+                use std::os::unix::process::CommandExt;
+                command.process_group(0);
+            }
+            command.spawn().map_err(VaultError::Agent)?;
+            for _ in 0..50 {
+                if agent_request("STATUS").is_some() {
+                    say!("🕵️ Agent started; unlocked keys are cached for {}s.", ttl);
+                    return Ok(());
+                }
+                std::thread::sleep(std::time::Duration::from_millis(100));
+            }
+            Err(VaultError::Agent(io::Error::new(io::ErrorKind::TimedOut, "the agent did not come up")))
+        }
+        AgentAction::Stop => {
+            if agent_request("STOP").is_some() {
+                say!("🛑 Agent stopped; cached keys wiped.");
+            } else {
+                say!("🛑 No agent is running.");
+            }
+            Ok(())
+        }
+        AgentAction::Status => {
+            match agent_request("STATUS") {
+                Some(reply) => {
+                    let fields: Vec<&str> = reply.split_whitespace().collect();
+                    let (count, ttl) = (fields.get(1).unwrap_or(&"?"), fields.get(2).unwrap_or(&"?"));
+                    say!("🕵️ Agent running: {} key(s) cached, {}s lifetime.", count, ttl);
+                }
+                None => say!("🕵️ No agent is running."),
+            }
+            Ok(())
+        }
+        AgentAction::Serve { ttl } => agent_serve(std::time::Duration::from_secs(ttl)),
+    }
+}
+
+// ----------------- Self-Test -----------------
+
+const SELF_TEST_PASSWORD: &str = "self-test password";
+const SELF_TEST_TITLE: &str = "Self-test note";
+// Non-ASCII and longer than one AES block, so encoding and multi-block encryption are exercised
+const SELF_TEST_CONTENT: &str = "Known plaintext, 0123456789 – äöü ✓ 🔐, long enough to span several cipher blocks.";
+const SELF_TEST_EDITED: &str = "Edited plaintext\nwith a second line.";
+
+/// Run one `self-test` step with normal output silenced, then report it as PASS or FAIL
+fn self_test_step<T>(name: &'static str, step: impl FnOnce() -> Result<T, String>) -> Result<T, VaultError> {
+    let level = VERBOSITY.swap(QUIET, Ordering::Relaxed);
+    let result = step();
+    VERBOSITY.store(level, Ordering::Relaxed);
+    match result {
+        Ok(value) => {
+            say!("   PASS  {}", name);
+            Ok(value)
+        }
+        Err(reason) => {
+            error!("   FAIL  {}: {}", name, reason);
+            Err(VaultError::SelfTestFailed(name))
+        }
+    }
+}
+
+/// Load the vault at `path` the way `run` does, checking that `key` opens it
+fn self_test_reopen(path: &Path, key: &GenericArray<u8, typenum::U32>) -> Result<Vault, String> {
+    let mut vault = load_notes(path).map_err(|err| err.to_string())?;
+    unlock_file(&mut vault, key, path).map_err(|err| err.to_string())?;
+    if !verify_key(&vault, key) {
+        return Err("the saved vault doesn't open with its password".into());
+    }
+    unseal_vault(&mut vault, key).map_err(|err| err.to_string())?;
+    Ok(vault)
+}
+
+/// The content and metadata of the note titled `SELF_TEST_TITLE` in `vault`
+fn self_test_note(vault: &Vault, in_trash: bool, key: &GenericArray<u8, typenum::U32>) -> Result<(String, NoteMeta), String> {
+    let index = find_note(&vault.notes, SELF_TEST_TITLE, false, in_trash, key)
+        .map_err(|err| err.to_string())?
+        .ok_or("the note is missing")?;
+    open_note(&vault.notes[index], key).ok_or_else(|| "the note doesn't decrypt".into())
+}
+
+/// `self-test`: run the everyday commands against a throwaway vault in a temporary directory
+/// with known inputs, checking the file after each one. The real vault is never opened.
+fn self_test(args: &Args) -> Result<(), VaultError> {
+    let dir = tempfile::tempdir().map_err(|err| VaultError::WriteVault(std::env::temp_dir(), err))?;
+    let path = dir.path().join("vault.json");
+    say!("🧪 Testing in '{}'; the real vault is not touched.", dir.path().display());
+    let mut vault = Vault { format: args.vault_format.unwrap_or_default(), ..Vault::default() };
+
+    let key = self_test_step("random salt and key derivation", || {
+        let salt = generate_salt().map_err(|err| err.to_string())?;
+        if generate_salt().map_err(|err| err.to_string())? == salt {
+            return Err("two random salts came out the same".into());
+        }
+        vault.salt = general_purpose::STANDARD.encode(salt);
+        let key = derive_vault_key(&vault, SELF_TEST_PASSWORD, None).map_err(|err| err.to_string())?;
+        if *derive_vault_key(&vault, SELF_TEST_PASSWORD, None).map_err(|err| err.to_string())? != *key {
+            return Err("the same password gave two different keys".into());
+        }
+        (vault.check, vault.check_nonce) = seal_check(&mut UsedNonces::of(&vault, &vault.notes), None, &key).map_err(|err| err.to_string())?;
+        Ok(key)
+    })?;
+
+    self_test_step("init", || {
+        run_command(VaultCommands::Init { force: false }, &mut vault, &key, &path, args).map_err(|err| err.to_string())?;
+        let saved = self_test_reopen(&path, &key)?;
+        if !saved.notes.is_empty() {
+            return Err("the new vault isn't empty".into());
+        }
+        Ok(())
+    })?;
+
+    self_test_step("new", || {
+        let new = VaultCommands::New {
+            title: SELF_TEST_TITLE.to_string(),
+            content: Some(SELF_TEST_CONTENT.to_string()),
+            editor: false,
+            prompt: false,
+            from_file: None,
+            force: false,
+            tags: vec!["self-test".to_string()],
+            expires_in: None,
+            sensitive: false,
+            totp: false,
+        };
+        run_command(new, &mut vault, &key, &path, args).map_err(|err| err.to_string())?;
+        let saved = self_test_reopen(&path, &key)?;
+        if saved.notes.len() != 1 {
+            return Err(format!("expected 1 note, found {}", saved.notes.len()));
+        }
+        Ok(())
+    })?;
+
+    self_test_step("list", || {
+        let saved = self_test_reopen(&path, &key)?;
+        let titles: Vec<String> = saved.notes.iter().filter_map(|note| open_note(note, &key)).map(|(_, meta)| meta.title).collect();
+        if titles != [SELF_TEST_TITLE] {
+            return Err(format!("expected only '{SELF_TEST_TITLE}', listed {titles:?}"));
+        }
+        Ok(())
+    })?;
+
+    self_test_step("read", || {
+        let (content, meta) = self_test_note(&self_test_reopen(&path, &key)?, false, &key)?;
+        if content != SELF_TEST_CONTENT {
+            return Err("the content read back differs from what was written".into());
+        }
+        if meta.tags != ["self-test"] {
+            return Err(format!("expected the tag 'self-test', found {:?}", meta.tags));
+        }
+        Ok(())
+    })?;
+
+    self_test_step("edit", || {
+        let edit = VaultCommands::Edit {
+            title: SELF_TEST_TITLE.to_string(),
+            id: false,
+            content: Some(SELF_TEST_EDITED.to_string()),
+            editor: false,
+            prompt: false,
+            append: false,
+            tags: Vec::new(),
+        };
+        run_command(edit, &mut vault, &key, &path, args).map_err(|err| err.to_string())?;
+        let saved = self_test_reopen(&path, &key)?;
+        let (content, _) = self_test_note(&saved, false, &key)?;
+        if content != SELF_TEST_EDITED {
+            return Err("the edited content read back differs from what was written".into());
+        }
+        let previous = saved.notes[0].history.first().and_then(|version| open_version(version, &key));
+        if previous.is_none_or(|previous| previous.content != SELF_TEST_CONTENT) {
+            return Err("the previous content is missing from the history".into());
+        }
+        Ok(())
+    })?;
+
+    self_test_step("wrong password", || {
+        let wrong = derive_vault_key(&vault, "not the self-test password", None).map_err(|err| err.to_string())?;
+        let saved = load_notes(&path).map_err(|err| err.to_string())?;
+        if verify_key(&saved, &wrong) || saved.notes.iter().any(|note| open_note(note, &wrong).is_some()) {
+            return Err("the vault opened with a wrong password".into());
+        }
+        Ok(())
+    })?;
+
+    self_test_step("delete", || {
+        let delete = VaultCommands::Delete { title: SELF_TEST_TITLE.to_string(), id: false, yes: true };
+        run_command(delete, &mut vault, &key, &path, args).map_err(|err| err.to_string())?;
+        let saved = self_test_reopen(&path, &key)?;
+        if find_note(&saved.notes, SELF_TEST_TITLE, false, false, &key).map_err(|err| err.to_string())?.is_some() {
+            return Err("the note is still listed".into());
+        }
+        self_test_note(&saved, true, &key).map(drop).map_err(|reason| format!("not in the trash: {reason}"))
+    })?;
+
+    say!("✅ Self-test passed: this build encrypts, saves and reads vaults correctly.");
+    Ok(())
+}
+
+// ----------------- Main Program -----------------
+
+fn main() -> ExitCode {
+    match run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            if JSON_ERRORS.load(Ordering::Relaxed) {
+                eprintln!("{}", err.to_json());
+            } else {
+                error!("❌ {err}");
+            }
+            ExitCode::from(err.exit_code())
+        }
+    }
+}
+
+/// Parse arguments and run the requested command, reporting failures as `VaultError`
+fn run() -> Result<(), VaultError> {
+    // Parse command-line arguments. Usage errors exit with 1 rather than clap's 2, which means "wrong password".
+    let args = Args::try_parse().unwrap_or_else(|err| {
+        // The flag itself wasn't parsed, so look for it directly
+        if err.use_stderr() && std::env::args_os().any(|arg| arg == "--json-errors") {
+            let message = err.render().to_string();
+            let json = serde_json::json!({ "error": "invalid_arguments", "message": message.trim(), "exit_code": 1 });
+            eprintln!("{json}");
+        } else {
+            let _ = err.print();
+        }
+        std::process::exit(if err.use_stderr() { 1 } else { 0 })
+    });
+    if args.quiet {
+        VERBOSITY.store(QUIET, Ordering::Relaxed);
+    } else if args.verbose {
+        VERBOSITY.store(VERBOSE, Ordering::Relaxed);
+    }
+    // Read-only commands ignore `--dry-run`
+    if args.dry_run && args.command.writes_vault() {
+        DRY_RUN.store(true, Ordering::Relaxed);
+    }
+    JSON_ERRORS.store(args.json_errors, Ordering::Relaxed);
+    PASSWORD_TIMEOUT.store(args.password_confirm_timeout.unwrap_or(0), Ordering::Relaxed);
+    if args.no_emoji || std::env::var_os(NO_EMOJI_ENV).is_some_and(|value| !value.is_empty()) {
+        NO_EMOJI.store(true, Ordering::Relaxed);
+    }
+
+    // Completions need no vault access, so don't ask for a password
+    if let VaultCommands::Completions { shell } = args.command {
+        let mut command = Args::command();
+        let name = command.get_name().to_string();
+        clap_complete::generate(shell, &mut command, name, &mut io::stdout());
+        return Ok(());
+    }
+    // The agent manages keys, not a vault
+    if let VaultCommands::Agent { action } = args.command {
+        return run_agent(action);
+    }
+    // Listing profiles only looks at file names, so it needs no password either
+    if let VaultCommands::Profiles = args.command {
+        let dir = profile_dir()?;
+        let names = list_profiles(&dir)?;
+        if names.is_empty() {
+            say!("📭 No profiles in '{}'. Create one with `vault --profile <NAME> init`.", dir.display());
+        }
+        for name in names {
+            say!("📁 {}", name);
+        }
+        return Ok(());
+    }
+    // A password that isn't being saved needs no vault either
+    if let VaultCommands::GenPassword { length, symbols, no_ambiguous, save_as: None } = args.command {
+        println!("{}", *generate_password(length, symbols, no_ambiguous)?);
+        return Ok(());
+    }
+    // The self-test brings its own temporary vault and password
+    if let VaultCommands::SelfTest = args.command {
+        return self_test(&args);
+    }
+    if args.password.is_some() {
+        return Err(VaultError::PasswordArgument);
+    }
+    if args.vault.len() > 1 {
+        return run_across(&args);
+    }
+    let vault_path = resolve_vault_path(args.vault.first().cloned(), args.profile.as_deref())?; // Pick the vault file to work on
+    let vault_path = check_symlink(vault_path, args.follow_symlinks)?;
+    // Backups copy the encrypted file as is, so no password is involved
+    if let VaultCommands::Backup { dir, keep } = &args.command {
+        if !vault_path.exists() {
+            return Err(VaultError::ReadVault(vault_path, io::ErrorKind::NotFound.into()));
+        }
+        let dir = dir.clone().unwrap_or_else(|| vault_path.parent().map(Path::to_path_buf).unwrap_or_default());
+        let backup = backup_vault(&vault_path, &dir)?;
+        say!("💾 Backed up '{}' to '{}'.", vault_path.display(), backup.display());
+        if let Some(keep) = keep {
+            let removed = prune_backups(&vault_path, &dir, *keep as usize)?;
+            if removed > 0 {
+                say!("🧹 Removed {} old backup(s).", removed);
+            }
+        }
+        return Ok(());
+    }
+    // Vaults are only ever created by `init`, which never silently replaces one
+    let replace = match args.command {
+        VaultCommands::Init { force } if vault_path.exists() => {
+            if !force {
+                return Err(VaultError::VaultExists(vault_path));
+            }
+            true
+        }
+        VaultCommands::Init { .. } => false,
+        _ if !vault_path.exists() => return Err(VaultError::NoVault(vault_path)),
+        _ => false,
+    };
+    // Commands that change the vault hold its lock from load to save, so concurrent runs can't
+    // overwrite each other's changes. Others take it only if it's free (see pruning below).
+    AUDIT.store(args.audit || load_config(&vault_path)?.audit, Ordering::Relaxed);
+    // ... and so, under `--audit`, do reads, which add to the audit log
+    let writes = args.command.writes_vault()
+        || matches!(args.command, VaultCommands::Shell)
+        || (auditing() && args.command.audited_read());
+    let lock = if writes {
+        lock_vault(&vault_path, true)? // Released when `run` returns
+    } else if vault_path.exists() {
+        lock_vault(&vault_path, false).ok().flatten() // e.g. a vault on a read-only mount
+    } else {
+        None
+    };
+    let mut vault = if replace { Vault::default() } else { load_notes(&vault_path)? }; // Load existing notes from file
+    check_attempts(&vault)?;
+    if let Some(format) = args.vault_format {
+        vault.format = format; // Takes effect with the next save
+    }
+    detail!("📂 Vault file: '{}'", vault_path.display());
+
+    // Unless a password source was given explicitly, a running agent may already hold the key.
+    // It's only trusted when the vault has a check token to confirm it against.
+    let explicit_password = args.password_stdin || args.password_file.is_some();
+    let cached_key = if explicit_password || vault.check.is_empty() || args.command.needs_password() {
+        None
+    } else {
+        agent_get_key(&vault_path).filter(|key| verify_key(&vault, key))
+    };
+    let from_agent = cached_key.is_some();
+    if from_agent {
+        detail!("🕵️ Using the key cached by the agent.");
+    }
+
+    let mut typed_password = None; // Kept for `rekey` and `change-password`, which check it again
+    let key = match cached_key {
+        Some(key) => key,
+        None => {
+            // A brand-new vault (from `init`) gets its salt here
+            let creating = vault.kdf == Kdf::Argon2id && vault.salt.is_empty() && vault.notes.is_empty();
+            if creating {
+                vault.salt = general_purpose::STANDARD.encode(generate_salt()?);
+                vault.key_file = args.key_file.is_some();
+                vault.argon2 = load_config(&vault_path)?.argon2;
+            }
+            if vault.kdf == Kdf::LegacySha256 {
+                warn!("⚠️ This vault uses the old unsalted SHA-256 key; run `rekey` (or `change-password`) to upgrade it to Argon2id.");
+            }
+            // Ask user for master password
+            let password = if creating { read_new_master_password(&args)? } else { read_master_password(&args)? };
+            if creating && !args.allow_weak {
+                warn_if_weak(&password);
+            }
+            let key_file = args.key_file.as_deref().map(read_key_file).transpose()?;
+            if creating && let Some(path) = &args.key_file {
+                warn!(
+                    "🗝️ This vault will need both the password and the key file '{}'. Keep a copy of the key file: losing either one means losing access for good.",
+                    path.display()
+                );
+            }
+            let key = derive_vault_key(&vault, &password, key_file.as_ref().map(|digest| digest.as_slice()))?; // Turn password into AES key
+            unlock_file(&mut vault, &key, &vault_path)?; // A locked file only shows its notes to the right key
+            typed_password = Some(password); // Wiped on drop when `run` returns
+            key
+        }
+    };
+
+    // Refuse to touch the vault at all under the wrong password
+    if !verify_key(&vault, &key) {
+        return Err(wrong_password(&vault_path, &mut vault, lock.is_some()));
+    }
+    reset_attempts(&vault_path, &mut vault, lock.is_some());
+    if !from_agent && !explicit_password && !dry_run() {
+        agent_put_key(&vault_path, &key);
+    }
+    unseal_vault(&mut vault, &key)?;
+    detail!(
+        "📚 Loaded {} note(s) ({} layout, {} key derivation).",
+        vault.notes.len(),
+        match vault.layout {
+            Layout::PerNote => "per-note",
+            Layout::Sealed => "sealed",
+        },
+        match vault.kdf {
+            Kdf::Argon2id => "Argon2id",
+            Kdf::LegacySha256 => "legacy SHA-256",
+        }
+    );
+
+    // Notes from older versions get an ID now; it is persisted on the next save
+    for note in vault.notes.iter_mut().filter(|n| n.id.is_empty()) {
+        note.id = new_note_id();
+    }
+    // New vaults (and older ones whose notes all open under this key) get a check token,
+    // persisted on the next save
+    if vault.check.is_empty()
+        && vault.notes.iter().all(|n| decrypt_body(n, &key).is_some())
+    {
+        (vault.check, vault.check_nonce) = seal_check(&mut UsedNonces::of(&vault, &vault.notes), None, &key)?;
+    }
+
+    // Expired notes are deleted for good (not trashed) before the command sees them
+    if !args.no_prune {
+        let now = Utc::now();
+        let before = vault.notes.len();
+        vault.notes.retain(|note| !decrypt_note_meta(note, &key).is_some_and(|meta| meta.expired(now)));
+        let pruned = before - vault.notes.len();
+        // Without the lock another process may be saving; they're deleted on a later run instead
+        if pruned > 0 && lock.is_some() {
+            save_notes(&vault_path, &vault, &key)?;
+            say!("⌛ Pruned {} expired note(s).", pruned);
+        }
+    }
+
+    // Read-only commands are done with the lock, so e.g. `read --watch` doesn't hold off the edits it shows
+    if !writes {
+        drop(lock);
+    }
+
+    if let VaultCommands::Shell = args.command {
+        return run_shell(&mut vault, &key, &vault_path, &args);
+    }
+    if let VaultCommands::Rekey = args.command {
+        return rekey_vault(&vault, &key, typed_password, &vault_path, &args);
+    }
+    if let VaultCommands::ChangePassword = args.command {
+        return change_password(&vault, &key, typed_password, &vault_path, &args);
+    }
+    run_command(args.command.clone(), &mut vault, &key, &vault_path, &args)
+}
+
+/// `List` or `Search` several vaults (`--vault` given more than once) as one. Each vault is
+/// opened read-only, with its own password or with one shared one (`--same-password`, or a
+/// password from stdin or a file). Expired notes are hidden but not pruned.
+fn run_across(args: &Args) -> Result<(), VaultError> {
+    if !matches!(args.command, VaultCommands::List(_) | VaultCommands::Search { .. }) {
+        return Err(VaultError::SeveralVaults);
+    }
+    let explicit_password = args.password_stdin || args.password_file.is_some();
+    let key_file = args.key_file.as_deref().map(read_key_file).transpose()?;
+    let mut shared_password: Option<Zeroizing<String>> = None;
+    let mut opened = Vec::new();
+    for path in &args.vault {
+        let vault_path = check_symlink(std::path::absolute(path).unwrap_or_else(|_| path.clone()), args.follow_symlinks)?;
+        if !vault_path.exists() {
+            return Err(VaultError::NoVault(vault_path));
+        }
+        let mut vault = load_notes(&vault_path)?;
+        check_attempts(&vault)?;
+        let cached_key = if explicit_password || vault.check.is_empty() {
+            None
+        } else {
+            agent_get_key(&vault_path).filter(|key| verify_key(&vault, key))
+        };
+        let key = match cached_key {
+            Some(key) => key,
+            None => {
+                let password = match &shared_password {
+                    Some(password) => password.clone(),
+                    None if explicit_password || args.same_password => {
+                        let password = read_master_password(args)?;
+                        shared_password = Some(password.clone());
+                        password
+                    }
+                    None => prompt_password(&format!("🔑 Password for '{}': ", path.display()))?,
+                };
+                let key = derive_vault_key(&vault, &password, key_file.as_ref().map(|digest| digest.as_slice()))?;
+                unlock_file(&mut vault, &key, &vault_path)?;
+                key
+            }
+        };
+        if !verify_key(&vault, &key) {
+            return Err(wrong_password(&vault_path, &mut vault, false));
+        }
+        reset_attempts(&vault_path, &mut vault, false);
+        unseal_vault(&mut vault, &key)?;
+        let now = Utc::now();
+        vault.notes.retain(|note| !decrypt_note_meta(note, &key).is_some_and(|meta| meta.expired(now)));
+        detail!("📚 Loaded {} note(s) from '{}'.", vault.notes.len(), vault_path.display());
+        opened.push((path.display().to_string(), vault, key));
+    }
+
+    let sources: Vec<ListSource> =
+        opened.iter().map(|(name, vault, key)| ListSource { name: Some(name.clone()), vault, key }).collect();
+    match args.command.clone() {
+        VaultCommands::List(options) => list_notes(&sources, options),
+        VaultCommands::Search { query, case_sensitive, regex, ignore_case } => {
+            search_notes(&sources, &query, case_sensitive, regex, ignore_case)
+        }
+        _ => unreachable!("checked above"),
+    }
+}
+
+/// Re-encrypt every note that opens under `key` with the chosen (or default) cipher and fresh
+/// nonces, earlier versions included. When every note opens, the key itself is also re-derived
+/// from the same password with a fresh salt and the current Argon2id parameters, which is how a
+/// legacy SHA-256 vault is upgraded. Otherwise the notes that don't open are left exactly as they
+/// are and the key is kept, since they may belong to another password.
+fn rekey_vault(
+    vault: &Vault,
+    key: &GenericArray<u8, typenum::U32>,
+    password: Option<Zeroizing<String>>,
+    vault_path: &Path,
+    args: &Args,
+) -> Result<(), VaultError> {
+    let cipher = args.cipher.unwrap_or_default();
+    let skipped = vault.notes.iter().filter(|note| open_note(note, key).is_none()).count();
+
+    let mut rekeyed = Vault {
+        version: VAULT_VERSION,
+        kdf: vault.kdf,
+        argon2: vault.argon2,
+        salt: vault.salt.clone(),
+        check: vault.check.clone(),
+        check_nonce: vault.check_nonce.clone(),
+        layout: vault.layout,
+        key_file: vault.key_file,
+        legacy_backup: vault.legacy_backup,
+        format: vault.format,
+        locked: vault.locked,
+        lockout: vault.lockout,
+        ..Vault::default()
+    };
+    let mut new_key = Zeroizing::new(*key);
+    // Notes that don't open keep their nonces under the old key (which may stay), so they count too
+    let mut used = UsedNonces::of(vault, &vault.notes);
+    if skipped == 0 {
+        // The password is needed again to derive the new key; a key from the agent doesn't carry it
+        let password = match password {
+            Some(password) => password,
+            None => read_master_password(args)?,
+        };
+        let key_file = args.key_file.as_deref().map(read_key_file).transpose()?;
+        let key_file = key_file.as_ref().map(|digest| digest.as_slice());
+        if !verify_key(vault, &*derive_vault_key(vault, &password, key_file)?) {
+            return Err(if vault.key_file { VaultError::WrongPasswordOrKeyFile } else { VaultError::WrongPassword });
+        }
+        let new_salt = generate_salt()?;
+        rekeyed.argon2 = load_config(vault_path)?.argon2;
+        new_key = derive_key_from_password(&password, &new_salt, rekeyed.argon2, key_file)?;
+        rekeyed.kdf = Kdf::Argon2id;
+        rekeyed.salt = general_purpose::STANDARD.encode(new_salt);
+        (rekeyed.check, rekeyed.check_nonce) = seal_check(&mut used, rekeyed.lockout, &new_key)?;
+    }
+    if !vault.audit.is_empty() {
+        seal_audit_log(&mut used, &mut rekeyed, &open_audit_log(vault, key)?, &new_key)?;
+    }
+
+    let progress = progress_bar(vault.notes.len(), "Re-encrypting");
+    for note in progress.wrap_iter(vault.notes.iter()) {
+        let Some((content, meta)) = open_note(note, key) else {
+            rekeyed.notes.push(note.clone()); // Not ours to touch
+            continue;
+        };
+        let mut sealed = seal_note_fresh(&mut used, &note.id, &content, &meta, cipher, &new_key)?;
+        for version in &note.history {
+            // An earlier version that doesn't open is dropped; it can't be re-encrypted
+            if let Some(past) = open_version(version, key) {
+                sealed.history.push(seal_version_fresh(&mut used, &past, cipher, &new_key)?);
+            }
+        }
+        rekeyed.notes.push(sealed);
+    }
+    progress.finish_and_clear();
+
+    save_notes(vault_path, &rekeyed, &new_key)?;
+    if !dry_run() && skipped == 0 {
+        agent_put_key(vault_path, &new_key); // Keep a running agent's cache valid
+    }
+    say!(
+        "🔁 Re-encrypted {} note(s) with {}.",
+        vault.notes.len() - skipped,
+        match cipher {
+            Cipher::Aes256Gcm => "AES-256-GCM",
+            Cipher::ChaCha20Poly1305 => "ChaCha20-Poly1305",
+        }
+    );
+    if skipped == 0 {
+        say!("🔑 Key re-derived with Argon2id and a fresh salt.");
+    } else {
+        warn!(
+            "⚠️ Skipped {} note(s) that don't decrypt with this password; the key derivation was left unchanged so they stay readable.",
+            skipped
+        );
+    }
+    Ok(())
+}
+
+/// Re-encrypt the whole vault under a new master password, after checking the current one
+/// (`password`, if it was typed to open the vault, or asked for again)
+fn change_password(
+    vault: &Vault,
+    key: &GenericArray<u8, typenum::U32>,
+    password: Option<Zeroizing<String>>,
+    vault_path: &Path,
+    args: &Args,
+) -> Result<(), VaultError> {
+    let argon2 = load_config(vault_path)?.argon2; // Checked before asking for anything
+    // The current password is always checked, even when `key` came from the agent
+    let password = match password {
+        Some(password) => password,
+        None => read_master_password(args)?,
+    };
+    let key_file = args.key_file.as_deref().map(read_key_file).transpose()?;
+    if !verify_key(vault, &*derive_vault_key(vault, &password, key_file.as_ref().map(|digest| digest.as_slice()))?) {
+        return Err(if vault.key_file { VaultError::WrongPasswordOrKeyFile } else { VaultError::WrongPassword });
+    }
+    drop(password);
+
+    let new_password = prompt_password("🔑 Enter new password: ")?;
+    if prompt_password("🔑 Confirm new password: ")? != new_password {
+        return Err(VaultError::PasswordMismatch);
+    }
+    // The key file stays; only the password changes
+    if vault.key_file && key_file.is_none() {
+        return Err(VaultError::KeyFileRequired);
+    }
+    let new_salt = generate_salt()?;
+    let new_key = derive_key_from_password(&new_password, &new_salt, argon2, key_file.as_ref().map(|digest| digest.as_slice()))?;
+    drop(new_password);
+
+    // Build the whole re-encrypted vault first so a failure never leaves it half-rotated
+    let mut notes = Vec::with_capacity(vault.notes.len());
+    let mut used = UsedNonces::of(vault, &vault.notes);
+    let mut undecryptable = 0;
+    let progress = progress_bar(vault.notes.len(), "Re-encrypting");
+    for note in progress.wrap_iter(vault.notes.iter()) {
+        match open_note(note, key) {
+            Some((content, meta)) => {
+                let cipher = args.cipher.unwrap_or(note.cipher);
+                let mut rotated = seal_note_fresh(&mut used, &note.id, &content, &meta, cipher, &new_key)?;
+                // Earlier versions move to the new key too; one that doesn't open blocks the change
+                for version in &note.history {
+                    match open_version(version, key) {
+                        Some(past) => rotated.history.push(seal_version_fresh(&mut used, &past, cipher, &new_key)?),
+                        None => undecryptable += 1,
+                    }
+                }
+                notes.push(rotated);
+            }
+            None => undecryptable += 1,
+        }
+    }
+    progress.finish_and_clear();
+    if undecryptable > 0 {
+        return Err(VaultError::UndecryptableNotes(undecryptable));
+    }
+
+    let (check, check_nonce) = seal_check(&mut used, vault.lockout, &new_key)?;
+    let audit_log = open_audit_log(vault, key)?;
+    let mut rotated = Vault {
+        argon2,
+        salt: general_purpose::STANDARD.encode(new_salt),
+        check,
+        check_nonce,
+        layout: vault.layout,
+        key_file: vault.key_file,
+        notes,
+        legacy_backup: vault.legacy_backup,
+        format: vault.format,
+        locked: vault.locked,
+        lockout: vault.lockout,
+        ..Vault::default()
+    };
+    if !audit_log.is_empty() {
+        seal_audit_log(&mut used, &mut rotated, &audit_log, &new_key)?;
+    }
+    save_notes(vault_path, &rotated, &new_key)?;
+    if !dry_run() {
+        agent_put_key(vault_path, &new_key); // Keep a running agent's cache valid
+    }
+    say!("🔁 Password changed; {} note(s) re-encrypted.", rotated.notes.len());
+    Ok(())
+}
+
+/// Commands `vault shell` accepts; the rest change the key or act on more than the open vault
+fn shell_allows(command: &VaultCommands) -> bool {
+    matches!(
+        command,
+        VaultCommands::List { .. }
+            | VaultCommands::Tags
+            | VaultCommands::Read { .. }
+            | VaultCommands::Cat { .. }
+            | VaultCommands::Info { .. }
+            | VaultCommands::Totp { .. }
+            | VaultCommands::Backlinks { .. }
+            | VaultCommands::Audit { .. }
+            | VaultCommands::New { .. }
+            | VaultCommands::Edit { .. }
+            | VaultCommands::History { .. }
+            | VaultCommands::Revert { .. }
+            | VaultCommands::Delete { .. }
+            | VaultCommands::Restore { .. }
+            | VaultCommands::Pin { .. }
+            | VaultCommands::Unpin { .. }
+            | VaultCommands::Archive { .. }
+            | VaultCommands::Unarchive { .. }
+            | VaultCommands::Search { .. }
+            | VaultCommands::Find { .. }
+            | VaultCommands::Diff { .. }
+    )
+}
+
+/// One line typed at the `vault shell` prompt, parsed with the same subcommands as the CLI
+#[derive(Parser, Debug)]
+#[command(
+    name = "vault",
+    no_binary_name = true,
+    disable_version_flag = true,
+    about = "In the shell: list, tags, read, cat, info, totp, backlinks, audit, new, edit, history, revert, delete, restore, pin, unpin, archive, unarchive, search, find and diff. Type `quit` to leave."
+)]
+struct ShellLine {
+    #[command(subcommand)]
+    command: VaultCommands,
+}
+
+/// Where `vault shell` reads its lines from: the terminal (`rustyline`), or a script in tests
+trait ShellInput {
+    /// The next line, or `None` once the user is done (Ctrl-C, Ctrl-D)
+    fn read_line(&mut self, prompt: &str) -> Result<Option<String>, VaultError>;
+    /// Offer `line` to the history of earlier lines
+    fn remember(&mut self, line: &str);
+}
+
+impl ShellInput for rustyline::DefaultEditor {
+    fn read_line(&mut self, prompt: &str) -> Result<Option<String>, VaultError> {
+        match self.readline(prompt) {
+            Ok(line) => Ok(Some(line)),
+            Err(rustyline::error::ReadlineError::Interrupted | rustyline::error::ReadlineError::Eof) => Ok(None),
+            Err(err) => Err(VaultError::Shell(err.to_string())),
+        }
+    }
+    fn remember(&mut self, line: &str) {
+        let _ = self.add_history_entry(line);
+    }
+}
+
+/// Read commands from the terminal and run them against the already unlocked vault.
+/// Each change is saved as it's made, exactly as if the command had been run on its own.
+/// `key` belongs to the caller and is wiped when `run` returns.
+fn run_shell(vault: &mut Vault, key: &GenericArray<u8, typenum::U32>, vault_path: &Path, args: &Args) -> Result<(), VaultError> {
+    let mut editor = rustyline::DefaultEditor::new().map_err(|err| VaultError::Shell(err.to_string()))?;
+    shell_loop(&mut editor, vault, key, vault_path, args)
+}
+
+/// The `vault shell` loop, reading lines from `input` until it ends or `quit` is typed
+fn shell_loop(
+    input: &mut impl ShellInput,
+    vault: &mut Vault,
+    key: &GenericArray<u8, typenum::U32>,
+    vault_path: &Path,
+    args: &Args,
+) -> Result<(), VaultError> {
+    say!("🐚 Vault unlocked. Type `help` for commands, `quit` to leave.");
+    while let Some(line) = input.read_line("vault> ")? {
+        let words = match shell_words::split(&line) {
+            Ok(words) => words,
+            Err(err) => {
+                error!("❌ {err}");
+                continue;
+            }
+        };
+        let Some(first) = words.first() else {
+            continue;
+        };
+        if first == "quit" || first == "exit" {
+            break;
+        }
+        // `new` and `edit` lines can carry note content, which must not end up in the history.
+        // The history only lives in memory anyway; it is never written to disk.
+        if first != "new" && first != "edit" {
+            input.remember(&line);
+        }
+        let command = match ShellLine::try_parse_from(&words) {
+            Ok(parsed) => parsed.command,
+            Err(err) => {
+                let _ = err.print(); // Also how `help` and `<command> --help` are shown
+                continue;
+            }
+        };
+        if let VaultCommands::Read { watch: true, .. } = command {
+            error!("❌ `read --watch` runs until stopped, so it isn't available in the shell; run `vault read --watch` instead.");
+            continue;
+        }
+        if !shell_allows(&command) {
+            error!("❌ `{first}` isn't available in the shell; run `vault {first}` instead.");
+            continue;
+        }
+        match run_command(command, vault, key, vault_path, args) {
+            // The timed-out prompt still holds the terminal (see `prompt_password`)
+            Err(err @ VaultError::PasswordTimeout(_)) => return Err(err),
+            Err(err) => error!("❌ {err}"),
+            Ok(()) => {}
+        }
+    }
+    say!("👋 Vault locked.");
+    Ok(())
+}
+
+/// Replace the vault's notes with `notes`, staged by a batch command (`Import`, `Merge`), and
+/// save. If saving fails the old notes are put back, so the batch applies fully or not at all,
+/// also for later commands in `vault shell`.
+fn commit_notes(vault: &mut Vault, notes: Vec<Note>, vault_path: &Path, key: &GenericArray<u8, typenum::U32>) -> Result<(), VaultError> {
+    let previous = std::mem::replace(&mut vault.notes, notes);
+    if let Err(err) = save_notes(vault_path, vault, key) {
+        vault.notes = previous;
+        return Err(err);
+    }
+    Ok(())
+}
+
+/// `Archive` (`archive` true) or `Unarchive` a note, which `List` then leaves out or shows again
+fn set_archived(
+    vault: &mut Vault,
+    key: &GenericArray<u8, typenum::U32>,
+    vault_path: &Path,
+    title: &str,
+    id: bool,
+    archive: bool,
+) -> Result<(), VaultError> {
+    let Some(index) = find_note_fuzzy(&vault.notes, title, id, key)? else {
+        return Err(VaultError::NoteNotFound(title.to_string()));
+    };
+    let Some((_, mut meta)) = open_note(&vault.notes[index], key) else {
+        return Err(VaultError::NoteUndecryptable);
+    };
+    if meta.archived == archive {
+        say!("📦 '{}' is already {}.", meta.title, if archive { "archived" } else { "not archived" });
+        return Ok(());
+    }
+    meta.archived = archive;
+    let sealed = seal_meta_fresh(&mut UsedNonces::of(vault, &vault.notes), &meta, vault.notes[index].cipher, key)?;
+    let note = &mut vault.notes[index];
+    (note.meta, note.meta_nonce) = sealed;
+    note.title.clear(); // Any legacy plaintext title moves into the encrypted metadata
+    save_notes(vault_path, vault, key)?;
+    if archive {
+        say!("📦 Archived '{}'; see it with `list --archived`.", meta.title);
+    } else {
+        say!("📌 Unarchived '{}'.", meta.title);
+    }
+    Ok(())
+}
+
+/// `Pin` (`pin` true) or `Unpin` a note, which `List` then shows first or among the rest
+fn set_pinned(
+    vault: &mut Vault,
+    key: &GenericArray<u8, typenum::U32>,
+    vault_path: &Path,
+    title: &str,
+    id: bool,
+    pin: bool,
+) -> Result<(), VaultError> {
+    let Some(index) = find_note_fuzzy(&vault.notes, title, id, key)? else {
+        return Err(VaultError::NoteNotFound(title.to_string()));
+    };
+    let Some((_, mut meta)) = open_note(&vault.notes[index], key) else {
+        return Err(VaultError::NoteUndecryptable);
+    };
+    if meta.pinned == pin {
+        say!("{} '{}' is already {}.", if pin { "⭐" } else { "📌" }, meta.title, if pin { "pinned" } else { "unpinned" });
+        return Ok(());
+    }
+    meta.pinned = pin;
+    let sealed = seal_meta_fresh(&mut UsedNonces::of(vault, &vault.notes), &meta, vault.notes[index].cipher, key)?;
+    let note = &mut vault.notes[index];
+    (note.meta, note.meta_nonce) = sealed;
+    note.title.clear(); // Any legacy plaintext title moves into the encrypted metadata
+    save_notes(vault_path, vault, key)?;
+    if pin {
+        say!("⭐ Pinned '{}'; `list` shows it first.", meta.title);
+    } else {
+        say!("📌 Unpinned '{}'.", meta.title);
+    }
+    Ok(())
+}
+
+/// `List` the notes of `sources`, combined into one listing
+fn list_notes(sources: &[ListSource], options: ListArgs) -> Result<(), VaultError> {
+    let ListArgs {
+        json,
+        include_trashed,
+        archived,
+        all,
+        tag,
+        grep_title,
+        plain,
+        sort,
+        reverse,
+        limit,
+        offset,
+        show_locked,
+        since,
+        until,
+        by_updated,
+        totp,
+        fields,
+    } = options;
+    let several = sources.iter().any(|source| source.name.is_some());
+    let mut listings: Vec<NoteListing> = sources
+        .iter()
+        .flat_map(|source| {
+            source.vault.notes.iter().filter_map(|note| {
+                open_note(note, source.key).map(|(content, meta)| NoteListing {
+                    size: content_size(&content, &meta),
+                    id: note.id.clone(),
+                    trashed: meta.trashed(),
+                    pinned: meta.pinned,
+                    archived: meta.archived,
+                    totp: meta.kind == NoteKind::Totp,
+                    title: meta.title,
+                    created_at: meta.created_at,
+                    updated_at: meta.updated_at,
+                    tags: meta.tags,
+                    vault: source.name.clone(),
+                })
+            })
+        })
+        .filter(|listing| include_trashed || !listing.trashed)
+        .filter(|listing| all || listing.archived == archived)
+        .filter(|listing| !totp || listing.totp)
+        .filter(|listing| tag.as_ref().is_none_or(|tag| listing.tags.contains(tag)))
+        .filter(|listing| grep_title.as_ref().is_none_or(|text| title_contains(&listing.title, text)))
+        .filter(|listing| {
+            if since.is_none() && until.is_none() {
+                return true;
+            }
+            // Notes from before timestamps were recorded have no date and never match a date bound
+            let date = if by_updated { &listing.updated_at } else { &listing.created_at };
+            DateTime::parse_from_rfc3339(date).is_ok_and(|date| {
+                since.is_none_or(|since| date >= since) && until.is_none_or(|until| date <= until)
+            })
+        })
+        .collect();
+    // Stable sorts, so ties keep insertion order. RFC 3339 timestamps in UTC compare
+    // correctly as strings; notes from before timestamps existed sort first.
+    match sort {
+        Some(SortKey::Title) => listings.sort_by_cached_key(|listing| listing.title.to_lowercase()),
+        Some(SortKey::Created) => listings.sort_by(|a, b| a.created_at.cmp(&b.created_at)),
+        Some(SortKey::Updated) => listings.sort_by(|a, b| a.updated_at.cmp(&b.updated_at)),
+        Some(SortKey::Size) => listings.sort_by_key(|listing| listing.size),
+        None => {}
+    }
+    if reverse {
+        listings.reverse();
+    }
+    listings.sort_by_key(|listing| !listing.pinned); // Pinned notes first, each group still in the chosen order
+    // Page through whatever is left after filtering and sorting
+    let total = listings.len();
+    let listings: Vec<NoteListing> = listings.into_iter().skip(offset).take(limit.unwrap_or(usize::MAX)).collect();
+    let remaining = total.saturating_sub(offset + listings.len());
+
+    if json {
+        let json = serde_json::to_string_pretty(&listings).map_err(VaultError::Serialize)?;
+        println!("{json}");
+    } else if !plain && listings.is_empty() {
+        say!("📭 No notes to show.");
+    } else if !plain {
+        let fields = if fields.is_empty() {
+            vec![ListField::Title]
+        } else {
+            fields
+        };
+        let mut rows: Vec<Vec<String>> = listings
+            .iter()
+            .map(|listing| {
+                fields
+                    .iter()
+                    .map(|field| match field {
+                        ListField::Title => {
+                            let title = match (listing.pinned, listing.trashed) {
+                                (_, true) => format!("{} (in trash)", listing.title),
+                                (true, false) => decorate(&format!("⭐ {}", listing.title)).into_owned(),
+                                (false, false) => listing.title.clone(),
+                            };
+                            if listing.archived { format!("{title} (archived)") } else { title }
+                        }
+                        ListField::Id => listing.id.clone(),
+                        // Just the date
+                        ListField::Created => listing.created_at.get(..10).unwrap_or("-").to_string(),
+                        ListField::Updated => listing.updated_at.get(..10).unwrap_or("-").to_string(),
+                        ListField::Tags => listing.tags.len().to_string(),
+                        ListField::Size => human_size(listing.size),
+                    })
+                    .collect()
+            })
+            .collect();
+        let mut headers: Vec<&str> = fields
+            .iter()
+            .map(|field| match field {
+                ListField::Title => "Title",
+                ListField::Id => "ID",
+                ListField::Created => "Created",
+                ListField::Updated => "Updated",
+                ListField::Tags => "Tags",
+                ListField::Size => "Size",
+            })
+            .collect();
+        let mut right_aligned: Vec<bool> = fields.iter().map(|field| matches!(field, ListField::Tags | ListField::Size)).collect();
+        if several {
+            headers.push("Vault");
+            right_aligned.push(false);
+            for (row, listing) in rows.iter_mut().zip(&listings) {
+                row.push(listing.vault.clone().unwrap_or_default());
+            }
+        }
+        say!("{}", render_table(&headers, &rows, &right_aligned, use_color()));
+    } else {
+        say!("🔐 Decryptable notes:");
+        for listing in &listings {
+            let from = listing.vault.as_ref().map(|name| format!(" [{name}]")).unwrap_or_default();
+            if listing.trashed {
+                say!("🗑️ {} (in trash){}", listing.title, from);
+            } else if listing.archived {
+                say!("📦 {} (archived){}", listing.title, from);
+            } else if listing.pinned {
+                say!("⭐ {}{}", listing.title, from);
+            } else {
+                say!("📌 {}{}", listing.title, from);
+            }
+        }
+    }
+    if !json && remaining > 0 {
+        say!("… {} more (use --offset {})", remaining, offset + listings.len());
+    }
+    // Notes under another key (an old password, or damage) are otherwise invisible.
+    // This goes to stderr so it doesn't disturb `--json` or `--plain` output.
+    if show_locked {
+        let locked: usize = sources
+            .iter()
+            .map(|source| source.vault.notes.iter().filter(|note| open_note(note, source.key).is_none()).count())
+            .sum();
+        if locked > 0 {
+            warn!("⚠️ {} note(s) could not be decrypted with this password", locked);
+        }
+    }
+    Ok(())
+}
+
+/// `Search` the notes of `sources` for `query`
+fn search_notes(sources: &[ListSource], query: &str, case_sensitive: bool, regex: bool, ignore_case: bool) -> Result<(), VaultError> {
+    let pattern = if regex {
+        Some(
+            regex::RegexBuilder::new(query)
+                .case_insensitive(ignore_case)
+                .multi_line(true) // `^` and `$` match at line boundaries, like grep
+                .build()
+                .map_err(VaultError::InvalidRegex)?,
+        )
+    } else {
+        None
+    };
+    say!("🔎 Notes matching '{}':", query);
+    // Like `List`, only notes that decrypt under the current key are considered
+    for source in sources {
+        for note in &source.vault.notes {
+            if let Some((content, meta)) = open_note(note, source.key)
+                && !meta.trashed()
+                && !meta.binary // Base64 would only produce nonsense matches
+                && let Some(line) = match &pattern {
+                    Some(pattern) => search_content_regex(&content, pattern),
+                    None => search_content(&content, query, case_sensitive),
+                }
+            {
+                match &source.name {
+                    Some(name) => say!("📌 {} [{}]", meta.title, name),
+                    None => say!("📌 {}", meta.title),
+                }
+                say!("   ↳ {}", line.trim());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Open the backup at `path` (or any other vault file) in memory, returning it unsealed with
+/// its key. A backup of this vault opens with `key`; otherwise its password is asked for.
+fn open_backup(path: &Path, args: &Args, key: &GenericArray<u8, typenum::U32>) -> Result<(Vault, Zeroizing<GenericArray<u8, typenum::U32>>), VaultError> {
+    if !path.exists() {
+        return Err(VaultError::ReadVault(path.to_path_buf(), io::ErrorKind::NotFound.into()));
+    }
+    let mut backup = load_notes(path)?;
+    check_attempts(&backup)?;
+    let same_key = (!backup.locked || unlock_file(&mut backup, key, path).is_ok()) && !backup.check.is_empty() && verify_key(&backup, key);
+    let backup_key = if same_key {
+        Zeroizing::new(*key)
+    } else {
+        let password = prompt_password(&format!("🔑 Password for '{}': ", path.display()))?;
+        let key_file = args.key_file.as_deref().map(read_key_file).transpose()?;
+        let backup_key = derive_vault_key(&backup, &password, key_file.as_ref().map(|digest| digest.as_slice()))?;
+        unlock_file(&mut backup, &backup_key, path)?;
+        if !verify_key(&backup, &backup_key) {
+            return Err(wrong_password(path, &mut backup, false));
+        }
+        reset_attempts(path, &mut backup, false);
+        backup_key
+    };
+    unseal_vault(&mut backup, &backup_key)?;
+    Ok((backup, backup_key))
+}
+
+/// Print a note for `Read`: just its stored bytes with `raw`, otherwise its content and metadata
+fn show_note(note: &Note, content: &str, meta: &NoteMeta, bytes: &[u8], raw: bool) -> Result<(), VaultError> {
+    if raw {
+        // Exactly the stored bytes (an attachment's original ones), so `vault read x --raw > file` is lossless
+        let mut stdout = io::stdout();
+        return stdout
+            .write_all(bytes)
+            .and_then(|()| stdout.flush())
+            .map_err(|err| VaultError::WriteOutput(PathBuf::from("<stdout>"), err));
+    }
+    if meta.binary {
+        say!("📎 Binary attachment, {}; save it with `--out <PATH>`.", human_size(bytes.len()));
+    } else {
+        say!("🔓 Content: {}", content);
+    }
+    say!("🆔 ID: {}", note.id);
+    if !meta.created_at.is_empty() {
+        say!("🕒 Created: {}", meta.created_at);
+    }
+    if !meta.updated_at.is_empty() {
+        say!("🕒 Updated: {}", meta.updated_at);
+    }
+    if !meta.tags.is_empty() {
+        say!("🏷️ Tags: {}", meta.tags.join(", "));
+    }
+    if !meta.expires_at.is_empty() {
+        say!("⌛ Expires: {}", meta.expires_at);
+    }
+    Ok(())
+}
+
+/// `Read --stats`: line, word and character counts like `wc`, or just bytes for an attachment
+fn show_note_stats(content: &str, meta: &NoteMeta, bytes: &[u8], raw: bool) {
+    let counts = if meta.binary {
+        format!("📏 {} bytes", bytes.len())
+    } else {
+        format!(
+            "📏 {} lines, {} words, {} characters ({} bytes)",
+            content.matches('\n').count(), // Newlines, as `wc -l` counts them
+            content.split_whitespace().count(),
+            content.chars().count(),
+            content.len()
+        )
+    };
+    if raw {
+        eprintln!("{}", decorate(&counts)); // Stdout holds exactly the note
+    } else {
+        say!("{}", counts);
+    }
+}
+
+/// Under `--audit`, log a read by `Read --watch` in the vault file at `path`. The watch holds
+/// no lock, so edits can go on while it runs; this takes it just long enough to save.
+fn log_watched_read(path: &Path, key: &GenericArray<u8, typenum::U32>, note_id: &str) -> Result<(), VaultError> {
+    if !auditing() {
+        return Ok(());
+    }
+    let _lock = lock_vault(path, true)?;
+    let mut vault = load_notes(path)?;
+    unlock_file(&mut vault, key, path)?;
+    unseal_vault(&mut vault, key)?;
+    log_access(&mut vault, key, note_id, AuditAction::Read)?;
+    save_notes(path, &vault, key)
+}
+
+/// `Read --watch`: print `note` again each time it changes in the vault file at `path`, until
+/// Ctrl-C. Saves replace the file by renaming a new one over it, so its directory is watched
+/// rather than the file itself; where the OS gives no change events, it is polled instead.
+/// Each print after the first is logged under `--audit`, like the first one by `Read`.
+fn watch_note(path: &Path, key: &GenericArray<u8, typenum::U32>, mut note: Note, raw: bool, stats: bool) -> Result<(), VaultError> {
+    use notify::{RecursiveMode, Watcher};
+    let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let (sender, events) = std::sync::mpsc::channel();
+    let native = notify::recommended_watcher(sender.clone())
+        .and_then(|mut watcher| watcher.watch(dir, RecursiveMode::NonRecursive).map(|()| watcher));
+    let _watcher: Box<dyn Watcher> = match native {
+        Ok(watcher) => Box::new(watcher),
+        Err(err) => {
+            detail!("👀 No file change events ({}); checking every {}s instead.", err, WATCH_POLL_SECS);
+            let config = notify::Config::default().with_poll_interval(std::time::Duration::from_secs(WATCH_POLL_SECS));
+            let mut watcher = notify::PollWatcher::new(sender, config).map_err(VaultError::Watch)?;
+            watcher.watch(dir, RecursiveMode::NonRecursive).map_err(VaultError::Watch)?;
+            Box::new(watcher)
+        }
+    };
+    // With --raw, stdout holds exactly the note
+    let status = |message: &str| if raw { eprintln!("{}", decorate(message)) } else { say!("{}", message) };
+    status(&format!("👀 Watching '{}' for changes; press Ctrl-C to stop.", path.display()));
+
+    for event in &events {
+        // The temp file and lock file beside the vault change too; only the vault itself matters
+        let Ok(event) = event else { continue };
+        if !event.paths.iter().any(|changed| changed.file_name() == path.file_name()) {
+            continue;
+        }
+        while events.recv_timeout(std::time::Duration::from_millis(WATCH_SETTLE_MS)).is_ok() {}
+
+        let mut vault = load_notes(path)?;
+        unlock_file(&mut vault, key, path)?;
+        if !verify_key(&vault, key) {
+            return Err(VaultError::WatchedVaultRekeyed);
+        }
+        unseal_vault(&mut vault, key)?;
+        // Followed by ID, so a rename doesn't lose it
+        let Some(current) = vault.notes.into_iter().find(|current| current.id == note.id) else {
+            status("🗑️ The note was deleted; stopped watching.");
+            return Ok(());
+        };
+        // Unchanged notes keep their ciphertext when the vault is saved, so this skips other notes' edits
+        if current.content == note.content && current.meta == note.meta {
+            continue;
+        }
+        note = current;
+        let Some((content, meta)) = open_note(&note, key) else {
+            return Err(VaultError::NoteUndecryptable);
+        };
+        if meta.trashed() {
+            status("🗑️ The note was moved to the trash; still watching in case it is restored.");
+            continue;
+        }
+        let bytes = note_bytes(&content, &meta)?;
+        log_watched_read(path, key, &note.id)?; // Its own save is seen next, and skipped as no change
+        if !raw {
+            say!("");
+        }
+        status(&format!("👀 Changed at {}:", Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true)));
+        show_note(&note, &content, &meta, &bytes, raw)?;
+        if stats {
+            show_note_stats(&content, &meta, &bytes, raw);
+        }
+    }
+    Ok(())
+}
+
+/// The text of the note `title` (or ID with `id`) in `notes`, for `Diff`
+fn note_text<'a>(
+    notes: &'a [Note],
+    title: &str,
+    id: bool,
+    key: &GenericArray<u8, typenum::U32>,
+) -> Result<(&'a Note, String, NoteMeta), VaultError> {
+    let Some(index) = find_note_fuzzy(notes, title, id, key)? else {
+        return Err(VaultError::NoteNotFound(title.to_string()));
+    };
+    let Some((content, meta)) = open_note(&notes[index], key) else {
+        return Err(VaultError::NoteUndecryptable);
+    };
+    if meta.binary {
+        return Err(VaultError::BinaryNote(meta.title));
+    }
+    Ok((&notes[index], content, meta))
+}
+
+/// Run one command against the unlocked vault. `args` supplies the global options (e.g. `--cipher`).
+fn run_command(
+    command: VaultCommands,
+    vault: &mut Vault,
+    key: &GenericArray<u8, typenum::U32>,
+    vault_path: &Path,
+    args: &Args,
+) -> Result<(), VaultError> {
+    match command {
+        VaultCommands::Init { force } => {
+            save_notes(vault_path, vault, key)?;
+            if force {
+                warn!("⚠️ The previous vault at '{}' was replaced; its notes are gone.", vault_path.display());
+            }
+            say!("✅ Created an empty vault at '{}'.", vault_path.display());
+        }
+
+        VaultCommands::New { title, content, editor, prompt, from_file, force, tags, expires_in, sensitive, totp } => {
+            let title = normalize_title(&title);
+            let tags = normalize_tags(tags);
+            let expires_at = expires_in.map(|after| (Utc::now() + after).to_rfc3339_opts(SecondsFormat::Secs, true));
+            let existing = find_note(&vault.notes, &title, false, false, key)?;
+            if existing.is_some() && !force {
+                return Err(VaultError::NoteExists(title));
+            }
+            // An attachment is stored as base64 text, so everything downstream handles it like any note
+            let binary = from_file.is_some();
+            let limit = note_size_limit(args, vault_path)?;
+            let content = match from_file {
+                Some(path) => {
+                    let bytes = Zeroizing::new(std::fs::read(&path).map_err(|err| VaultError::ReadAttachment(path, err))?);
+                    check_note_size(&title, bytes.len(), limit)?;
+                    general_purpose::STANDARD.encode(&*bytes)
+                }
+                None => {
+                    let mut content = resolve_content(content, editor, prompt, "")?;
+                    if totp {
+                        content = totp_secret(&title, &content)?;
+                    }
+                    check_note_size(&title, content.len(), limit)?;
+                    content
+                }
+            };
+            let kind = if totp { NoteKind::Totp } else { NoteKind::Text };
+            let now = now_rfc3339();
+
+            if let Some(index) = existing {
+                // Replace in place, keeping the note's ID and creation time
+                let note = &vault.notes[index];
+                let mut meta = decrypt_note_meta(note, key).unwrap_or_default();
+                // The overwritten content goes into the note's history, like an edit
+                let previous = decrypt_body(note, key).map(|content| PastVersion {
+                    content,
+                    written_at: meta.updated_at.clone(),
+                    replaced_at: now.clone(),
+                    binary: meta.binary,
+                });
+                meta.updated_at = now;
+                meta.binary = binary;
+                if !tags.is_empty() {
+                    meta.tags = tags;
+                }
+                if let Some(expires_at) = expires_at {
+                    meta.expires_at = expires_at;
+                }
+                meta.sensitive |= sensitive;
+                meta.kind = kind;
+                let cipher = args.cipher.unwrap_or(note.cipher);
+                revise_note(&mut UsedNonces::of(vault, &vault.notes), &mut vault.notes[index], previous, &content, &meta, cipher, key)?;
+                save_notes(vault_path, vault, key)?;
+                say!("✅ Note '{}' overwritten.", title);
+            } else {
+                let meta = NoteMeta {
+                    title,
+                    created_at: now.clone(),
+                    updated_at: now,
+                    tags,
+                    expires_at: expires_at.unwrap_or_default(),
+                    binary,
+                    sensitive,
+                    kind,
+                    ..NoteMeta::default()
+                };
+                let note = seal_note_fresh(&mut UsedNonces::of(vault, &vault.notes), &new_note_id(), &content, &meta, args.cipher.unwrap_or_default(), key)?;
+                vault.notes.push(note);
+                save_notes(vault_path, vault, key)?;
+                say!("✅ Note added.");
+            }
+        }
+
+        VaultCommands::List(options) => list_notes(&[ListSource { name: None, vault, key }], options)?,
+
+        VaultCommands::Tags => {
+            // Sorted by tag name for stable output
+            let mut counts: std::collections::BTreeMap<String, usize> = std::collections::BTreeMap::new();
+            for note in &vault.notes {
+                if let Some((_, meta)) = open_note(note, key)
+                    && !meta.trashed()
+                {
+                    for tag in meta.tags {
+                        *counts.entry(tag).or_default() += 1;
+                    }
+                }
+            }
+            say!("🏷️ Tags:");
+            for (tag, count) in &counts {
+                say!("   {} ({})", tag, count);
+            }
+        }
+
+        VaultCommands::Edit { title, id, content, editor, prompt, append, tags } => {
+            if let Some(index) = find_note_fuzzy(&vault.notes, &title, id, key)? {
+                let note = &vault.notes[index];
+                // Only overwrite notes we can prove we own
+                let Some((current, mut meta)) = open_note(note, key) else {
+                    return Err(VaultError::NoteUndecryptable);
+                };
+                if meta.binary {
+                    return Err(VaultError::BinaryNote(meta.title));
+                }
+                let content = if append {
+                    // The editor, if it opens, starts empty: only the new text is typed there
+                    let text = resolve_content(content, false, prompt, "")?;
+                    if current.is_empty() || current.ends_with('\n') {
+                        format!("{current}{text}")
+                    } else {
+                        format!("{current}\n{text}")
+                    }
+                } else {
+                    resolve_content(content, editor, prompt, &current)?
+                };
+                let content = if meta.kind == NoteKind::Totp { totp_secret(&meta.title, &content)? } else { content };
+                check_note_size(&meta.title, content.len(), note_size_limit(args, vault_path)?)?;
+                let written_at = std::mem::replace(&mut meta.updated_at, now_rfc3339());
+                if !tags.is_empty() {
+                    meta.tags = normalize_tags(tags);
+                }
+
+                // Always re-encrypt with a fresh nonce; never reuse the stored one (or any other).
+                // Any legacy plaintext title moves into the encrypted metadata.
+                let cipher = args.cipher.unwrap_or(note.cipher);
+                let previous = PastVersion { content: current, written_at, replaced_at: meta.updated_at.clone(), binary: false };
+                let note_id = note.id.clone();
+                revise_note(&mut UsedNonces::of(vault, &vault.notes), &mut vault.notes[index], Some(previous), &content, &meta, cipher, key)?;
+                log_access(vault, key, &note_id, AuditAction::Edit)?;
+                save_notes(vault_path, vault, key)?;
+                say!("✏️ Note updated.");
+            } else {
+                return Err(VaultError::NoteNotFound(title));
+            }
+        }
+
+        VaultCommands::Read { title, id, raw, out, clipboard, clear_after, stats, resolve_links, watch } => {
+            let Some(index) = find_note_fuzzy(&vault.notes, &title, id, key)? else {
+                return Err(VaultError::NoteNotFound(title));
+            };
+            let note = &vault.notes[index];
+            let Some(decrypted) = decrypt_body(note, key) else {
+                return Err(VaultError::NoteUndecryptable);
+            };
+            let meta = decrypt_note_meta(note, key).unwrap_or_default();
+            if meta.sensitive {
+                confirm_password(vault, key, &meta.title, args)?;
+            }
+            let bytes = note_bytes(&decrypted, &meta)?;
+            // Logged before anything is shown, so a read that can't be logged doesn't happen
+            let note_id = note.id.clone();
+            if watch {
+                log_watched_read(vault_path, key, &note_id)?;
+            } else if log_access(vault, key, &note_id, AuditAction::Read)? {
+                save_notes(vault_path, vault, key)?;
+            }
+            let note = &vault.notes[index];
+
+            if let Some(path) = out {
+                create_private_file(&path)
+                    .and_then(|mut file| file.write_all(&bytes))
+                    .map_err(|err| VaultError::WriteOutput(path.clone(), err))?;
+                say!("💾 Wrote note '{}' to '{}'.", title, path.display());
+            } else if clipboard {
+                let name = if meta.title.is_empty() { title.clone() } else { meta.title.clone() };
+                if meta.binary {
+                    return Err(VaultError::BinaryNote(name));
+                }
+                copy_to_clipboard(&name, &decrypted, clear_after)?;
+            } else {
+                show_note(note, &decrypted, &meta, &bytes, raw)?;
+            }
+            if stats {
+                show_note_stats(&decrypted, &meta, &bytes, raw);
+            }
+            if resolve_links && !meta.binary {
+                let links = note_links(&decrypted);
+                if links.is_empty() {
+                    say!("🔗 No [[links]] in this note.");
+                } else {
+                    say!("🔗 Links:");
+                }
+                // Exact titles, like `[[...]]` itself; trashed notes count as missing
+                for link in links {
+                    match find_note(&vault.notes, &link, false, false, key) {
+                        Ok(Some(index)) if decrypt_body(&vault.notes[index], key).is_some() => say!("   ✅ {}", link),
+                        Ok(Some(_)) => say!("   ⚠️ {} (does not decrypt)", link),
+                        Ok(None) => say!("   ❌ {} (no such note)", link),
+                        Err(_) => say!("   ⚠️ {} (several notes have this title)", link),
+                    }
+                }
+            }
+            if watch {
+                watch_note(vault_path, key, note.clone(), raw, stats)?;
+            }
+        }
+
+        VaultCommands::Cat { titles, id } => {
+            // The content is the output asked for, so it prints even with --quiet; problems are only warned about
+            let mut first = true;
+            for title in titles {
+                let index = match find_note_fuzzy(&vault.notes, &title, id, key) {
+                    Ok(Some(index)) => index,
+                    Ok(None) => {
+                        warn!("⚠️ Skipping '{}': note not found.", title);
+                        continue;
+                    }
+                    Err(err) => {
+                        warn!("⚠️ Skipping '{}': {}", title, err);
+                        continue;
+                    }
+                };
+                let Some((content, meta)) = open_note(&vault.notes[index], key) else {
+                    warn!("⚠️ Skipping '{}': it does not decrypt with this password.", title);
+                    continue;
+                };
+                if meta.binary {
+                    warn!("⚠️ Skipping '{}': it is a binary attachment (use `read --out <PATH>`).", meta.title);
+                    continue;
+                }
+                if meta.sensitive {
+                    confirm_password(vault, key, &meta.title, args)?;
+                }
+                let note_id = vault.notes[index].id.clone();
+                if log_access(vault, key, &note_id, AuditAction::Read)? {
+                    save_notes(vault_path, vault, key)?;
+                }
+                if !first {
+                    println!();
+                }
+                first = false;
+                println!("==> {} <==", meta.title);
+                if content.ends_with('\n') {
+                    print!("{content}");
+                } else {
+                    println!("{content}");
+                }
+            }
+        }
+
+        VaultCommands::History { title, id } => {
+            let Some(index) = find_note_fuzzy(&vault.notes, &title, id, key)? else {
+                return Err(VaultError::NoteNotFound(title));
+            };
+            let note = &vault.notes[index];
+            let Some((content, meta)) = open_note(note, key) else {
+                return Err(VaultError::NoteUndecryptable);
+            };
+            if note.history.is_empty() {
+                say!("📭 '{}' has no earlier versions.", meta.title);
+                return Ok(());
+            }
+            say!("🕘 Versions of '{}' (restore one with `revert --version <N>`):", meta.title);
+            say!("   current  {}  {}", or_dash(&meta.updated_at), human_size(content_size(&content, &meta)));
+            for (number, version) in note.history.iter().enumerate() {
+                match open_version(version, key) {
+                    Some(past) => say!(
+                        "   {:>7}  {}  {}",
+                        number + 1,
+                        or_dash(&past.written_at),
+                        human_size(content_size(&past.content, &NoteMeta { binary: past.binary, ..NoteMeta::default() }))
+                    ),
+                    None => say!("   {:>7}  (does not decrypt)", number + 1),
+                }
+            }
+        }
+
+        VaultCommands::Revert { title, id, version } => {
+            let Some(index) = find_note_fuzzy(&vault.notes, &title, id, key)? else {
+                return Err(VaultError::NoteNotFound(title));
+            };
+            let note = &vault.notes[index];
+            let Some((current, mut meta)) = open_note(note, key) else {
+                return Err(VaultError::NoteUndecryptable);
+            };
+            let Some(stored) = note.history.get(version - 1) else {
+                return Err(VaultError::NoSuchVersion(meta.title, version, note.history.len()));
+            };
+            let Some(past) = open_version(stored, key) else {
+                return Err(VaultError::VersionUndecryptable(version));
+            };
+            // The current content becomes the newest history entry, so a revert can be undone too
+            let now = now_rfc3339();
+            let previous = PastVersion {
+                content: current,
+                written_at: std::mem::replace(&mut meta.updated_at, now.clone()),
+                replaced_at: now,
+                binary: std::mem::replace(&mut meta.binary, past.binary),
+            };
+            let cipher = args.cipher.unwrap_or(note.cipher);
+            let note_id = note.id.clone();
+            revise_note(&mut UsedNonces::of(vault, &vault.notes), &mut vault.notes[index], Some(previous), &past.content, &meta, cipher, key)?;
+            log_access(vault, key, &note_id, AuditAction::Edit)?;
+            save_notes(vault_path, vault, key)?;
+            say!("⏪ '{}' reverted to version {} from {}.", meta.title, version, or_dash(&past.written_at));
+        }
+
+        VaultCommands::Totp { title, id } => {
+            let Some(index) = find_note_fuzzy(&vault.notes, &title, id, key)? else {
+                return Err(VaultError::NoteNotFound(title));
+            };
+            let Some((secret, meta)) = open_note(&vault.notes[index], key) else {
+                return Err(VaultError::NoteUndecryptable);
+            };
+            let secret = Zeroizing::new(secret);
+            if meta.kind != NoteKind::Totp {
+                return Err(VaultError::NotTotp(meta.title));
+            }
+            if meta.sensitive {
+                confirm_password(vault, key, &meta.title, args)?;
+            }
+            let now = Utc::now().timestamp().max(0) as u64;
+            let Some(code) = totp_code(&secret, now) else {
+                return Err(VaultError::InvalidTotpSecret(meta.title));
+            };
+            let note_id = vault.notes[index].id.clone();
+            if log_access(vault, key, &note_id, AuditAction::Read)? {
+                save_notes(vault_path, vault, key)?;
+            }
+            // Only the code goes to stdout, so `vault totp github | xclip` copies just that
+            println!("{code}");
+            if verbosity() >= NORMAL {
+                let left = totp_lite::DEFAULT_STEP - now % totp_lite::DEFAULT_STEP;
+                eprintln!("{}", decorate(&format!("🔢 Valid for {left} more second(s).")));
+            }
+        }
+
+        VaultCommands::Info { title, id } => {
+            let Some(index) = find_note_fuzzy(&vault.notes, &title, id, key)? else {
+                return Err(VaultError::NoteNotFound(title));
+            };
+            let note = &vault.notes[index];
+            // Decrypting proves the note is ours; only its length is shown
+            let Some((content, meta)) = open_note(note, key) else {
+                return Err(VaultError::NoteUndecryptable);
+            };
+            say!("📌 Title: {}", meta.title);
+            say!("🆔 ID: {}", note.id);
+            if !meta.created_at.is_empty() {
+                say!("🕒 Created: {}", meta.created_at);
+            }
+            if !meta.updated_at.is_empty() {
+                say!("🕒 Updated: {}", meta.updated_at);
+            }
+            if !meta.tags.is_empty() {
+                say!("🏷️ Tags: {}", meta.tags.join(", "));
+            }
+            if !meta.expires_at.is_empty() {
+                say!("⌛ Expires: {}", meta.expires_at);
+            }
+            if meta.trashed() {
+                say!("🗑️ In trash since: {}", meta.deleted_at);
+            }
+            let size = content_size(&content, &meta);
+            say!("📏 Size: {} ({} bytes{})", human_size(size), size, if note.compressed { ", stored compressed" } else { "" });
+            if meta.sensitive {
+                say!("🔐 Sensitive: the password is asked for again to read it");
+            }
+            if meta.kind == NoteKind::Totp {
+                say!("🔢 TOTP secret; print its code with `totp`");
+            }
+            if meta.archived {
+                say!("📦 Archived; shown by `list --archived`");
+            }
+            if meta.binary {
+                say!("📎 Binary attachment");
+            }
+            say!(
+                "🔐 Cipher: {}",
+                match note.cipher {
+                    Cipher::Aes256Gcm => "AES-256-GCM",
+                    Cipher::ChaCha20Poly1305 => "ChaCha20-Poly1305",
+                }
+            );
+        }
+
+        VaultCommands::Delete { title, id, yes } => {
+            if let Some(index) = find_note_fuzzy(&vault.notes, &title, id, key)? {
+                let note = &vault.notes[index];
+                let name = decrypt_note_meta(note, key).map_or(title, |meta| meta.title);
+                if decrypt_body(note, key).is_some() {
+                    if !yes && !dry_run() && !confirm(&format!("Are you sure you want to delete '{}'?", name))? {
+                        say!("❎ Deletion cancelled.");
+                        return Ok(());
+                    }
+                    // Soft delete: the note stays in the vault, flagged, until the trash is emptied
+                    let mut meta = decrypt_note_meta(&vault.notes[index], key).unwrap_or_default();
+                    meta.deleted_at = now_rfc3339();
+                    let sealed = seal_meta_fresh(&mut UsedNonces::of(vault, &vault.notes), &meta, vault.notes[index].cipher, key)?;
+                    let note = &mut vault.notes[index];
+                    (note.meta, note.meta_nonce) = sealed;
+                    note.title.clear(); // Any legacy plaintext title moves into the encrypted metadata
+                    let note_id = note.id.clone();
+                    log_access(vault, key, &note_id, AuditAction::Delete)?;
+                    save_notes(vault_path, vault, key)?;
+                    say!("🗑️ Note '{}' moved to the trash (use `restore` to undo).", name);
+                } else {
+                    return Err(VaultError::NoteUndecryptable);
+                }
+            } else {
+                return Err(VaultError::NoteNotFound(title));
+            }
+        }
+
+        VaultCommands::Restore { title, id } => {
+            let Some(index) = find_note(&vault.notes, &title, id, true, key)? else {
+                return Err(VaultError::NotInTrash(title));
+            };
+            let Some((_, mut meta)) = open_note(&vault.notes[index], key) else {
+                return Err(VaultError::NoteUndecryptable);
+            };
+            meta.deleted_at.clear();
+            let sealed = seal_meta_fresh(&mut UsedNonces::of(vault, &vault.notes), &meta, vault.notes[index].cipher, key)?;
+            let note = &mut vault.notes[index];
+            (note.meta, note.meta_nonce) = sealed;
+            save_notes(vault_path, vault, key)?;
+            say!("♻️ Note '{}' restored.", meta.title);
+        }
+
+        VaultCommands::Recover { title, from, force } => {
+            let (backup, backup_key) = open_backup(&from, args, key)?;
+            // A note trashed before the backup was taken can be recovered too; it comes back live
+            let found = match find_note(&backup.notes, &title, false, false, &backup_key)? {
+                Some(index) => Some(index),
+                None => find_note(&backup.notes, &title, false, true, &backup_key)?,
+            };
+            let Some(index) = found else {
+                return Err(VaultError::NoteNotFound(title));
+            };
+            let saved = &backup.notes[index];
+            let Some((content, mut meta)) = open_note(saved, &backup_key) else {
+                return Err(VaultError::NoteUndecryptable);
+            };
+            meta.deleted_at.clear();
+            meta.title = normalize_title(&meta.title);
+            let cipher = args.cipher.unwrap_or(saved.cipher);
+
+            if let Some(index) = find_note(&vault.notes, &title, false, false, key)? {
+                if !force {
+                    return Err(VaultError::NoteExists(title));
+                }
+                // The replaced content goes into the note's history, like `New --force`
+                let note = &vault.notes[index];
+                let current = decrypt_note_meta(note, key).unwrap_or_default();
+                let previous = decrypt_body(note, key).map(|content| PastVersion {
+                    content,
+                    written_at: current.updated_at,
+                    replaced_at: now_rfc3339(),
+                    binary: current.binary,
+                });
+                revise_note(&mut UsedNonces::of(vault, &vault.notes), &mut vault.notes[index], previous, &content, &meta, cipher, key)?;
+            } else {
+                // Keep the note's ID unless this vault already uses it
+                let id = if vault.notes.iter().any(|note| note.id == saved.id) || saved.id.is_empty() {
+                    new_note_id()
+                } else {
+                    saved.id.clone()
+                };
+                let note = seal_note_fresh(&mut UsedNonces::of(vault, &vault.notes), &id, &content, &meta, cipher, key)?;
+                vault.notes.push(note);
+            }
+            save_notes(vault_path, vault, key)?;
+            say!("♻️ Note '{}' recovered from '{}'.", meta.title, from.display());
+        }
+
+        VaultCommands::EmptyTrash { yes } => {
+            let in_trash = |note: &Note| open_note(note, key).is_some_and(|(_, meta)| meta.trashed());
+            let count = vault.notes.iter().filter(|note| in_trash(note)).count();
+            if count == 0 {
+                say!("🗑️ The trash is already empty.");
+                return Ok(());
+            }
+            if !yes && !dry_run() && !confirm(&format!("Permanently delete {} note(s) in the trash?", count))? {
+                say!("❎ Trash left as is.");
+                return Ok(());
+            }
+            vault.notes.retain(|note| !in_trash(note));
+            save_notes(vault_path, vault, key)?;
+            say!("🔥 Permanently deleted {} note(s).", count);
+        }
+
+        VaultCommands::Purge { yes, force: _, shred: wipe } => {
+            let question = format!(
+                "This deletes '{}' and all {} note(s) in it for good. Type DELETE to confirm:",
+                vault_path.display(),
+                vault.notes.len()
+            );
+            if !yes && !dry_run() && !confirm_phrase(&question, "DELETE")? {
+                say!("❎ Vault left as is.");
+                return Ok(());
+            }
+            if dry_run() {
+                say!("🔥 Would delete '{}' and its {} note(s); nothing was removed.", vault_path.display(), vault.notes.len());
+                return Ok(());
+            }
+            if wipe
+                && let Some(file) = open_for_shredding(vault_path)
+                && let Err(err) = shred(file)
+            {
+                warn!("⚠️ Could not overwrite '{}' before deleting it: {}", vault_path.display(), err);
+            }
+            std::fs::remove_file(vault_path).map_err(|err| VaultError::WriteVault(vault_path.to_path_buf(), err))?;
+            say!("🔥 Deleted the vault '{}'. Run `vault init` to start over.", vault_path.display());
+        }
+
+        VaultCommands::Audit { title, id } => {
+            // A trashed note was used too; look there if no live note matches
+            let found = match find_note_fuzzy(&vault.notes, &title, id, key)? {
+                Some(index) => Some(index),
+                None => find_note(&vault.notes, &title, id, true, key)?,
+            };
+            let (note_id, name) = match found {
+                Some(index) => {
+                    let note = &vault.notes[index];
+                    (note.id.clone(), decrypt_note_meta(note, key).map_or(title, |meta| meta.title))
+                }
+                None if id => (title.clone(), title), // Its entries outlive the note
+                None => return Err(VaultError::NoteNotFound(title)),
+            };
+            let entries: Vec<AuditEntry> =
+                open_audit_log(vault, key)?.into_iter().filter(|entry| entry.note_id == note_id).collect();
+            if entries.is_empty() {
+                say!("📭 No recorded access to '{}'.", name);
+                if !auditing() {
+                    say!("💡 Access is only logged with --audit, or `audit = true` in vault.toml.");
+                }
+                return Ok(());
+            }
+            say!("📜 Access log of '{}':", name);
+            for entry in entries {
+                say!(
+                    "   {}  {}",
+                    entry.at,
+                    match entry.action {
+                        AuditAction::Read => "read",
+                        AuditAction::Edit => "edit",
+                        AuditAction::Delete => "delete",
+                    }
+                );
+            }
+        }
+
+        VaultCommands::Pin { title, id } => set_pinned(vault, key, vault_path, &title, id, true)?,
+        VaultCommands::Unpin { title, id } => set_pinned(vault, key, vault_path, &title, id, false)?,
+        VaultCommands::Backlinks { title } => {
+            let mut sources: Vec<String> = vault
+                .notes
+                .iter()
+                .filter_map(|note| open_note(note, key))
+                .filter(|(content, meta)| !meta.trashed() && !meta.binary && note_links(content).iter().any(|link| same_title(link, &title)))
+                .map(|(_, meta)| meta.title)
+                .collect();
+            sources.sort_by_cached_key(|source| source.to_lowercase());
+            if sources.is_empty() {
+                say!("📭 No notes link to '{}'.", title);
+            } else {
+                say!("🔗 {} note(s) link to '{}':", sources.len(), title);
+                for source in sources {
+                    say!("📌 {}", source);
+                }
+            }
+        }
+
+        VaultCommands::Archive { title, id } => set_archived(vault, key, vault_path, &title, id, true)?,
+        VaultCommands::Unarchive { title, id } => set_archived(vault, key, vault_path, &title, id, false)?,
+
+        VaultCommands::Rename { old_title, new_title, id, no_clobber } => {
+            let new_title = normalize_title(&new_title);
+            let Some(index) = find_note(&vault.notes, &old_title, id, false, key)? else {
+                return Err(VaultError::NoteNotFound(old_title));
+            };
+            let note = &vault.notes[index];
+            let Some((_, mut meta)) = open_note(note, key) else {
+                return Err(VaultError::NoteUndecryptable);
+            };
+
+            let clobbers = vault.notes.iter().enumerate().any(|(i, other)| {
+                i != index && decrypt_note_meta(other, key).is_some_and(|m| same_title(&m.title, &new_title) && !m.trashed())
+            });
+            if clobbers {
+                if no_clobber {
+                    return Err(VaultError::TitleExists(new_title));
+                }
+                warn!("⚠️ Another note is already titled '{}'; both will share it.", new_title);
+            }
+
+            let old_name = std::mem::replace(&mut meta.title, new_title);
+            meta.updated_at = now_rfc3339();
+            // Only the metadata changes; it gets a fresh nonce like any re-encryption,
+            // and must keep the cipher the content is under
+            let sealed = seal_meta_fresh(&mut UsedNonces::of(vault, &vault.notes), &meta, vault.notes[index].cipher, key)?;
+            let note = &mut vault.notes[index];
+            (note.meta, note.meta_nonce) = sealed;
+            note.title.clear(); // Any legacy plaintext title moves into the encrypted metadata
+            save_notes(vault_path, vault, key)?;
+            say!("🏷️ Renamed '{}' to '{}'.", old_name, meta.title);
+        }
+
+        VaultCommands::Duplicate { title, new_title, id, force } => {
+            let new_title = normalize_title(&new_title);
+            let Some(index) = find_note_fuzzy(&vault.notes, &title, id, key)? else {
+                return Err(VaultError::NoteNotFound(title));
+            };
+            let source = &vault.notes[index];
+            let Some((content, source_meta)) = open_note(source, key) else {
+                return Err(VaultError::NoteUndecryptable);
+            };
+            let cipher = args.cipher.unwrap_or(source.cipher);
+            let existing = find_note(&vault.notes, &new_title, false, false, key)?;
+            if existing.is_some() && !force {
+                return Err(VaultError::NoteExists(new_title));
+            }
+            let now = now_rfc3339();
+
+            if let Some(target) = existing {
+                // Like `new --force`: the target keeps its ID and creation time, and its old content goes into its history
+                let note = &vault.notes[target];
+                let mut meta = decrypt_note_meta(note, key).unwrap_or_default();
+                let previous = decrypt_body(note, key).map(|content| PastVersion {
+                    content,
+                    written_at: meta.updated_at.clone(),
+                    replaced_at: now.clone(),
+                    binary: meta.binary,
+                });
+                meta.updated_at = now;
+                meta.tags = source_meta.tags;
+                meta.binary = source_meta.binary;
+                revise_note(&mut UsedNonces::of(vault, &vault.notes), &mut vault.notes[target], previous, &content, &meta, cipher, key)?;
+            } else {
+                // A new note in its own right: new ID, nonces and timestamps, and no history or expiry
+                let meta = NoteMeta {
+                    title: new_title.clone(),
+                    created_at: now.clone(),
+                    updated_at: now,
+                    tags: source_meta.tags,
+                    binary: source_meta.binary,
+                    ..NoteMeta::default()
+                };
+                let note = seal_note_fresh(&mut UsedNonces::of(vault, &vault.notes), &new_note_id(), &content, &meta, cipher, key)?;
+                vault.notes.push(note);
+            }
+            save_notes(vault_path, vault, key)?;
+            say!("📑 Copied '{}' to '{}'.", source_meta.title, new_title);
+        }
+
+        VaultCommands::ChangePassword => unreachable!("handled by `change_password`"),
+
+        VaultCommands::Search { query, case_sensitive, regex, ignore_case } => {
+            search_notes(&[ListSource { name: None, vault, key }], &query, case_sensitive, regex, ignore_case)?;
+        }
+
+        VaultCommands::Find { tags, contains, before, after } => {
+            let tags = normalize_tags(tags);
+            let mut matches = Vec::new();
+            for note in &vault.notes {
+                let Some((content, meta)) = open_note(note, key) else { continue };
+                if meta.trashed() || !tags.iter().all(|tag| meta.tags.contains(tag)) {
+                    continue;
+                }
+                if let Some(text) = &contains
+                    && (meta.binary || search_content(&content, text, false).is_none())
+                {
+                    continue;
+                }
+                if before.is_some() || after.is_some() {
+                    // Notes from before timestamps were recorded have no date and never match a date bound
+                    let Ok(created) = DateTime::parse_from_rfc3339(&meta.created_at) else { continue };
+                    if before.is_some_and(|before| created >= before) || after.is_some_and(|after| created < after) {
+                        continue;
+                    }
+                }
+                matches.push(meta.title);
+            }
+            if matches.is_empty() {
+                say!("📭 No notes match.");
+            } else {
+                say!("🔎 {} note(s) match:", matches.len());
+                for title in matches {
+                    say!("📌 {}", title);
+                }
+            }
+        }
+
+        VaultCommands::Export { path, format, ndjson } => {
+            warn!("⚠️ WARNING: '{}' will contain your notes UNENCRYPTED.", path.display());
+            let exported = vault.notes.iter().filter_map(|note| match open_note(note, key) {
+                Some((_, meta)) if meta.trashed() => None, // Trashed notes aren't part of the export
+                Some((content, meta)) => Some(PlainNote {
+                    title: meta.title,
+                    content,
+                    created_at: meta.created_at,
+                    binary: meta.binary,
+                    kind: meta.kind,
+                }),
+                None => {
+                    warn!("⚠️ Skipping note {}: it does not decrypt with this password.", note.id);
+                    None
+                }
+            });
+
+            // Streamed note by note, so only one decrypted note is held at a time
+            if ndjson || format == ExportFormat::Ndjson {
+                let write_err = |err| VaultError::WriteOutput(path.clone(), err);
+                let mut out = io::BufWriter::new(create_private_file(&path).map_err(write_err)?);
+                let mut count = 0;
+                for plain in exported {
+                    serde_json::to_writer(&mut out, &plain).map_err(VaultError::Serialize)?;
+                    out.write_all(b"\n").map_err(write_err)?;
+                    count += 1;
+                }
+                out.flush().map_err(write_err)?;
+                say!("📤 Exported {} note(s) to '{}'.", count, path.display());
+                return Ok(());
+            }
+            let exported: Vec<PlainNote> = exported.collect();
+            let serialized = match format {
+                ExportFormat::Json => serde_json::to_string_pretty(&exported).map_err(VaultError::Serialize)?,
+                ExportFormat::Yaml => serde_yaml_ng::to_string(&exported).map_err(|err| VaultError::SerializeExport(err.to_string()))?,
+                ExportFormat::Toml => toml::to_string_pretty(&TomlExport { notes: &exported })
+                    .map_err(|err| VaultError::SerializeExport(err.to_string()))?,
+                ExportFormat::Ndjson => unreachable!("streamed above"),
+            };
+            create_private_file(&path)
+                .and_then(|mut file| file.write_all(serialized.as_bytes()))
+                .map_err(|err| VaultError::WriteOutput(path.clone(), err))?;
+            say!("📤 Exported {} note(s) to '{}'.", exported.len(), path.display());
+        }
+
+        VaultCommands::Import { path, replace_on_conflict } => {
+            let json = std::fs::read_to_string(&path).map_err(|err| VaultError::ReadImport(path.clone(), err))?;
+            let mut imported: Vec<PlainNote> =
+                serde_json::from_str(&json).map_err(|err| VaultError::ParseImport(path.clone(), err))?;
+            // All or nothing: every note is checked before any is added, and the notes are
+            // staged in a copy that only replaces the vault's once all of them are encrypted
+            let limit = note_size_limit(args, vault_path)?;
+            for (index, plain) in imported.iter_mut().enumerate() {
+                plain.title = normalize_title(&plain.title);
+                let invalid = |reason| VaultError::InvalidImport(path.clone(), index + 1, reason);
+                if plain.title.trim().is_empty() {
+                    return Err(invalid("it has no title"));
+                }
+                if plain.binary && general_purpose::STANDARD.decode(&plain.content).is_err() {
+                    return Err(invalid("its attachment content is not valid base64"));
+                }
+                if plain.kind == NoteKind::Totp {
+                    plain.content = totp_secret(&plain.title, &plain.content)?; // Stored the way `new --totp` stores it
+                }
+                let meta = NoteMeta { binary: plain.binary, ..NoteMeta::default() };
+                check_note_size(&plain.title, content_size(&plain.content, &meta), limit)?;
+            }
+            let mut notes = vault.notes.clone();
+            let mut used = UsedNonces::of(vault, &notes);
+
+            let (mut added, mut replaced, mut skipped) = (0, 0, 0);
+            let progress = progress_bar(imported.len(), "Importing");
+            for plain in progress.wrap_iter(imported.into_iter()) {
+                let now = now_rfc3339();
+                match find_note(&notes, &plain.title, false, false, key)? {
+                    Some(index) if replace_on_conflict => {
+                        // Keep the existing note's identity and creation time
+                        let existing = &notes[index];
+                        let mut meta = decrypt_note_meta(existing, key).unwrap_or_default();
+                        // The replaced content goes into the note's history, like `new --force`
+                        let previous = decrypt_body(existing, key).map(|content| PastVersion {
+                            content,
+                            written_at: meta.updated_at.clone(),
+                            replaced_at: now.clone(),
+                            binary: meta.binary,
+                        });
+                        meta.updated_at = now;
+                        meta.binary = plain.binary;
+                        meta.kind = plain.kind;
+                        let cipher = args.cipher.unwrap_or(existing.cipher);
+                        revise_note(&mut used, &mut notes[index], previous, &plain.content, &meta, cipher, key)?;
+                        replaced += 1;
+                    }
+                    Some(_) => {
+                        progress.suspend(|| say!("⏭️ Skipping '{}': a note with that title already exists.", plain.title));
+                        skipped += 1;
+                    }
+                    None => {
+                        let meta = NoteMeta {
+                            title: plain.title,
+                            created_at: if plain.created_at.is_empty() { now.clone() } else { plain.created_at },
+                            updated_at: now,
+                            binary: plain.binary,
+                            kind: plain.kind,
+                            ..NoteMeta::default()
+                        };
+                        notes.push(seal_note_fresh(&mut used, &new_note_id(), &plain.content, &meta, args.cipher.unwrap_or_default(), key)?);
+                        added += 1;
+                    }
+                }
+            }
+            progress.finish_and_clear();
+
+            if added + replaced > 0 {
+                commit_notes(vault, notes, vault_path, key)?;
+            }
+            say!("📥 Imported {} note(s): {} added, {} replaced, {} skipped.", added + replaced, added, replaced, skipped);
+        }
+
+        VaultCommands::Merge { other, on_conflict, other_key_file } => {
+            let other_path = std::path::absolute(&other).unwrap_or(other);
+            let same_file = |a: &Path, b: &Path| a.canonicalize().ok().is_some_and(|a| b.canonicalize().is_ok_and(|b| a == b));
+            if same_file(&other_path, vault_path) {
+                return Err(VaultError::MergeIntoSelf);
+            }
+            if !other_path.exists() {
+                return Err(VaultError::ReadVault(other_path, io::ErrorKind::NotFound.into()));
+            }
+
+            // Unlock the other vault in memory only; only its lockout counter is ever written
+            let mut other = load_notes(&other_path)?;
+            check_attempts(&other)?;
+            let other_password = prompt_password(&format!("🔑 Password for '{}': ", other_path.display()))?;
+            let other_key_file = other_key_file.as_deref().map(read_key_file).transpose()?;
+            let other_key = derive_vault_key(&other, &other_password, other_key_file.as_ref().map(|digest| digest.as_slice()))?;
+            drop(other_password);
+            unlock_file(&mut other, &other_key, &other_path)?;
+            if !verify_key(&other, &other_key) {
+                return Err(wrong_password(&other_path, &mut other, false));
+            }
+            reset_attempts(&other_path, &mut other, false);
+            unseal_vault(&mut other, &other_key)?;
+
+            // Staged in a copy, so a failure part-way leaves the vault's notes as they were
+            let mut notes = vault.notes.clone();
+            let mut used = UsedNonces::of(vault, &notes);
+            let (mut added, mut renamed, mut overwritten, mut skipped, mut locked) = (0, 0, 0, 0, 0);
+            for incoming in &other.notes {
+                let Some((content, mut meta)) = open_note(incoming, &other_key) else {
+                    locked += 1;
+                    continue;
+                };
+                meta.title = normalize_title(&meta.title);
+                let cipher = args.cipher.unwrap_or(incoming.cipher);
+                match find_note(&notes, &meta.title, false, meta.trashed(), key)? {
+                    Some(_) if on_conflict == OnConflict::Skip => {
+                        say!("⏭️ Skipping '{}': a note with that title already exists.", meta.title);
+                        skipped += 1;
+                    }
+                    Some(index) if on_conflict == OnConflict::Overwrite => {
+                        // The overwritten content goes into the note's history, like `new --force`
+                        let existing = &notes[index];
+                        let previous = decrypt_note_meta(existing, key).and_then(|old| {
+                            decrypt_body(existing, key).map(|content| PastVersion {
+                                content,
+                                written_at: old.updated_at,
+                                replaced_at: now_rfc3339(),
+                                binary: old.binary,
+                            })
+                        });
+                        revise_note(&mut used, &mut notes[index], previous, &content, &meta, cipher, key)?;
+                        overwritten += 1;
+                    }
+                    existing => {
+                        if existing.is_some() {
+                            // First free "<title> (merged)", "<title> (merged 2)", ...
+                            let base = meta.title.clone();
+                            meta.title = format!("{base} (merged)");
+                            for n in 2.. {
+                                if find_note(&notes, &meta.title, false, meta.trashed(), key)?.is_none() {
+                                    break;
+                                }
+                                meta.title = format!("{base} (merged {n})");
+                            }
+                            say!("🏷️ Adding '{}' as '{}'.", base, meta.title);
+                            renamed += 1;
+                        } else {
+                            added += 1;
+                        }
+                        // Keep the note's ID unless this vault already uses it
+                        let id = if notes.iter().any(|note| note.id == incoming.id) || incoming.id.is_empty() {
+                            new_note_id()
+                        } else {
+                            incoming.id.clone()
+                        };
+                        notes.push(seal_note_fresh(&mut used, &id, &content, &meta, cipher, key)?);
+                    }
+                }
+            }
+            if locked > 0 {
+                warn!("⚠️ {} note(s) in '{}' don't decrypt with that password and were not merged.", locked, other_path.display());
+            }
+            if added + renamed + overwritten > 0 {
+                commit_notes(vault, notes, vault_path, key)?;
+            }
+            say!(
+                "🔀 Merged {} note(s) from '{}': {} added, {} renamed, {} overwritten, {} skipped.",
+                added + renamed + overwritten,
+                other_path.display(),
+                added,
+                renamed,
+                overwritten,
+                skipped
+            );
+        }
+
+        VaultCommands::Stats { per_tag } => {
+            let (mut unlocked, mut trashed) = (0, 0);
+            let mut dates: Vec<DateTime<chrono::FixedOffset>> = Vec::new();
+            // Tag -> (notes, content bytes) over notes outside the trash, like `Tags`; "" for untagged ones
+            let mut by_tag: std::collections::HashMap<String, (usize, usize)> = std::collections::HashMap::new();
+            for note in &vault.notes {
+                if let Some((content, meta)) = open_note(note, key) {
+                    unlocked += 1;
+                    trashed += usize::from(meta.trashed());
+                    dates.extend(DateTime::parse_from_rfc3339(&meta.created_at).ok());
+                    if per_tag && !meta.trashed() {
+                        let size = content_size(&content, &meta);
+                        let tags = if meta.tags.is_empty() { vec![String::new()] } else { meta.tags };
+                        for tag in tags {
+                            let entry = by_tag.entry(tag).or_default();
+                            entry.0 += 1;
+                            entry.1 += size;
+                        }
+                    }
+                }
+            }
+            let encrypted_bytes: usize = vault
+                .notes
+                .iter()
+                .map(|note| encrypted_len(&note.content) + encrypted_len(&note.meta))
+                .sum();
+
+            say!("📊 Vault statistics:");
+            say!("   Total notes:        {}", vault.notes.len());
+            say!("   Decryptable:        {}", unlocked);
+            say!("   In trash:           {}", trashed);
+            say!("   Locked/other key:   {}", vault.notes.len() - unlocked);
+            say!("   Encrypted size:     {} bytes", encrypted_bytes);
+            if let Some(lockout) = vault.lockout {
+                say!(
+                    "   Lockout:            after {} wrong password(s){}",
+                    lockout.max_attempts,
+                    if lockout.wipe { ", deleting the file" } else { "" }
+                );
+            }
+            if let (Some(oldest), Some(newest)) = (dates.iter().min(), dates.iter().max()) {
+                say!("   Oldest note:        {}", oldest.to_rfc3339_opts(SecondsFormat::Secs, true));
+                say!("   Newest note:        {}", newest.to_rfc3339_opts(SecondsFormat::Secs, true));
+            }
+            if per_tag && !by_tag.is_empty() {
+                // Most used first, ties by name; untagged notes come last
+                let mut tags: Vec<(String, (usize, usize))> = by_tag.into_iter().collect();
+                tags.sort_by(|(a, (a_count, _)), (b, (b_count, _))| {
+                    a.is_empty().cmp(&b.is_empty()).then(b_count.cmp(a_count)).then(a.cmp(b))
+                });
+                let rows: Vec<Vec<String>> = tags
+                    .into_iter()
+                    .map(|(tag, (count, size))| {
+                        vec![if tag.is_empty() { "(untagged)".to_string() } else { tag }, count.to_string(), human_size(size)]
+                    })
+                    .collect();
+                say!("");
+                say!("{}", render_table(&["Tag", "Notes", "Size"], &rows, &[false, true, true], use_color()));
+            }
+        }
+
+        VaultCommands::Verify => {
+            // Trashed notes count too; nothing is saved, so the vault is left exactly as it was
+            let (mut ok, mut failed, mut malformed) = (0, 0, 0);
+            for note in &vault.notes {
+                if !well_formed(&note.content, &note.nonce) || (!note.meta.is_empty() && !well_formed(&note.meta, &note.meta_nonce)) {
+                    malformed += 1;
+                    say!("⚠️ Note {}: ciphertext or nonce is malformed.", note.id);
+                } else if open_note(note, key).is_some() {
+                    ok += 1;
+                } else {
+                    failed += 1;
+                    say!("⚠️ Note {}: does not decrypt with this password (damaged or tampered with?).", note.id);
+                }
+            }
+            say!("🩺 Checked {} note(s): {} OK, {} failed to decrypt, {} malformed.", vault.notes.len(), ok, failed, malformed);
+            if failed + malformed > 0 {
+                return Err(VaultError::VerifyFailed(failed + malformed));
+            }
+        }
+
+        VaultCommands::GenPassword { length, symbols, no_ambiguous, save_as: Some(title) } => {
+            let title = normalize_title(&title);
+            if find_note(&vault.notes, &title, false, false, key)?.is_some() {
+                return Err(VaultError::TitleExists(title));
+            }
+            let password = generate_password(length, symbols, no_ambiguous)?;
+            let now = now_rfc3339();
+            let meta = NoteMeta {
+                title: title.clone(),
+                created_at: now.clone(),
+                updated_at: now,
+                ..NoteMeta::default()
+            };
+            vault.notes.push(seal_note_fresh(&mut UsedNonces::of(vault, &vault.notes), &new_note_id(), &password, &meta, args.cipher.unwrap_or_default(), key)?);
+            save_notes(vault_path, vault, key)?;
+            println!("{}", *password);
+            say!("✅ Saved as note '{}'.", title);
+        }
+
+        VaultCommands::Seal => {
+            if vault.layout == Layout::Sealed {
+                say!("🔒 Vault is already sealed.");
+                return Ok(());
+            }
+            vault.layout = Layout::Sealed;
+            save_notes(vault_path, vault, key)?;
+            say!("🔒 Vault sealed; only its header is readable without the password.");
+        }
+
+        VaultCommands::Unseal => {
+            if vault.layout == Layout::PerNote {
+                say!("🔓 Vault is not sealed.");
+                return Ok(());
+            }
+            vault.layout = Layout::PerNote;
+            save_notes(vault_path, vault, key)?;
+            say!("🔓 Vault unsealed; notes are stored individually encrypted again.");
+        }
+
+        VaultCommands::Lock => {
+            if vault.locked {
+                say!("🔐 Vault file is already locked.");
+                return Ok(());
+            }
+            if vault.lockout.is_some() {
+                return Err(VaultError::LockoutLocked);
+            }
+            vault.locked = true;
+            save_notes(vault_path, vault, key)?;
+            say!("🔐 Vault file locked; it reads as opaque bytes until `unlock`.");
+        }
+
+        VaultCommands::Unlock => {
+            if !vault.locked {
+                say!("🔓 Vault file is not locked.");
+                return Ok(());
+            }
+            vault.locked = false;
+            save_notes(vault_path, vault, key)?;
+            say!("🔓 Vault file unlocked.");
+        }
+
+        VaultCommands::Lockout { max_attempts, panic_wipe } => {
+            if vault.locked && max_attempts > 0 {
+                return Err(VaultError::LockoutLocked);
+            }
+            vault.lockout = (max_attempts > 0).then_some(LockoutPolicy { max_attempts, wipe: panic_wipe, failed: 0 });
+            (vault.check, vault.check_nonce) = seal_check(&mut UsedNonces::of(vault, &vault.notes), vault.lockout, key)?;
+            save_notes(vault_path, vault, key)?;
+            match vault.lockout {
+                None => say!("🛡️ Lockout turned off."),
+                Some(lockout) if lockout.wipe => {
+                    say!("🛡️ The vault file will be deleted after {} wrong password(s) in a row.", lockout.max_attempts)
+                }
+                Some(lockout) => say!("🛡️ The vault will stop opening after {} wrong password(s) in a row.", lockout.max_attempts),
+            }
+        }
+
+        VaultCommands::Diff { title_a, title_b, id, against_backup } => {
+            let (note, current, meta) = note_text(&vault.notes, &title_a, id, key)?;
+            let (old, old_name, new, new_name) = if let Some(path) = against_backup {
+                let (backup, backup_key) = open_backup(&path, args, key)?;
+                // Matched by ID, so a note renamed since the backup is still found
+                let (_, saved, _) = match note_text(&backup.notes, &note.id, true, &backup_key) {
+                    Err(VaultError::NoteNotFound(_)) => note_text(&backup.notes, &meta.title, false, &backup_key)?,
+                    found => found?,
+                };
+                (saved, format!("{} ({})", meta.title, path.display()), current, meta.title)
+            } else {
+                let title_b = title_b.expect("required without --against-backup");
+                let (_, other, other_meta) = note_text(&vault.notes, &title_b, id, key)?;
+                (current, meta.title, other, other_meta.title)
+            };
+            match render_diff(&old, &new, &old_name, &new_name, use_color()) {
+                Some(diff) => print!("{diff}"),
+                None => say!("✅ '{}' and '{}' are identical.", old_name, new_name),
+            }
+        }
+
+        VaultCommands::Completions { .. }
+        | VaultCommands::Agent { .. }
+        | VaultCommands::Backup { .. }
+        | VaultCommands::Profiles
+        | VaultCommands::SelfTest
+        | VaultCommands::GenPassword { .. } => unreachable!("handled before the vault is opened"),
+        VaultCommands::Shell => unreachable!("the shell runs commands itself"),
+        VaultCommands::Rekey => unreachable!("handled by `rekey_vault`"),
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+    use std::cell::{Cell, RefCell};
+    use std::collections::VecDeque;
+
+    /// Argon2id costs low enough to derive keys quickly in tests
+    const CHEAP_ARGON2: KdfParams = KdfParams { memory_kib: 64, iterations: 1, parallelism: 1 };
+
+    fn test_key() -> GenericArray<u8, typenum::U32> {
+        GenericArray::from([7u8; 32])
+    }
+
+    fn meta(title: &str) -> NoteMeta {
+        NoteMeta { title: title.to_string(), ..NoteMeta::default() }
+    }
+
+    /// A vault at `<dir>/vault.json` holding one note titled "first", saved under `test_key`
+    fn saved_vault(dir: &Path) -> (PathBuf, Vault) {
+        let path = dir.join("vault.json");
+        let key = test_key();
+        let mut vault = Vault::default();
+        (vault.check, vault.check_nonce) = encrypt_note_content(CHECK_PLAINTEXT, Cipher::Aes256Gcm, &key).unwrap();
+        vault.notes.push(seal_note(&new_note_id(), "hello", &meta("first"), Cipher::Aes256Gcm, &key).unwrap());
+        save_notes(&path, &vault, &key).unwrap();
+        (path, vault)
+    }
+
+    thread_local! {
+        /// Stands in for `OsRng` in `fill_random` while `with_rng` runs
+        static TEST_RNG: RefCell<Option<Box<dyn RngCore>>> = const { RefCell::new(None) };
+    }
+
+    /// `fill_random` from the RNG installed by `with_rng`, if any
+    pub(super) fn fill_from_test_rng(bytes: &mut [u8]) -> Option<Result<(), VaultError>> {
+        TEST_RNG.with_borrow_mut(|rng| rng.as_mut().map(|rng| rng.try_fill_bytes(bytes).map_err(VaultError::Entropy)))
+    }
+
+    /// Run `f` with `rng` drawing every salt and nonce on this thread
+    fn with_rng<T>(rng: impl RngCore + 'static, f: impl FnOnce() -> T) -> T {
+        TEST_RNG.set(Some(Box::new(rng)));
+        let result = f();
+        TEST_RNG.set(None);
+        result
+    }
+
+    /// An RNG with no entropy to give, as on an embedded target whose OS RNG isn't seeded
+    struct FailingRng;
+
+    impl RngCore for FailingRng {
+        fn next_u32(&mut self) -> u32 {
+            unreachable!("only `try_fill_bytes` is used")
+        }
+        fn next_u64(&mut self) -> u64 {
+            unreachable!("only `try_fill_bytes` is used")
+        }
+        fn fill_bytes(&mut self, _: &mut [u8]) {
+            unreachable!("only `try_fill_bytes` is used")
+        }
+        fn try_fill_bytes(&mut self, _: &mut [u8]) -> Result<(), rand::Error> {
+            Err(rand::Error::new(io::Error::other("no entropy")))
+        }
+    }
+
+    fn args(command: &[&str]) -> Args {
+        Args::try_parse_from(["vault"].iter().chain(command)).unwrap()
+    }
+
+    /// Run `command` (as typed after `vault`) on `vault`, saved at `path` under `test_key`
+    fn run_on(vault: &mut Vault, path: &Path, command: &[&str]) -> Result<(), VaultError> {
+        let args = args(command);
+        run_command(args.command.clone(), vault, &test_key(), path, &args)
+    }
+
+    thread_local! {
+        /// `--audit` for the test running on this thread, instead of the process-wide `AUDIT`
+        pub(super) static AUDITING: Cell<bool> = const { Cell::new(false) };
+    }
+
+    /// Turns auditing on for this thread until dropped
+    struct Audited;
+
+    fn audited() -> Audited {
+        AUDITING.set(true);
+        Audited
+    }
+
+    impl Drop for Audited {
+        fn drop(&mut self) {
+            AUDITING.set(false);
+        }
+    }
+
+    thread_local! {
+        /// Stands in for the terminal in `prompt_password` and `ask` while `answering` runs
+        static ANSWERS: RefCell<Option<VecDeque<String>>> = const { RefCell::new(None) };
+    }
+
+    /// The next line `answering` scripted, if a script is running; once it runs out, `missing`
+    pub(super) fn scripted_answer(missing: VaultError) -> Option<Result<String, VaultError>> {
+        ANSWERS.with_borrow_mut(|answers| answers.as_mut().map(|answers| answers.pop_front().ok_or(missing)))
+    }
+
+    /// Run `f` with `answers` typed at its prompts, one per prompt; asserts all were asked for
+    fn answering<T>(answers: &[&str], f: impl FnOnce() -> T) -> T {
+        ANSWERS.set(Some(answers.iter().map(|answer| answer.to_string()).collect()));
+        let result = f();
+        let left = ANSWERS.take().unwrap_or_default();
+        assert!(left.is_empty(), "unanswered prompts expected: {left:?}");
+        result
+    }
+
+    /// A vault at `<dir>/<name>.json` keyed from `password` with cheap Argon2 costs, holding one
+    /// note titled "first" with `content`; returns it with its key
+    fn password_vault(dir: &Path, name: &str, password: &str, content: &str) -> (PathBuf, Vault, Zeroizing<GenericArray<u8, typenum::U32>>) {
+        let path = dir.join(format!("{name}.json"));
+        let mut vault = Vault { argon2: CHEAP_ARGON2, ..Vault::default() };
+        vault.salt = general_purpose::STANDARD.encode(generate_salt().unwrap());
+        let key = derive_vault_key(&vault, password, None).unwrap();
+        (vault.check, vault.check_nonce) = encrypt_note_content(CHECK_PLAINTEXT, Cipher::Aes256Gcm, &key).unwrap();
+        vault.notes.push(seal_note(&new_note_id(), content, &meta("first"), Cipher::Aes256Gcm, &key).unwrap());
+        save_notes(&path, &vault, &key).unwrap();
+        (path, vault, key)
+    }
+
+    #[test]
+    fn wrong_length_nonce_fails_cleanly() {
+        let key = test_key();
+        let (ciphertext, nonce) = encrypt_note_content("secret", Cipher::Aes256Gcm, &key).unwrap();
+        let mut short = general_purpose::STANDARD.decode(&nonce).unwrap();
+        short.pop();
+        let short = general_purpose::STANDARD.encode(short);
+        for cipher in [Cipher::Aes256Gcm, Cipher::ChaCha20Poly1305] {
+            assert_eq!(decrypt_note_content(&ciphertext, &short, cipher, &key), None);
+        }
+        assert!(!well_formed(&ciphertext, &short));
+    }
+
+    #[test]
+    fn failed_write_leaves_the_vault_intact() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, vault) = saved_vault(dir.path());
+        let before = std::fs::read(&path).unwrap();
+        // A directory where the temp file goes makes the write fail before the rename
+        std::fs::create_dir(temp_path(&path)).unwrap();
+        assert!(write_vault_file(&path, &vault, b"truncated").is_err());
+        assert_eq!(std::fs::read(&path).unwrap(), before);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn vault_file_is_owner_only() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir().unwrap();
+        let (path, _) = saved_vault(dir.path());
+        assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinked_vault_is_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let (target, _) = saved_vault(dir.path());
+        let link = dir.path().join("link.json");
+        std::os::unix::fs::symlink(&target, &link).unwrap();
+        assert!(matches!(check_symlink(link.clone(), false), Err(VaultError::VaultSymlink(path)) if path == link));
+        assert_eq!(check_symlink(link, true).unwrap(), target.canonicalize().unwrap());
+    }
+
+    #[test]
+    fn failing_rng_is_an_error() {
+        let key = test_key();
+        let encrypted = with_rng(FailingRng, || encrypt_note_content("secret", Cipher::Aes256Gcm, &key));
+        assert!(matches!(encrypted, Err(VaultError::Entropy(_))));
+        assert!(matches!(with_rng(FailingRng, generate_salt), Err(VaultError::Entropy(_))));
+    }
+
+    #[test]
+    fn failing_rng_stops_password_generation() {
+        assert!(matches!(with_rng(FailingRng, || generate_password(20, true, false)), Err(VaultError::Entropy(_))));
+    }
+
+    #[test]
+    fn repeated_nonce_is_drawn_again() {
+        let key = test_key();
+        let first = with_rng(StdRng::seed_from_u64(1), || seal_note("a", "one", &meta("a"), Cipher::Aes256Gcm, &key)).unwrap();
+        // The same seed draws the same nonces again, so the first attempt collides with `first`
+        let again = with_rng(StdRng::seed_from_u64(1), || seal_note("b", "two", &meta("b"), Cipher::Aes256Gcm, &key)).unwrap();
+        assert_eq!(again.nonce, first.nonce);
+        let mut used = UsedNonces::of(&Vault::default(), std::slice::from_ref(&first));
+        let fresh = with_rng(StdRng::seed_from_u64(1), || seal_note_fresh(&mut used, "b", "two", &meta("b"), Cipher::Aes256Gcm, &key)).unwrap();
+        let before = UsedNonces::of(&Vault::default(), std::slice::from_ref(&first));
+        assert!(!before.contains(&fresh.nonce) && !before.contains(&fresh.meta_nonce));
+        assert!(used.contains(&fresh.nonce) && used.contains(&fresh.meta_nonce));
+        assert_eq!(decrypt_body(&fresh, &key).as_deref(), Some("two"));
+    }
+
+    #[test]
+    fn import_with_an_invalid_record_changes_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, mut vault) = saved_vault(dir.path());
+        let before = std::fs::read(&path).unwrap();
+        let import = dir.path().join("import.json");
+        let records = r#"[{"title": "second", "content": "ok"}, {"title": " ", "content": "no title"}]"#;
+        std::fs::write(&import, records).unwrap();
+        let args = args(&["import", import.to_str().unwrap()]);
+        let imported = run_command(args.command.clone(), &mut vault, &test_key(), &path, &args);
+        assert!(matches!(imported, Err(VaultError::InvalidImport(_, 2, _))));
+        assert_eq!(vault.notes.len(), 1);
+        assert_eq!(std::fs::read(&path).unwrap(), before);
+    }
+
+    #[test]
+    fn lockout_trips_at_max_attempts_and_resets_on_the_right_password() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("vault.json");
+        let vault = Vault { lockout: Some(LockoutPolicy { max_attempts: 3, wipe: false, failed: 0 }), ..Vault::default() };
+        write_header(&path, &vault).unwrap();
+        let fail = |times| {
+            for _ in 0..times {
+                let mut loaded = load_notes(&path).unwrap();
+                assert!(check_attempts(&loaded).is_ok());
+                assert!(matches!(wrong_password(&path, &mut loaded, false), VaultError::WrongPassword));
+            }
+        };
+
+        // Two wrong passwords, then the right one: the count starts over
+        fail(2);
+        let mut loaded = load_notes(&path).unwrap();
+        reset_attempts(&path, &mut loaded, false);
+        assert_eq!(load_notes(&path).unwrap().lockout.unwrap().failed, 0);
+
+        fail(2);
+        assert!(check_attempts(&load_notes(&path).unwrap()).is_ok());
+        fail(1);
+        assert!(matches!(check_attempts(&load_notes(&path).unwrap()), Err(VaultError::AttemptsExhausted(3))));
+    }
+
+    #[test]
+    fn each_read_is_audited() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, mut vault) = saved_vault(dir.path());
+        let key = test_key();
+        let _audited = audited();
+        let args = args(&["read", "first"]);
+        for _ in 0..2 {
+            run_command(args.command.clone(), &mut vault, &key, &path, &args).unwrap();
+        }
+        let saved = load_notes(&path).unwrap();
+        let log = open_audit_log(&saved, &key).unwrap();
+        assert_eq!(log.len(), 2);
+        assert!(log.iter().all(|entry| entry.note_id == vault.notes[0].id && matches!(entry.action, AuditAction::Read)));
+    }
+
+    #[test]
+    fn backup_copies_the_file_and_keep_prunes_the_oldest() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, _) = saved_vault(dir.path());
+        let backups = dir.path().join("backups");
+        let copies: Vec<PathBuf> = (0..3).map(|_| backup_vault(&path, &backups).unwrap()).collect();
+        assert_eq!(std::fs::read(&copies[0]).unwrap(), std::fs::read(&path).unwrap());
+        // Names outside the backup scheme are left alone, including ones the timestamp cut would split mid-character
+        let unrelated = backups.join("vault-ma-sauvegarde-réelle.json");
+        std::fs::write(&unrelated, "keep me").unwrap();
+
+        assert_eq!(prune_backups(&path, &backups, 1).unwrap(), 2);
+        assert!(!copies[0].exists() && !copies[1].exists());
+        assert!(copies[2].exists() && unrelated.exists());
+    }
+
+    #[test]
+    fn backups_next_to_profiles_are_not_profiles() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, _) = saved_vault(dir.path());
+        assert_eq!(list_profiles(dir.path()).unwrap(), ["vault"]);
+        backup_vault(&path, dir.path()).unwrap();
+        backup_vault(&path, dir.path()).unwrap();
+        assert_eq!(list_profiles(dir.path()).unwrap(), ["vault"]);
+    }
+
+    #[test]
+    fn imported_totp_secret_is_normalized() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, mut vault) = saved_vault(dir.path());
+        let key = test_key();
+        let import = dir.path().join("import.json");
+        std::fs::write(&import, r#"[{"title": "gh", "kind": "totp", "content": "jbsw y3dp ehpk 3pxp"}]"#).unwrap();
+        for command in [&["import", import.to_str().unwrap()][..], &["totp", "gh"]] {
+            let args = args(command);
+            run_command(args.command.clone(), &mut vault, &key, &path, &args).unwrap();
+        }
+        let (content, _) = open_note(&vault.notes[1], &key).unwrap();
+        assert_eq!(content, "JBSWY3DPEHPK3PXP");
+    }
+
+    #[test]
+    fn merge_overwrite_keeps_the_old_content_in_history() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, mut vault, key) = password_vault(dir.path(), "vault", "pw", "mine");
+        let (other, _, _) = password_vault(dir.path(), "other", "other pw", "theirs");
+        let args = args(&["merge", other.to_str().unwrap(), "--on-conflict", "overwrite"]);
+        answering(&["other pw"], || run_command(args.command.clone(), &mut vault, &key, &path, &args)).unwrap();
+
+        let saved = load_notes(&path).unwrap();
+        assert_eq!(saved.notes.len(), 1);
+        assert_eq!(open_note(&saved.notes[0], &key).unwrap().0, "theirs");
+        let history: Vec<String> = saved.notes[0].history.iter().map(|version| open_version(version, &key).unwrap().content).collect();
+        assert_eq!(history, ["mine"]);
+    }
+
+    #[test]
+    fn grep_title_matches_a_decomposed_query() {
+        let title = normalize_title("Café notes");
+        assert!(title_contains(&title, "cafe\u{301}"));
+        assert!(title_contains(&title, "CAFÉ"));
+        assert!(!title_contains(&title, "cafe notes"));
+    }
+
+
+    /// Every nonce stored in `vault`, header and notes alike, repeats included
+    fn all_nonces(vault: &Vault) -> Vec<String> {
+        let notes = vault.notes.iter().flat_map(|note| {
+            [note.nonce.clone(), note.meta_nonce.clone()].into_iter().chain(note.history.iter().map(|version| version.nonce.clone()))
+        });
+        [vault.check_nonce.clone(), vault.audit_nonce.clone()].into_iter().chain(notes).filter(|nonce| !nonce.is_empty()).collect()
+    }
+
+    #[test]
+    fn repeated_pins_and_renames_keep_every_nonce_distinct() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, mut vault) = saved_vault(dir.path());
+        let key = test_key();
+        let _audited = audited();
+        // Every seal below draws from the seed the check token was drawn from, so each first try repeats its nonce
+        let seed = || StdRng::seed_from_u64(1);
+        (vault.check, vault.check_nonce) = with_rng(seed(), || seal_check(&mut UsedNonces::of(&vault, &vault.notes), None, &key)).unwrap();
+        for _ in 0..5 {
+            for command in [&["pin", "first"][..], &["read", "first"], &["rename", "first", "second"], &["unpin", "second"], &["rename", "second", "first"]] {
+                let args = args(command);
+                with_rng(seed(), || run_command(args.command.clone(), &mut vault, &key, &path, &args)).unwrap();
+            }
+        }
+        let nonces = all_nonces(&vault);
+        let distinct: std::collections::HashSet<&String> = nonces.iter().collect();
+        assert_eq!(distinct.len(), nonces.len());
+        assert!(!vault.audit_nonce.is_empty());
+    }
+
+
+    #[test]
+    fn same_password_and_salt_give_the_same_key() {
+        let salt = [1u8; SALT_LEN];
+        let key = derive_key_from_password("correct horse", &salt, CHEAP_ARGON2, None).unwrap();
+        assert_eq!(*derive_key_from_password("correct horse", &salt, CHEAP_ARGON2, None).unwrap(), *key);
+        assert_ne!(*derive_key_from_password("correct horse", &[2u8; SALT_LEN], CHEAP_ARGON2, None).unwrap(), *key);
+        assert_ne!(*derive_key_from_password("correct horse!", &salt, CHEAP_ARGON2, None).unwrap(), *key);
+    }
+
+
+    #[test]
+    fn notes_round_trip_through_a_vault_flag_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("elsewhere.json");
+        let key = test_key();
+        let args = args(&["--vault", path.to_str().unwrap(), "new", "note", "round trip"]);
+        let vault_path = resolve_vault_path(args.vault.first().cloned(), None).unwrap();
+        assert_eq!(vault_path, path);
+        run_command(args.command.clone(), &mut Vault::default(), &key, &vault_path, &args).unwrap();
+
+        let saved = load_notes(&path).unwrap();
+        assert_eq!(saved.notes.len(), 1);
+        let (content, meta) = open_note(&saved.notes[0], &key).unwrap();
+        assert_eq!((content.as_str(), meta.title.as_str()), ("round trip", "note"));
+    }
+
+
+    #[test]
+    fn edit_replaces_the_ciphertext_and_the_nonce() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, mut vault) = saved_vault(dir.path());
+        let key = test_key();
+        let before = vault.notes[0].clone();
+        let args = args(&["edit", "first", "changed"]);
+        run_command(args.command.clone(), &mut vault, &key, &path, &args).unwrap();
+
+        let after = &load_notes(&path).unwrap().notes[0];
+        assert_eq!(after.id, before.id);
+        assert_ne!(after.content, before.content);
+        assert_ne!(after.nonce, before.nonce);
+        assert_eq!(decrypt_body(after, &key).as_deref(), Some("changed"));
+    }
+
+
+    #[test]
+    fn saved_titles_are_not_in_plaintext() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, mut vault) = saved_vault(dir.path());
+        let args = args(&["new", "Bank PIN", "1234"]);
+        run_command(args.command.clone(), &mut vault, &test_key(), &path, &args).unwrap();
+
+        let raw = std::fs::read_to_string(&path).unwrap();
+        assert!(!raw.contains("Bank PIN") && !raw.contains("first"));
+        assert!(load_notes(&path).unwrap().notes.iter().all(|note| note.title.is_empty()));
+    }
+
+
+    #[test]
+    fn unwritable_vault_location_is_an_error_not_a_panic() {
+        let dir = tempfile::tempdir().unwrap();
+        // A file where the vault's directory should be can't be written into, even by root
+        let blocker = dir.path().join("not-a-dir");
+        std::fs::write(&blocker, "").unwrap();
+        let path = blocker.join("vault.json");
+        let saved = save_notes(&path, &Vault::default(), &test_key());
+        assert!(matches!(&saved, Err(VaultError::WriteVault(failed, _)) if *failed == path));
+        assert!(saved.unwrap_err().to_string().starts_with("Could not write vault"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn read_only_vault_directory_is_an_error_not_a_panic() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir().unwrap();
+        let (path, mut vault) = saved_vault(dir.path());
+        std::fs::set_permissions(dir.path(), std::fs::Permissions::from_mode(0o555)).unwrap();
+        // Permissions don't stop root, so there is nothing to check when the tests run as root
+        let writable = File::create(dir.path().join("probe")).is_ok();
+        let args = args(&["new", "second", "content"]);
+        let saved = (!writable).then(|| run_command(args.command.clone(), &mut vault, &test_key(), &path, &args));
+        std::fs::set_permissions(dir.path(), std::fs::Permissions::from_mode(0o755)).unwrap();
+        if let Some(saved) = saved {
+            assert!(matches!(saved, Err(VaultError::WriteVault(failed, _)) if failed == path));
+            assert_eq!(load_notes(&path).unwrap().notes.len(), 1);
+        }
+    }
+
+
+    #[test]
+    fn change_password_retires_the_old_one() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join(CONFIG_FILE), "[argon2]\nmemory_kib = 64\niterations = 1\nparallelism = 1\n").unwrap();
+        let (path, mut vault, key) = password_vault(dir.path(), "vault", "old password", "hello");
+        vault.notes.push(seal_note(&new_note_id(), "world", &meta("second"), Cipher::ChaCha20Poly1305, &key).unwrap());
+        let args = args(&["change-password"]);
+        answering(&["new password", "new password"], || {
+            change_password(&vault, &key, Some(Zeroizing::new("old password".into())), &path, &args)
+        })
+        .unwrap();
+
+        let saved = load_notes(&path).unwrap();
+        assert!(!verify_key(&saved, &derive_vault_key(&saved, "old password", None).unwrap()));
+        let new_key = derive_vault_key(&saved, "new password", None).unwrap();
+        assert!(verify_key(&saved, &new_key));
+        let contents: Vec<String> = saved.notes.iter().map(|note| decrypt_body(note, &new_key).unwrap()).collect();
+        assert_eq!(contents, ["hello", "world"]);
+    }
+
+
+    #[test]
+    fn notes_sharing_a_title_are_told_apart_by_id() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, mut vault) = saved_vault(dir.path());
+        let key = test_key();
+        vault.notes.push(seal_note(&new_note_id(), "other", &meta("first"), Cipher::Aes256Gcm, &key).unwrap());
+        let ids: Vec<String> = vault.notes.iter().map(|note| note.id.clone()).collect();
+        assert_ne!(ids[0], ids[1]);
+
+        let read = run_on(&mut vault, &path, &["read", "first"]);
+        assert!(matches!(read, Err(VaultError::AmbiguousTitle(_, listed)) if listed == ids));
+
+        run_on(&mut vault, &path, &["edit", "--id", &ids[1], "changed"]).unwrap();
+        let saved = load_notes(&path).unwrap();
+        assert_eq!(saved.notes.iter().map(|note| note.id.clone()).collect::<Vec<_>>(), ids);
+        assert_eq!(decrypt_body(&saved.notes[0], &key).as_deref(), Some("hello"));
+        assert_eq!(decrypt_body(&saved.notes[1], &key).as_deref(), Some("changed"));
+        assert_eq!(find_note(&saved.notes, &ids[1], true, false, &key).unwrap(), Some(1));
+    }
+
+
+    #[test]
+    fn renamed_note_is_found_by_its_new_title_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, mut vault) = saved_vault(dir.path());
+        let key = test_key();
+        run_on(&mut vault, &path, &["rename", "first", "renamed"]).unwrap();
+
+        let saved = load_notes(&path).unwrap();
+        assert_eq!(find_note(&saved.notes, "renamed", false, false, &key).unwrap(), Some(0));
+        assert_eq!(find_note(&saved.notes, "first", false, false, &key).unwrap(), None);
+        assert_eq!(decrypt_body(&saved.notes[0], &key).as_deref(), Some("hello"));
+        assert!(matches!(run_on(&mut vault, &path, &["read", "first"]), Err(VaultError::NoteNotFound(_))));
+    }
+
+
+    /// Title and content of every note of `vault` that opens under `test_key`, in order
+    fn contents(vault: &Vault) -> Vec<(String, String)> {
+        vault.notes.iter().filter_map(|note| open_note(note, &test_key())).map(|(content, meta)| (meta.title, content)).collect()
+    }
+
+    #[test]
+    fn export_round_trips_through_import() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, mut vault) = saved_vault(dir.path());
+        let dated = NoteMeta { created_at: "2020-01-02T03:04:05Z".into(), ..meta("dated") };
+        vault.notes.push(seal_note(&new_note_id(), "old", &dated, Cipher::ChaCha20Poly1305, &test_key()).unwrap());
+        run_on(&mut vault, &path, &["new", "second", "two\nlines\n"]).unwrap();
+        let export = dir.path().join("export.json");
+        run_on(&mut vault, &path, &["export", export.to_str().unwrap()]).unwrap();
+
+        let other = dir.path().join("other.json");
+        let mut imported = Vault::default();
+        run_on(&mut imported, &other, &["import", export.to_str().unwrap()]).unwrap();
+        assert_eq!(contents(&load_notes(&other).unwrap()), contents(&vault));
+        assert_eq!(decrypt_note_meta(&imported.notes[1], &test_key()).unwrap().created_at, dated.created_at);
+    }
+
+
+    #[test]
+    fn imported_notes_decrypt_to_their_content() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, mut vault) = saved_vault(dir.path());
+        let import = dir.path().join("import.json");
+        let records = r#"[{"title": "Wifi", "content": "hunter2"}, {"title": "Café", "content": "two\nlines ✓\n"}]"#;
+        std::fs::write(&import, records).unwrap();
+        run_on(&mut vault, &path, &["import", import.to_str().unwrap()]).unwrap();
+
+        let expected = [("first", "hello"), ("Wifi", "hunter2"), ("Café", "two\nlines ✓\n")];
+        let expected: Vec<(String, String)> = expected.iter().map(|(title, content)| (title.to_string(), content.to_string())).collect();
+        assert_eq!(contents(&load_notes(&path).unwrap()), expected);
+    }
+
+
+    #[test]
+    fn each_cipher_round_trips_and_the_other_one_fails() {
+        let key = test_key();
+        for (cipher, other) in [(Cipher::Aes256Gcm, Cipher::ChaCha20Poly1305), (Cipher::ChaCha20Poly1305, Cipher::Aes256Gcm)] {
+            let (ciphertext, nonce) = encrypt_note_content("secret", cipher, &key).unwrap();
+            assert_eq!(decrypt_note_content(&ciphertext, &nonce, cipher, &key).as_deref(), Some("secret"));
+            assert_eq!(decrypt_note_content(&ciphertext, &nonce, other, &key), None);
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let (path, mut vault) = saved_vault(dir.path());
+        run_on(&mut vault, &path, &["--cipher", "chacha20-poly1305", "new", "second", "sealed with ChaCha"]).unwrap();
+        let saved = load_notes(&path).unwrap();
+        assert_eq!(saved.notes[1].cipher, Cipher::ChaCha20Poly1305);
+        assert_eq!(decrypt_body(&saved.notes[1], &key).as_deref(), Some("sealed with ChaCha"));
+        let swapped = Note { cipher: Cipher::Aes256Gcm, ..saved.notes[1].clone() };
+        assert_eq!(decrypt_body(&swapped, &key), None);
+    }
+
+
+    /// Compiles only for types that wipe their memory when dropped
+    fn wiped_on_drop<T: zeroize::ZeroizeOnDrop>(_: &T) {}
+
+    #[test]
+    fn password_and_key_are_wiped_on_drop() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("password");
+        std::fs::write(&file, "secret\n").unwrap();
+        let password = read_master_password(&args(&["--password-file", file.to_str().unwrap(), "list"])).unwrap();
+        wiped_on_drop(&password);
+        assert_eq!(password.as_str(), "secret");
+
+        let mut key = derive_key_from_password(&password, &[1u8; SALT_LEN], CHEAP_ARGON2, None).unwrap();
+        wiped_on_drop(&key);
+        wiped_on_drop(&generate_password(20, false, false).unwrap());
+        // What dropping `key` does to its bytes
+        assert!(key.iter().any(|&byte| byte != 0));
+        zeroize::Zeroize::zeroize(&mut *key);
+        assert!(key.iter().all(|&byte| byte == 0));
+    }
+
+
+    #[test]
+    fn new_refuses_an_existing_title_unless_forced() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, mut vault) = saved_vault(dir.path());
+        let before = std::fs::read(&path).unwrap();
+        let refused = run_on(&mut vault, &path, &["new", "first", "replacement"]);
+        assert!(matches!(refused, Err(VaultError::NoteExists(title)) if title == "first"));
+        assert_eq!(std::fs::read(&path).unwrap(), before);
+
+        let id = vault.notes[0].id.clone();
+        run_on(&mut vault, &path, &["new", "first", "replacement", "--force"]).unwrap();
+        let saved = load_notes(&path).unwrap();
+        assert_eq!(saved.notes.len(), 1);
+        assert_eq!(saved.notes[0].id, id);
+        assert_eq!(decrypt_body(&saved.notes[0], &test_key()).as_deref(), Some("replacement"));
+    }
+
+
+    /// Whether the note titled `title` in `vault` is in the trash
+    fn trashed(vault: &Vault, title: &str) -> bool {
+        find_note(&vault.notes, title, false, true, &test_key()).unwrap().is_some()
+    }
+
+    #[test]
+    fn delete_asks_first_unless_told_yes() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, mut vault) = saved_vault(dir.path());
+        answering(&["n"], || run_on(&mut vault, &path, &["delete", "first"])).unwrap();
+        assert!(!trashed(&load_notes(&path).unwrap(), "first"));
+        answering(&["y"], || run_on(&mut vault, &path, &["delete", "first"])).unwrap();
+        assert!(trashed(&load_notes(&path).unwrap(), "first"));
+
+        run_on(&mut vault, &path, &["new", "second", "content"]).unwrap();
+        // No answers scripted: a prompt would fail the test
+        answering(&[], || run_on(&mut vault, &path, &["delete", "second", "--yes"])).unwrap();
+        assert!(trashed(&load_notes(&path).unwrap(), "second"));
+    }
+
+
+    #[test]
+    fn trash_restores_and_empties() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, mut vault) = saved_vault(dir.path());
+        run_on(&mut vault, &path, &["new", "second", "keep me"]).unwrap();
+        run_on(&mut vault, &path, &["delete", "first", "--yes"]).unwrap();
+        run_on(&mut vault, &path, &["restore", "first"]).unwrap();
+        let saved = load_notes(&path).unwrap();
+        assert!(!trashed(&saved, "first"));
+        assert_eq!(contents(&saved)[0], ("first".to_string(), "hello".to_string()));
+
+        let kept = vault.notes[1].id.clone();
+        run_on(&mut vault, &path, &["delete", "first", "--yes"]).unwrap();
+        run_on(&mut vault, &path, &["empty-trash", "--yes"]).unwrap();
+        let saved = load_notes(&path).unwrap();
+        assert_eq!(saved.notes.iter().map(|note| note.id.clone()).collect::<Vec<_>>(), [kept]);
+        assert!(matches!(run_on(&mut vault, &path, &["restore", "first"]), Err(VaultError::NotInTrash(_))));
+    }
+
+
+    /// A clipboard in memory. With `replaced_by`, that text is copied by "someone else" as soon
+    /// as the clipboard is first read.
+    #[cfg(feature = "clipboard")]
+    #[derive(Default)]
+    struct MockClipboard {
+        text: Option<String>,
+        replaced_by: Option<&'static str>,
+    }
+
+    #[cfg(feature = "clipboard")]
+    impl Clipboard for MockClipboard {
+        fn set_text(&mut self, text: &str) -> Result<(), VaultError> {
+            self.text = Some(text.to_string());
+            Ok(())
+        }
+        fn get_text(&mut self) -> Result<String, VaultError> {
+            if let Some(other) = self.replaced_by.take() {
+                self.text = Some(other.to_string());
+            }
+            self.text.clone().ok_or_else(|| VaultError::Clipboard("empty".into()))
+        }
+        fn clear(&mut self) -> Result<(), VaultError> {
+            self.text = None;
+            Ok(())
+        }
+    }
+
+    #[cfg(feature = "clipboard")]
+    #[test]
+    fn clipboard_is_cleared_only_if_it_still_holds_the_note() {
+        let mut clipboard = MockClipboard::default();
+        copy_to(&mut clipboard, "note", "secret", None).unwrap();
+        assert_eq!(clipboard.text.as_deref(), Some("secret"));
+        copy_to(&mut clipboard, "note", "secret", Some(0)).unwrap();
+        assert_eq!(clipboard.text, None);
+
+        let mut clipboard = MockClipboard { replaced_by: Some("copied since"), ..MockClipboard::default() };
+        copy_to(&mut clipboard, "note", "secret", Some(0)).unwrap();
+        assert_eq!(clipboard.text.as_deref(), Some("copied since"));
+    }
+
+
+    #[test]
+    fn each_profile_resolves_to_its_own_file() {
+        let dir = profile_dir().unwrap();
+        let work = resolve_vault_path(None, Some("work")).unwrap();
+        assert_eq!(work, dir.join(format!("work.{PROFILE_EXTENSION}")));
+        assert_ne!(resolve_vault_path(None, Some("home")).unwrap(), work);
+        // A profile wins over `--vault` and `$VAULT_FILE`
+        assert_eq!(resolve_vault_path(Some(PathBuf::from("elsewhere.json")), Some("work")).unwrap(), work);
+        for name in ["", ".", "..", "../escape", "a/b", "a\\b"] {
+            assert!(matches!(resolve_vault_path(None, Some(name)), Err(VaultError::InvalidProfile(_))), "{name:?}");
+        }
+    }
+
+
+    #[test]
+    fn generated_passwords_keep_to_length_and_classes() {
+        for _ in 0..50 {
+            let plain = generate_password(4, false, false).unwrap();
+            assert_eq!(plain.chars().count(), 4);
+            assert!(plain.chars().all(|c| c.is_ascii_alphanumeric()));
+            assert!(plain.chars().any(|c| c.is_ascii_lowercase()) && plain.chars().any(|c| c.is_ascii_uppercase()));
+            assert!(plain.chars().any(|c| c.is_ascii_digit()));
+
+            let strong = generate_password(32, true, true).unwrap();
+            assert_eq!(strong.chars().count(), 32);
+            assert!(strong.chars().any(|c| PASSWORD_SYMBOLS.contains(c)));
+            assert!(strong.chars().all(|c| c.is_ascii_alphanumeric() || PASSWORD_SYMBOLS.contains(c)));
+            assert!(!strong.chars().any(|c| AMBIGUOUS_CHARS.contains(c)));
+        }
+        assert_eq!(generate_password(1024, false, false).unwrap().len(), 1024);
+    }
+
+
+    #[test]
+    fn newer_vault_versions_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, _) = saved_vault(dir.path());
+        let mut json: serde_json::Value = serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(json["version"], VAULT_VERSION);
+        assert_eq!(load_notes(&path).unwrap().notes.len(), 1);
+
+        json["version"] = (VAULT_VERSION + 1).into();
+        std::fs::write(&path, serde_json::to_vec(&json).unwrap()).unwrap();
+        assert!(matches!(load_notes(&path), Err(VaultError::NewerVault)));
+    }
+
+
+    #[test]
+    fn legacy_array_file_loads_and_upgrades_without_loss() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("vault.json");
+        let key = derive_legacy_key("old password");
+        let (content, nonce) = encrypt_note_content("from the first version", Cipher::Aes256Gcm, &key).unwrap();
+        let legacy = serde_json::json!([{ "title": "Old note", "content": content, "nonce": nonce }]).to_string();
+        std::fs::write(&path, &legacy).unwrap();
+
+        let vault = load_notes(&path).unwrap();
+        assert_eq!(vault.kdf, Kdf::LegacySha256);
+        assert_eq!(*derive_vault_key(&vault, "old password", None).unwrap(), *key);
+        save_notes(&path, &vault, &key).unwrap();
+
+        // The original file is kept next to the upgraded one, which still holds the note
+        assert_eq!(std::fs::read_to_string(dir.path().join("vault.json.bak")).unwrap(), legacy);
+        let upgraded = load_notes(&path).unwrap();
+        assert!(std::fs::read_to_string(&path).unwrap().trim_start().starts_with('{'));
+        assert_eq!(upgraded.kdf, Kdf::LegacySha256);
+        let (content, meta) = open_note(&upgraded.notes[0], &key).unwrap();
+        assert_eq!((meta.title.as_str(), content.as_str()), ("Old note", "from the first version"));
+    }
+
+
+    #[test]
+    fn sealed_vault_file_shows_no_note_structure() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, mut vault) = saved_vault(dir.path());
+        run_on(&mut vault, &path, &["new", "Bank PIN", "1234"]).unwrap();
+        run_on(&mut vault, &path, &["seal"]).unwrap();
+
+        let raw = std::fs::read_to_string(&path).unwrap();
+        for hidden in [vault.notes[0].id.as_str(), &vault.notes[1].id, "Bank PIN", "\"notes\"", "meta_nonce", "\"content\""] {
+            assert!(!raw.contains(hidden), "'{hidden}' is readable in the sealed file");
+        }
+        let mut sealed = load_notes(&path).unwrap();
+        assert_eq!(sealed.layout, Layout::Sealed);
+        assert!(sealed.notes.is_empty());
+        unseal_vault(&mut sealed, &test_key()).unwrap();
+        assert_eq!(contents(&sealed), contents(&vault));
+    }
+
+
+    #[test]
+    fn verify_reports_a_corrupted_note() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, mut vault) = saved_vault(dir.path());
+        run_on(&mut vault, &path, &["new", "second", "intact"]).unwrap();
+        run_on(&mut vault, &path, &["verify"]).unwrap();
+
+        let mut ciphertext = general_purpose::STANDARD.decode(&vault.notes[0].content).unwrap();
+        ciphertext[0] ^= 1;
+        vault.notes[0].content = general_purpose::STANDARD.encode(ciphertext);
+        let verified = run_on(&mut vault, &path, &["verify"]);
+        assert!(matches!(verified, Err(VaultError::VerifyFailed(1))));
+        assert_ne!(verified.unwrap_err().exit_code(), 0);
+
+        vault.notes[1].nonce = "not base64!".into();
+        assert!(matches!(run_on(&mut vault, &path, &["verify"]), Err(VaultError::VerifyFailed(2))));
+    }
+
+
+    #[test]
+    fn fuzzy_title_uses_a_single_match_and_lists_ambiguous_ones() {
+        let key = test_key();
+        let notes: Vec<Note> = ["Bank PIN", "Shopping list", "Work list", "list"]
+            .iter()
+            .map(|title| seal_note(&new_note_id(), "", &meta(title), Cipher::Aes256Gcm, &key).unwrap())
+            .collect();
+
+        assert_eq!(find_note_fuzzy(&notes, "list", false, &key).unwrap(), Some(3));
+        assert_eq!(find_note_fuzzy(&notes, "bank", false, &key).unwrap(), Some(0));
+        assert_eq!(find_note_fuzzy(&notes, "BANK PIN", false, &key).unwrap(), Some(0));
+        assert_eq!(find_note_fuzzy(&notes, "nothing", false, &key).unwrap(), None);
+        let ambiguous = find_note_fuzzy(&notes, "pin", false, &key);
+        assert!(matches!(ambiguous, Err(VaultError::AmbiguousMatch(_, ref titles)) if titles == &["Bank PIN", "Shopping list"]));
+    }
+
+
+    #[test]
+    fn large_notes_are_compressed_and_small_ones_are_not() {
+        let key = test_key();
+        let large = "2026-10-14 12:00:00 INFO request served\n".repeat(100);
+        let small = "a".repeat(COMPRESS_THRESHOLD - 1);
+        for (content, compressed) in [(large.as_str(), true), (small.as_str(), false), ("", false)] {
+            let note = seal_note(&new_note_id(), content, &meta("note"), Cipher::Aes256Gcm, &key).unwrap();
+            assert_eq!(note.compressed, compressed, "{} bytes", content.len());
+            assert_eq!(open_note(&note, &key).unwrap().0, content);
+        }
+        let note = seal_note(&new_note_id(), &large, &meta("log"), Cipher::Aes256Gcm, &key).unwrap();
+        assert!(general_purpose::STANDARD.decode(&note.content).unwrap().len() < large.len() / 4);
+    }
+
+
+    #[test]
+    fn a_new_vault_password_must_be_typed_twice() {
+        let typed = args(&["init"]);
+        let password = answering(&["pw", "pw"], || read_new_master_password(&typed)).unwrap();
+        assert_eq!(password.as_str(), "pw");
+        let mismatch = answering(&["pw", "typo"], || read_new_master_password(&typed));
+        assert!(matches!(mismatch, Err(VaultError::PasswordMismatch)));
+
+        // A password from a file was not typed, so is not asked for again
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("password");
+        std::fs::write(&file, "from file\n").unwrap();
+        let from_file = args(&["--password-file", file.to_str().unwrap(), "init"]);
+        assert_eq!(answering(&[], || read_new_master_password(&from_file)).unwrap().as_str(), "from file");
+    }
+
+
+    /// `vault shell` input from a list of lines, recording what goes into the history
+    struct ScriptedShell {
+        lines: VecDeque<&'static str>,
+        history: Vec<String>,
+    }
+
+    impl ShellInput for ScriptedShell {
+        fn read_line(&mut self, _prompt: &str) -> Result<Option<String>, VaultError> {
+            Ok(self.lines.pop_front().map(str::to_string))
+        }
+        fn remember(&mut self, line: &str) {
+            self.history.push(line.to_string());
+        }
+    }
+
+    #[test]
+    fn shell_runs_scripted_lines_until_quit() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, mut vault) = saved_vault(dir.path());
+        let lines = [
+            "new Alpha 'top secret'",
+            "edit Alpha 'even more secret'",
+            "not-a-command",
+            "change-password",
+            "list",
+            "delete first --yes",
+            "quit",
+            "new Never",
+        ];
+        let mut shell = ScriptedShell { lines: lines.into(), history: Vec::new() };
+        shell_loop(&mut shell, &mut vault, &test_key(), &path, &args(&["shell"])).unwrap();
+
+        // Every change was saved as it was made, and nothing after `quit` ran
+        let saved = load_notes(&path).unwrap();
+        assert_eq!(contents(&saved), [("first".to_string(), "hello".to_string()), ("Alpha".to_string(), "even more secret".to_string())]);
+        assert!(trashed(&saved, "first"));
+        // Lines carrying note content stay out of the history
+        assert_eq!(shell.history, ["not-a-command", "change-password", "list", "delete first --yes"]);
+
+        // Running out of input ends the session like Ctrl-D
+        let mut shell = ScriptedShell { lines: ["list"].into(), history: Vec::new() };
+        shell_loop(&mut shell, &mut vault, &test_key(), &path, &args(&["shell"])).unwrap();
+    }
+
+
+    #[test]
+    fn the_password_alone_does_not_open_a_key_file_vault() {
+        let salt = [1u8; SALT_LEN];
+        let digest = Sha256::digest(b"second factor bytes");
+        let key = derive_key_from_password("pw", &salt, CHEAP_ARGON2, Some(digest.as_slice())).unwrap();
+        let note = seal_note(&new_note_id(), "two factors", &meta("Secret"), Cipher::Aes256Gcm, &key).unwrap();
+
+        let without = derive_key_from_password("pw", &salt, CHEAP_ARGON2, None).unwrap();
+        assert!(open_note(&note, &without).is_none());
+        assert_eq!(open_note(&note, &key).unwrap().0, "two factors");
+        // The vault records the need for one, so opening without it fails outright
+        let vault = Vault { salt: general_purpose::STANDARD.encode(salt), key_file: true, ..Vault::default() };
+        assert!(matches!(derive_vault_key(&vault, "pw", None), Err(VaultError::KeyFileRequired)));
+    }
+
+
+    #[test]
+    fn revert_restores_the_chosen_earlier_version() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, mut vault) = saved_vault(dir.path());
+        run_on(&mut vault, &path, &["edit", "first", "second draft"]).unwrap();
+        run_on(&mut vault, &path, &["edit", "first", "third draft"]).unwrap();
+        assert_eq!(vault.notes[0].history.len(), 2);
+
+        run_on(&mut vault, &path, &["revert", "first", "--version", "2"]).unwrap();
+        assert_eq!(contents(&load_notes(&path).unwrap()), [("first".to_string(), "hello".to_string())]);
+        // Reverting keeps what it replaced, so the revert itself can be undone
+        run_on(&mut vault, &path, &["revert", "first"]).unwrap();
+        assert_eq!(contents(&vault), [("first".to_string(), "third draft".to_string())]);
+        assert!(matches!(run_on(&mut vault, &path, &["revert", "first", "--version", "9"]), Err(VaultError::NoSuchVersion(..))));
+    }
+
+
+    #[test]
+    fn rekey_rewrites_old_cipher_notes_under_the_default() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join(CONFIG_FILE), "[argon2]\nmemory_kib = 64\niterations = 1\nparallelism = 1\n").unwrap();
+        let (path, mut vault, key) = password_vault(dir.path(), "vault", "pw", "hello");
+        vault.notes.push(seal_note(&new_note_id(), "older", &meta("second"), Cipher::ChaCha20Poly1305, &key).unwrap());
+        save_notes(&path, &vault, &key).unwrap();
+
+        rekey_vault(&vault, &key, Some(Zeroizing::new("pw".into())), &path, &args(&["rekey"])).unwrap();
+        let saved = load_notes(&path).unwrap();
+        let new_key = derive_vault_key(&saved, "pw", None).unwrap();
+        assert!(verify_key(&saved, &new_key));
+        assert!(saved.notes.iter().all(|note| note.cipher == Cipher::default()));
+        let contents: Vec<String> = saved.notes.iter().map(|note| decrypt_body(note, &new_key).unwrap()).collect();
+        assert_eq!(contents, ["hello", "older"]);
+    }
+
+
+    #[test]
+    fn duplicate_copies_content_and_tags_under_fresh_nonces_and_dates() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, mut vault) = saved_vault(dir.path());
+        let source = NoteMeta { created_at: "2020-01-02T03:04:05Z".into(), tags: vec!["work".into()], ..meta("template") };
+        vault.notes.push(seal_note(&new_note_id(), "shared text", &source, Cipher::Aes256Gcm, &test_key()).unwrap());
+        run_on(&mut vault, &path, &["duplicate", "template", "copy"]).unwrap();
+
+        let (original, copy) = (&vault.notes[1], &vault.notes[2]);
+        let (content, copied) = open_note(copy, &test_key()).unwrap();
+        assert_eq!((content.as_str(), copied.title.as_str(), copied.tags.as_slice()), ("shared text", "copy", ["work".to_string()].as_slice()));
+        assert_ne!(copy.id, original.id);
+        assert_ne!(copy.nonce, original.nonce);
+        assert_ne!(copy.meta_nonce, original.meta_nonce);
+        assert!(copied.created_at > source.created_at);
+
+        assert!(matches!(run_on(&mut vault, &path, &["duplicate", "first", "copy"]), Err(VaultError::NoteExists(_))));
+        run_on(&mut vault, &path, &["duplicate", "first", "copy", "--force"]).unwrap();
+        assert_eq!(contents(&load_notes(&path).unwrap()).last().unwrap(), &("copy".to_string(), "hello".to_string()));
+    }
+
+
+    #[test]
+    fn both_vault_formats_round_trip_and_msgpack_is_compact() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, mut vault) = saved_vault(dir.path());
+        vault.notes.push(seal_note(&new_note_id(), "two\nlines ✓", &meta("second"), Cipher::ChaCha20Poly1305, &test_key()).unwrap());
+        let mut sizes = Vec::new();
+        for format in [VaultFormat::Json, VaultFormat::Msgpack] {
+            vault.format = format;
+            save_notes(&path, &vault, &test_key()).unwrap();
+            let bytes = std::fs::read(&path).unwrap();
+            assert_eq!(std::str::from_utf8(&bytes).is_ok(), format == VaultFormat::Json);
+            sizes.push(bytes.len());
+
+            let loaded = load_notes(&path).unwrap();
+            assert_eq!(loaded.format, format);
+            assert_eq!(contents(&loaded), contents(&vault));
+            assert_eq!(all_nonces(&loaded), all_nonces(&vault));
+        }
+        assert!(sizes[1] < sizes[0], "msgpack {} bytes, json {} bytes", sizes[1], sizes[0]);
+    }
+
+
+    #[test]
+    fn appending_twice_keeps_the_entries_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, mut vault) = saved_vault(dir.path());
+        run_on(&mut vault, &path, &["edit", "first", "--append", "monday: ran"]).unwrap();
+        run_on(&mut vault, &path, &["edit", "first", "--append", "tuesday: rested"]).unwrap();
+        let expected = ("first".to_string(), "hello\nmonday: ran\ntuesday: rested".to_string());
+        assert_eq!(contents(&load_notes(&path).unwrap()), [expected]);
+    }
+
+
+    #[test]
+    fn purge_only_goes_ahead_once_delete_is_typed() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, mut vault) = saved_vault(dir.path());
+        answering(&["delete"], || run_on(&mut vault, &path, &["purge"])).unwrap(); // Cancelled
+        assert!(path.exists());
+        answering(&["DELETE"], || run_on(&mut vault, &path, &["purge"])).unwrap();
+        assert!(!path.exists());
+    }
+
+
+    #[test]
+    fn notes_over_the_size_limit_are_refused_and_at_it_accepted() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, mut vault) = saved_vault(dir.path());
+        run_on(&mut vault, &path, &["--max-note-size", "5", "new", "fits", "12345"]).unwrap();
+        let over = run_on(&mut vault, &path, &["--max-note-size", "5", "new", "too big", "123456"]);
+        assert!(matches!(over, Err(VaultError::NoteTooLarge(title, 6, 5)) if title == "too big"));
+        let edit = run_on(&mut vault, &path, &["--max-note-size", "5", "edit", "fits", "--append", "6"]);
+        assert!(matches!(edit, Err(VaultError::NoteTooLarge(_, 7, 5))));
+
+        // The config file's limit applies when the flag isn't given
+        std::fs::write(dir.path().join(CONFIG_FILE), "max_note_size = 3\n").unwrap();
+        assert!(matches!(run_on(&mut vault, &path, &["new", "four", "1234"]), Err(VaultError::NoteTooLarge(_, 4, 3))));
+        run_on(&mut vault, &path, &["new", "three", "123"]).unwrap();
+        assert_eq!(contents(&load_notes(&path).unwrap()).len(), 3);
+    }
+
+
+    #[test]
+    fn recover_brings_a_note_back_from_a_backup() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, mut vault) = saved_vault(dir.path());
+        let backup = dir.path().join("backup.json");
+        std::fs::copy(&path, &backup).unwrap();
+        let backup = backup.to_str().unwrap();
+        vault.notes.clear();
+        save_notes(&path, &vault, &test_key()).unwrap();
+
+        // The backup has this vault's key, so no password is asked for
+        answering(&[], || run_on(&mut vault, &path, &["recover", "first", "--from", backup])).unwrap();
+        assert_eq!(contents(&load_notes(&path).unwrap()), [("first".to_string(), "hello".to_string())]);
+        assert!(matches!(run_on(&mut vault, &path, &["recover", "first", "--from", backup]), Err(VaultError::NoteExists(_))));
+        run_on(&mut vault, &path, &["recover", "first", "--from", backup, "--force"]).unwrap();
+        assert_eq!(vault.notes.len(), 1);
+
+        // A backup under another password is opened with that one, and re-encrypted under this key
+        let (other, _, _) = password_vault(dir.path(), "other", "old password", "from the old vault");
+        let other = other.to_str().unwrap();
+        answering(&["old password"], || run_on(&mut vault, &path, &["recover", "first", "--from", other, "--force"])).unwrap();
+        assert_eq!(contents(&load_notes(&path).unwrap()), [("first".to_string(), "from the old vault".to_string())]);
+    }
+
+
+    #[test]
+    fn sensitive_notes_ask_for_the_password_again_and_others_do_not() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, mut vault, key) = password_vault(dir.path(), "vault", "pw", "ordinary");
+        let read = |vault: &mut Vault, title: &str| {
+            let args = args(&["read", title]);
+            run_command(args.command.clone(), vault, &key, &path, &args)
+        };
+        let sensitive = NoteMeta { sensitive: true, ..meta("bank") };
+        vault.notes.push(seal_note(&new_note_id(), "pin 1234", &sensitive, Cipher::Aes256Gcm, &key).unwrap());
+
+        answering(&[], || read(&mut vault, "first")).unwrap();
+        answering(&["pw"], || read(&mut vault, "bank")).unwrap();
+        assert!(matches!(answering(&["wrong"], || read(&mut vault, "bank")), Err(VaultError::WrongPassword)));
+        // Without a terminal to ask on, a sensitive note isn't shown at all
+        assert!(matches!(answering(&[], || read(&mut vault, "bank")), Err(VaultError::NoTerminal)));
+    }
+
+
+    #[test]
+    fn totp_codes_match_the_rfc_6238_vectors() {
+        // The RFC's SHA-1 secret, "12345678901234567890", as a setup page would show it
+        let secret = totp_secret("gh", "gezd gnbv gy3t qojq gezd gnbv gy3t qojq").unwrap();
+        assert_eq!(secret, "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ");
+        // The RFC lists 8 digits; a 6-digit code is their last six
+        for (time, code) in [(59, "287082"), (1111111109, "081804"), (1234567890, "005924"), (20000000000, "353130")] {
+            assert_eq!(totp_code(&secret, time).as_deref(), Some(code), "at {time}");
+        }
+        assert!(matches!(totp_secret("gh", "not base32!"), Err(VaultError::InvalidTotpSecret(_))));
+    }
+
+
+    #[test]
+    fn links_are_found_once_each_in_order() {
+        let content = "See [[ Wifi ]] and [[Bank]], then [[Wifi]] again.\n[[]] [[not\nclosed]] [[[Nested]]]";
+        assert_eq!(note_links(content), ["Wifi", "Bank", "Nested"]);
+        assert!(note_links("no links, just [brackets]").is_empty());
+    }
+
+
+    #[test]
+    fn composed_and_decomposed_titles_are_the_same_note() {
+        let (composed, decomposed) = ("Caf\u{e9}", "Cafe\u{301}");
+        assert_ne!(composed.as_bytes(), decomposed.as_bytes());
+        let dir = tempfile::tempdir().unwrap();
+        let (path, mut vault) = saved_vault(dir.path());
+        run_on(&mut vault, &path, &["new", decomposed, "menu"]).unwrap();
+        // Stored in NFC, whichever form was typed
+        assert_eq!(contents(&vault)[1], (composed.to_string(), "menu".to_string()));
+
+        assert!(matches!(run_on(&mut vault, &path, &["new", composed, "again"]), Err(VaultError::NoteExists(_))));
+        run_on(&mut vault, &path, &["read", composed]).unwrap();
+        run_on(&mut vault, &path, &["edit", composed, "new menu"]).unwrap();
+        run_on(&mut vault, &path, &["rename", decomposed, "Bistro"]).unwrap();
+        assert_eq!(contents(&vault)[1], ("Bistro".to_string(), "new menu".to_string()));
+        run_on(&mut vault, &path, &["rename", "Bistro", decomposed]).unwrap();
+        run_on(&mut vault, &path, &["delete", composed, "--yes"]).unwrap();
+        assert!(trashed(&vault, decomposed));
+    }
+
+
+    #[test]
+    fn watch_re_reads_the_vault_when_it_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, mut vault) = saved_vault(dir.path());
+        let watched = vault.notes[0].clone();
+        let watcher = {
+            let path = path.clone();
+            std::thread::spawn(move || watch_note(&path, &test_key(), watched, true, false))
+        };
+        let settle = std::time::Duration::from_millis(WATCH_SETTLE_MS * 3);
+        std::thread::sleep(settle);
+
+        // An edit is shown, and watching goes on
+        run_on(&mut vault, &path, &["edit", "first", "changed"]).unwrap();
+        std::thread::sleep(settle);
+        assert!(!watcher.is_finished());
+        // Deleting the note for good is seen on the next change, and ends the watch
+        vault.notes.clear();
+        save_notes(&path, &vault, &test_key()).unwrap();
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(WATCH_POLL_SECS * 10);
+        while !watcher.is_finished() && std::time::Instant::now() < deadline {
+            std::thread::sleep(std::time::Duration::from_millis(50));
+        }
+        assert!(watcher.is_finished(), "the deletion was never noticed");
+        watcher.join().unwrap().unwrap();
     }
 }
@@ -0,0 +1,996 @@
+//! End-to-end tests that run the built `vault` binary, for what only shows in its output and
+//! exit code. Everything else is tested next to the code in `src/main.rs`.
+
+use assert_cmd::Command;
+use assert_cmd::cargo::cargo_bin_cmd;
+use std::path::{Path, PathBuf};
+
+/// Master password of every sandbox vault
+const PASSWORD: &str = "tests only";
+
+/// A temporary directory holding a vault, its password file and a `vault.toml` with cheap
+/// Argon2 costs. Commands run with the directory as home, config and runtime directory, so
+/// nothing outside it (a real profile, config or running agent) is ever touched.
+struct Sandbox {
+    dir: tempfile::TempDir,
+}
+
+impl Sandbox {
+    /// A sandbox with an empty vault
+    fn new() -> Self {
+        let sandbox = Sandbox::uninitialized();
+        sandbox.run(&["--allow-weak", "init"]);
+        sandbox
+    }
+
+    /// A sandbox whose vault doesn't exist yet
+    fn uninitialized() -> Self {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("vault.toml"), "[argon2]\nmemory_kib = 64\niterations = 1\nparallelism = 1\n").unwrap();
+        write_private(&dir.path().join("password"), &format!("{PASSWORD}\n"));
+        Sandbox { dir }
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.dir.path().join(name)
+    }
+
+    fn vault(&self) -> PathBuf {
+        self.path("vault.json")
+    }
+
+    /// `vault` with nothing but `args`, in the sandbox
+    fn bare(&self, args: &[&str]) -> Command {
+        let mut command = cargo_bin_cmd!("vault-cli");
+        command
+            .current_dir(self.dir.path())
+            .env("HOME", self.dir.path())
+            .env("XDG_CONFIG_HOME", self.dir.path())
+            .env("XDG_RUNTIME_DIR", self.dir.path())
+            .env_remove("VAULT_FILE")
+            .env_remove("VAULT_NO_EMOJI")
+            .env_remove("EDITOR")
+            .env_remove("VISUAL")
+            .args(args);
+        command
+    }
+
+    /// `vault` on the sandbox vault with its password file, then `args`
+    fn command(&self, args: &[&str]) -> Command {
+        let vault = self.vault();
+        let password = self.path("password");
+        let mut command = self.bare(&["--vault", vault.to_str().unwrap(), "--password-file", password.to_str().unwrap()]);
+        command.args(args);
+        command
+    }
+
+    /// Run `args` on the sandbox vault, which must succeed, and return what it printed
+    fn run(&self, args: &[&str]) -> String {
+        let output = self.command(args).assert().success().get_output().stdout.clone();
+        String::from_utf8(output).unwrap()
+    }
+}
+
+/// Write `contents` to a new owner-only file, as a password file should be
+fn write_private(path: &Path, contents: &str) {
+    std::fs::write(path, contents).unwrap();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600)).unwrap();
+    }
+}
+
+#[test]
+fn search_finds_the_notes_containing_the_query() {
+    let sandbox = Sandbox::new();
+    sandbox.run(&["new", "Groceries", "apples\npears"]);
+    sandbox.run(&["new", "Recipes", "Apple pie: flour, butter"]);
+    sandbox.run(&["new", "Wifi", "hunter2"]);
+
+    let found = sandbox.run(&["search", "apple"]);
+    assert!(found.contains("Groceries") && found.contains("Recipes"));
+    assert!(!found.contains("Wifi"));
+    let found = sandbox.run(&["search", "--case-sensitive", "Apple"]);
+    assert!(found.contains("Recipes") && !found.contains("Groceries"));
+}
+
+#[test]
+fn list_json_describes_each_note() {
+    let sandbox = Sandbox::new();
+    sandbox.run(&["new", "Wifi", "hunter2", "--tag", "home"]);
+    sandbox.run(&["new", "Bank", "1234"]);
+
+    let listed: serde_json::Value = serde_json::from_str(&sandbox.run(&["list", "--json"])).unwrap();
+    let notes = listed.as_array().unwrap();
+    assert_eq!(notes.len(), 2);
+    let wifi = &notes[0];
+    let mut keys: Vec<&str> = wifi.as_object().unwrap().keys().map(String::as_str).collect();
+    keys.sort();
+    assert_eq!(keys, ["archived", "created_at", "id", "pinned", "size", "tags", "title", "totp", "trashed", "updated_at"]);
+    assert_eq!(wifi["title"], "Wifi");
+    assert_eq!(wifi["tags"], serde_json::json!(["home"]));
+    assert_eq!(wifi["size"], 7);
+    assert_eq!((wifi["trashed"].as_bool(), wifi["pinned"].as_bool()), (Some(false), Some(false)));
+    assert_eq!(notes[1]["title"], "Bank");
+    assert_ne!(notes[0]["id"], notes[1]["id"]);
+}
+
+#[test]
+fn content_from_stdin_is_stored_exactly() {
+    let sandbox = Sandbox::new();
+    let content = "first line\n  indented\n\n";
+    sandbox.command(&["new", "Piped", "-"]).write_stdin(content).assert().success();
+    assert_eq!(sandbox.run(&["read", "Piped", "--raw"]), content);
+
+    // With --password-stdin the password takes the first line and the content the rest
+    let vault = sandbox.vault();
+    let mut piped = sandbox.bare(&["--vault", vault.to_str().unwrap(), "--password-stdin", "edit", "Piped", "-"]);
+    piped.write_stdin(format!("{PASSWORD}\nreplaced\n\n")).assert().success();
+    assert_eq!(sandbox.run(&["read", "Piped", "--raw"]), "replaced\n\n");
+}
+
+/// An `$EDITOR` that appends `line` to the file it is given, then exits
+#[cfg(unix)]
+fn fake_editor(sandbox: &Sandbox, line: &str) -> PathBuf {
+    use std::os::unix::fs::PermissionsExt;
+    let script = sandbox.path(&format!("editor-{}", line.len()));
+    std::fs::write(&script, format!("#!/bin/sh\nprintf '%s\\n' '{line}' >> \"$1\"\n")).unwrap();
+    std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+    script
+}
+
+#[cfg(unix)]
+#[test]
+fn editor_output_becomes_the_content() {
+    let sandbox = Sandbox::new();
+    let editor = fake_editor(&sandbox, "written in the editor");
+    sandbox.command(&["new", "Composed"]).env("EDITOR", &editor).assert().success();
+    assert_eq!(sandbox.run(&["read", "Composed", "--raw"]), "written in the editor\n");
+
+    // `edit --editor` starts from the current content
+    let editor = fake_editor(&sandbox, "and more");
+    sandbox.command(&["edit", "Composed", "--editor"]).env("EDITOR", &editor).assert().success();
+    assert_eq!(sandbox.run(&["read", "Composed", "--raw"]), "written in the editor\nand more\n");
+}
+
+/// The value `vault stats` prints after `label`, e.g. `stat(&output, "Total notes:")` for `3`
+fn stat<'a>(output: &'a str, label: &str) -> &'a str {
+    output.lines().find_map(|line| line.trim().strip_prefix(label)).unwrap_or_else(|| panic!("no '{label}' in:\n{output}")).trim()
+}
+
+#[test]
+fn stats_counts_a_known_vault() {
+    let sandbox = Sandbox::new();
+    for (title, content) in [("One", "1"), ("Two", "22"), ("Three", "333")] {
+        sandbox.run(&["new", title, content]);
+    }
+    sandbox.run(&["delete", "Two", "--yes"]);
+
+    let output = sandbox.run(&["stats"]);
+    assert_eq!(stat(&output, "Total notes:"), "3");
+    assert_eq!(stat(&output, "Decryptable:"), "3");
+    assert_eq!(stat(&output, "In trash:"), "1");
+    assert_eq!(stat(&output, "Locked/other key:"), "0");
+    assert!(stat(&output, "Oldest note:") <= stat(&output, "Newest note:"));
+}
+
+#[test]
+fn bash_completions_name_the_subcommands() {
+    let sandbox = Sandbox::uninitialized();
+    let output = sandbox.bare(&["completions", "bash"]).assert().success().get_output().stdout.clone();
+    let script = String::from_utf8(output).unwrap();
+    assert!(!script.is_empty());
+    for name in ["init", "new", "list", "read", "edit", "delete", "change-password", "gen-password"] {
+        assert!(script.contains(name), "no '{name}' in the completions");
+    }
+    assert!(!sandbox.vault().exists());
+}
+
+#[test]
+fn raw_and_out_give_back_the_exact_content() {
+    let sandbox = Sandbox::new();
+    let content = "tab\there · ünïcode\r\nno newline at the end";
+    sandbox.command(&["new", "Exact", "-"]).write_stdin(content).assert().success();
+
+    let raw = sandbox.command(&["read", "Exact", "--raw"]).assert().success().get_output().stdout.clone();
+    assert_eq!(raw, content.as_bytes());
+    let out = sandbox.path("exact.txt");
+    sandbox.run(&["read", "Exact", "--out", out.to_str().unwrap()]);
+    assert_eq!(std::fs::read(&out).unwrap(), content.as_bytes());
+}
+
+/// Titles in the `list --json` output for `args`, in order
+fn listed(sandbox: &Sandbox, args: &[&str]) -> Vec<String> {
+    let json: serde_json::Value = serde_json::from_str(&sandbox.run(&[&["list", "--json"], args].concat())).unwrap();
+    json.as_array().unwrap().iter().map(|note| note["title"].as_str().unwrap().to_string()).collect()
+}
+
+#[test]
+fn tags_are_stored_and_filter_the_list() {
+    let sandbox = Sandbox::new();
+    sandbox.run(&["new", "Payslip", "£", "--tag", "work", "--tag", " money ", "--tag", "work"]);
+    sandbox.run(&["new", "Rent", "£", "--tag", "money"]);
+    sandbox.run(&["new", "Untagged", "-"]);
+
+    let json: serde_json::Value = serde_json::from_str(&sandbox.run(&["list", "--json", "--tag", "work"])).unwrap();
+    assert_eq!(json[0]["tags"], serde_json::json!(["work", "money"]));
+    assert_eq!(listed(&sandbox, &["--tag", "work"]), ["Payslip"]);
+    assert_eq!(listed(&sandbox, &["--tag", "money"]), ["Payslip", "Rent"]);
+    assert!(listed(&sandbox, &["--tag", "nothing"]).is_empty());
+    assert_eq!(listed(&sandbox, &[]).len(), 3);
+}
+
+#[test]
+fn quiet_new_prints_nothing() {
+    let sandbox = Sandbox::new();
+    assert!(!sandbox.run(&["new", "Loud", "content"]).is_empty());
+    let quiet = sandbox.command(&["--quiet", "new", "Quiet", "content"]).assert().success().get_output().clone();
+    assert!(quiet.stdout.is_empty() && quiet.stderr.is_empty());
+    assert_eq!(sandbox.run(&["-q", "read", "Quiet", "--raw"]), "content");
+    // --verbose adds details, like which file was opened
+    assert!(sandbox.run(&["--verbose", "list"]).contains("vault.json"));
+    assert!(!sandbox.run(&["list"]).contains("vault.json"));
+}
+
+#[test]
+fn password_comes_from_stdin_or_a_file() {
+    let sandbox = Sandbox::new();
+    sandbox.run(&["new", "Note", "content"]);
+    let vault = sandbox.vault();
+    let vault = vault.to_str().unwrap();
+
+    let read = |password: &str| sandbox.bare(&["--vault", vault, "--password-stdin", "read", "Note", "--raw"]).write_stdin(password).assert();
+    assert_eq!(read(&format!("{PASSWORD}\n")).success().get_output().stdout, b"content");
+    assert_eq!(read(&format!("{PASSWORD}\r\n")).success().get_output().stdout, b"content");
+    read("wrong\n").code(2);
+    read("").failure();
+
+    // Only the file's first line is the password
+    let file = sandbox.path("other-password");
+    write_private(&file, &format!("{PASSWORD}\nnot part of it\n"));
+    let output = sandbox.bare(&["--vault", vault, "--password-file", file.to_str().unwrap(), "read", "Note", "--raw"]).assert().success();
+    assert_eq!(output.get_output().stdout, b"content");
+    write_private(&file, "wrong\n");
+    sandbox.bare(&["--vault", vault, "--password-file", file.to_str().unwrap(), "list"]).assert().code(2);
+}
+
+/// A running `vault agent` in the sandbox, stopped when dropped
+#[cfg(unix)]
+struct Agent<'a>(&'a Sandbox);
+
+#[cfg(unix)]
+impl<'a> Agent<'a> {
+    fn start(sandbox: &'a Sandbox, ttl: u64) -> Self {
+        sandbox.bare(&["agent", "start", "--ttl", &ttl.to_string()]).assert().success();
+        Agent(sandbox)
+    }
+
+    /// Hand the agent the sandbox vault's key, as a command that was typed the password does
+    fn put_key(&self) {
+        use argon2::{Algorithm, Argon2, Params, Version};
+        use base64::{Engine as _, engine::general_purpose::STANDARD};
+        use std::io::{BufRead, Write};
+
+        let header: serde_json::Value = serde_json::from_slice(&std::fs::read(self.0.vault()).unwrap()).unwrap();
+        let salt = STANDARD.decode(header["salt"].as_str().unwrap()).unwrap();
+        let mut key = [0u8; 32];
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, Params::new(64, 1, 1, Some(32)).unwrap())
+            .hash_password_into(PASSWORD.as_bytes(), &salt, &mut key)
+            .unwrap();
+        let id = STANDARD.encode(self.0.vault().as_os_str().as_encoded_bytes());
+        let mut socket = std::os::unix::net::UnixStream::connect(self.0.path("vault/agent.sock")).unwrap();
+        writeln!(socket, "PUT {id} {}", STANDARD.encode(key)).unwrap();
+        let mut reply = String::new();
+        std::io::BufReader::new(socket).read_line(&mut reply).unwrap();
+        assert_eq!(reply, "OK\n");
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Agent<'_> {
+    fn drop(&mut self) {
+        let _ = self.0.bare(&["agent", "stop"]).output();
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn agent_serves_keys_until_they_expire() {
+    let sandbox = Sandbox::new();
+    sandbox.run(&["new", "Note", "content"]);
+    let vault = sandbox.vault();
+    let without_password = || sandbox.bare(&["--vault", vault.to_str().unwrap(), "read", "Note", "--raw"]).assert();
+
+    // No agent: the password is asked for, which can't work without a terminal
+    let failed = without_password().failure().get_output().stderr.clone();
+    assert!(String::from_utf8(failed).unwrap().contains("terminal"));
+
+    let agent = Agent::start(&sandbox, 2);
+    without_password().failure();
+    agent.put_key();
+    assert_eq!(without_password().success().get_output().stdout, b"content");
+
+    // Once the key's time is up, it's back to asking
+    std::thread::sleep(std::time::Duration::from_millis(2500));
+    without_password().failure();
+    assert_eq!(sandbox.run(&["read", "Note", "--raw"]), "content");
+}
+
+#[test]
+fn expired_notes_are_pruned_and_others_survive() {
+    let sandbox = Sandbox::new();
+    sandbox.run(&["new", "Short", "gone soon", "--expires-in", "1s"]);
+    sandbox.run(&["new", "Long", "still here", "--expires-in", "1d"]);
+    sandbox.run(&["new", "Forever", "no expiry"]);
+    std::thread::sleep(std::time::Duration::from_secs(2));
+
+    assert_eq!(stat(&sandbox.run(&["--no-prune", "stats"]), "Total notes:"), "3");
+    assert!(sandbox.run(&["stats"]).contains("Pruned 1 expired note(s)"));
+    assert_eq!(stat(&sandbox.run(&["--no-prune", "stats"]), "Total notes:"), "2");
+    assert_eq!(listed(&sandbox, &[]), ["Long", "Forever"]);
+    assert_eq!(sandbox.run(&["read", "Long", "--raw"]), "still here");
+}
+
+#[test]
+fn piped_list_is_uncolored_and_stable() {
+    let sandbox = Sandbox::new();
+    sandbox.run(&["new", "Wifi", "hunter2", "--tag", "home"]);
+    sandbox.run(&["new", "A much longer title", "x"]);
+
+    let table = sandbox.run(&["list", "--fields", "title,tags"]);
+    assert!(!table.contains('\x1b'), "escape codes in piped output:\n{table}");
+    assert_eq!(sandbox.run(&["list", "--fields", "title,tags"]), table);
+    let rows: Vec<&str> = table.lines().collect();
+    assert!(rows.iter().any(|row| row.starts_with("Wifi") && row.ends_with(" 1")), "{table}");
+    // Every row is padded to the same width, so the columns line up
+    assert!(rows.iter().all(|row| row.chars().count() == rows[0].chars().count()), "{table}");
+    assert_eq!(sandbox.command(&["list"]).env("NO_COLOR", "1").assert().success().get_output().stdout, sandbox.run(&["list"]).as_bytes());
+}
+
+#[test]
+fn dry_run_delete_leaves_the_vault_untouched() {
+    let sandbox = Sandbox::new();
+    sandbox.run(&["new", "Keep", "content"]);
+    let before = std::fs::read(sandbox.vault()).unwrap();
+
+    let output = sandbox.run(&["--dry-run", "delete", "Keep"]);
+    assert!(output.starts_with("[dry-run] "), "{output}");
+    assert_eq!(std::fs::read(sandbox.vault()).unwrap(), before);
+    assert_eq!(listed(&sandbox, &[]), ["Keep"]);
+}
+
+#[test]
+fn weak_master_passwords_are_warned_about_on_creation() {
+    let init = |password: &str, flags: &[&str]| {
+        let sandbox = Sandbox::uninitialized();
+        write_private(&sandbox.path("password"), &format!("{password}\n"));
+        let output = sandbox.command(&[flags, &["init"]].concat()).assert().success().get_output().clone();
+        let warned = String::from_utf8(output.stderr).unwrap().contains("master password is weak");
+        // Only creating the vault checks, never opening it
+        let reopened = sandbox.command(&["list"]).assert().success().get_output().stderr.clone();
+        assert!(!String::from_utf8(reopened).unwrap().contains("weak"));
+        warned
+    };
+    assert!(init("password1", &[]));
+    assert!(!init("correct horse battery staple ferric", &[]));
+    assert!(!init("password1", &["--allow-weak"]));
+}
+
+/// A sandbox with notes whose title, creation date and size orders all differ:
+/// "banana" (2021, 100 bytes), "Apple" (2023, 10 bytes) and "cherry" (2022, 1 byte)
+fn fruit_sandbox() -> Sandbox {
+    let sandbox = Sandbox::new();
+    let notes = serde_json::json!([
+        { "title": "banana", "content": "b".repeat(100), "created_at": "2021-01-01T00:00:00Z" },
+        { "title": "Apple", "content": "a".repeat(10), "created_at": "2023-01-01T00:00:00Z" },
+        { "title": "cherry", "content": "c", "created_at": "2022-01-01T00:00:00Z" },
+    ]);
+    std::fs::write(sandbox.path("fruit.json"), notes.to_string()).unwrap();
+    sandbox.run(&["import", sandbox.path("fruit.json").to_str().unwrap()]);
+    sandbox
+}
+
+#[test]
+fn list_sorts_by_each_key() {
+    let sandbox = fruit_sandbox();
+    assert_eq!(listed(&sandbox, &[]), ["banana", "Apple", "cherry"]);
+    assert_eq!(listed(&sandbox, &["--sort", "title"]), ["Apple", "banana", "cherry"]);
+    assert_eq!(listed(&sandbox, &["--sort", "created"]), ["banana", "cherry", "Apple"]);
+    assert_eq!(listed(&sandbox, &["--sort", "size"]), ["cherry", "Apple", "banana"]);
+    assert_eq!(listed(&sandbox, &["--sort", "size", "--reverse"]), ["banana", "Apple", "cherry"]);
+
+    // Timestamps have whole seconds, so wait for the edit to be the latest change
+    std::thread::sleep(std::time::Duration::from_secs(1));
+    sandbox.command(&["edit", "Apple", "-"]).write_stdin("changed").assert().success();
+    assert_eq!(listed(&sandbox, &["--sort", "updated"]), ["banana", "cherry", "Apple"]);
+    assert_eq!(listed(&sandbox, &["--sort", "updated", "--reverse"]), ["Apple", "cherry", "banana"]);
+}
+
+#[test]
+fn limit_and_offset_page_through_the_sorted_list() {
+    let sandbox = fruit_sandbox();
+    let page = |flags: &[&str]| listed(&sandbox, &[&["--sort", "title"], flags].concat());
+    assert_eq!(page(&["--limit", "2"]), ["Apple", "banana"]);
+    assert_eq!(page(&["--limit", "2", "--offset", "2"]), ["cherry"]);
+    assert_eq!(page(&["--offset", "1"]), ["banana", "cherry"]);
+    assert!(page(&["--offset", "5"]).is_empty());
+
+    let plain = sandbox.run(&["list", "--plain", "--sort", "title", "--limit", "1"]);
+    assert!(plain.ends_with("… 2 more (use --offset 1)\n"), "{plain}");
+    assert!(!sandbox.run(&["list", "--plain", "--limit", "3"]).contains("more"));
+}
+
+#[test]
+fn regex_search_matches_some_notes_and_rejects_bad_patterns() {
+    let sandbox = Sandbox::new();
+    sandbox.run(&["new", "Server", "ssh root@10.0.0.1"]);
+    sandbox.run(&["new", "Office", "printer at 192.168.1.20"]);
+    sandbox.run(&["new", "Wifi", "hunter2"]);
+
+    let found = sandbox.run(&["search", "--regex", r"\d+\.\d+\.\d+\.\d+"]);
+    assert!(found.contains("Server") && found.contains("Office") && !found.contains("Wifi"), "{found}");
+    assert!(found.contains("↳ printer at 192.168.1.20"), "{found}");
+    assert!(!sandbox.run(&["search", "--regex", "^SSH"]).contains("Server"));
+    assert!(sandbox.run(&["search", "--regex", "--ignore-case", "^SSH"]).contains("Server"));
+
+    let invalid = sandbox.command(&["search", "--regex", "(unclosed"]).assert().code(1).get_output().stderr.clone();
+    assert!(String::from_utf8(invalid).unwrap().contains("Invalid regular expression"));
+}
+
+#[test]
+fn info_shows_metadata_but_never_the_content() {
+    let sandbox = Sandbox::new();
+    sandbox.run(&["new", "Bank", "pin 8142-secret", "--tag", "money"]);
+
+    let info = sandbox.run(&["info", "Bank"]);
+    assert!(!info.contains("8142") && !info.contains("secret"), "{info}");
+    assert!(info.contains("Title: Bank") && info.contains("Tags: money"), "{info}");
+    assert!(info.contains("(15 bytes)"), "{info}");
+}
+
+#[test]
+fn binary_attachments_round_trip_losslessly() {
+    let sandbox = Sandbox::new();
+    let blob: Vec<u8> = (0..=255u8).chain([0xff, 0xfe, 0x00, 0xc3]).collect();
+    assert!(String::from_utf8(blob.clone()).is_err());
+    std::fs::write(sandbox.path("key.der"), &blob).unwrap();
+    sandbox.run(&["new", "Cert", "--from-file", sandbox.path("key.der").to_str().unwrap()]);
+
+    let out = sandbox.path("restored.der");
+    sandbox.run(&["read", "Cert", "--out", out.to_str().unwrap()]);
+    assert_eq!(std::fs::read(&out).unwrap(), blob);
+    let raw = sandbox.command(&["read", "Cert", "--raw"]).assert().success().get_output().stdout.clone();
+    assert_eq!(raw, blob);
+}
+
+#[test]
+fn each_export_format_parses_back_to_the_same_notes() {
+    let sandbox = Sandbox::new();
+    let tricky = "line one\n  indented: \"quoted\" 'single' # not a comment\n[not a table]\n---\ttab & ünïcode\n";
+    sandbox.command(&["new", "Tricky: yes", "-"]).write_stdin(tricky).assert().success();
+    sandbox.run(&["new", "Plain", "simple"]);
+
+    for format in ["json", "yaml", "toml", "ndjson"] {
+        let path = sandbox.path(&format!("export.{format}"));
+        sandbox.run(&["export", path.to_str().unwrap(), "--format", format]);
+        let text = std::fs::read_to_string(&path).unwrap();
+        let notes: Vec<serde_json::Value> = match format {
+            "json" => serde_json::from_str(&text).unwrap(),
+            "yaml" => serde_yaml_ng::from_str(&text).unwrap(),
+            "toml" => {
+                let document: serde_json::Value = toml::from_str(&text).unwrap();
+                serde_json::from_value(document["notes"].clone()).unwrap()
+            }
+            _ => text.lines().map(|line| serde_json::from_str(line).unwrap()).collect(),
+        };
+        let pairs: Vec<(&str, &str)> = notes.iter().map(|note| (note["title"].as_str().unwrap(), note["content"].as_str().unwrap())).collect();
+        assert_eq!(pairs, [("Tricky: yes", tricky), ("Plain", "simple")], "{format}");
+        assert!(notes.iter().all(|note| note["created_at"].as_str().is_some_and(|date| !date.is_empty())), "{format}");
+    }
+}
+
+#[test]
+fn a_key_file_vault_needs_the_key_file_as_well() {
+    let sandbox = Sandbox::uninitialized();
+    std::fs::write(sandbox.path("key.bin"), b"second factor bytes").unwrap();
+    std::fs::write(sandbox.path("other.bin"), b"some other file").unwrap();
+    let key_file = sandbox.path("key.bin");
+    let with_key = |args: &[&str]| sandbox.command(&[&["--key-file", key_file.to_str().unwrap()], args].concat());
+    with_key(&["--allow-weak", "init"]).assert().success();
+    with_key(&["new", "Secret", "two factors"]).assert().success();
+
+    let missing = sandbox.command(&["read", "Secret", "--raw"]).assert().code(2).get_output().stderr.clone();
+    assert!(String::from_utf8(missing).unwrap().contains("needs its key file"));
+    let other = sandbox.path("other.bin");
+    let wrong = sandbox.command(&["--key-file", other.to_str().unwrap(), "read", "Secret"]).assert().code(2).get_output().stderr.clone();
+    assert!(String::from_utf8(wrong).unwrap().contains("Wrong password or key file"));
+    assert_eq!(with_key(&["read", "Secret", "--raw"]).assert().success().get_output().stdout, b"two factors");
+}
+
+#[test]
+fn list_reports_notes_under_another_key_with_show_locked() {
+    let sandbox = Sandbox::new();
+    sandbox.run(&["new", "Mine", "current password"]);
+    let other = Sandbox::uninitialized();
+    write_private(&other.path("password"), "a former password\n");
+    other.run(&["--allow-weak", "init"]);
+    other.run(&["new", "Old one", "x"]);
+    other.run(&["new", "Old two", "y"]);
+
+    // Splice the other vault's notes into this one, as a mixed-key vault would have them
+    let mut vault: serde_json::Value = serde_json::from_slice(&std::fs::read(sandbox.vault()).unwrap()).unwrap();
+    let foreign: serde_json::Value = serde_json::from_slice(&std::fs::read(other.vault()).unwrap()).unwrap();
+    vault["notes"].as_array_mut().unwrap().extend(foreign["notes"].as_array().unwrap().iter().cloned());
+    std::fs::write(sandbox.vault(), vault.to_string()).unwrap();
+
+    let output = sandbox.command(&["list", "--show-locked"]).assert().success().get_output().clone();
+    assert!(String::from_utf8(output.stdout).unwrap().contains("Mine"));
+    assert!(String::from_utf8(output.stderr).unwrap().contains("2 note(s) could not be decrypted with this password"));
+    let quiet = sandbox.command(&["list"]).assert().success().get_output().stderr.clone();
+    assert!(!String::from_utf8(quiet).unwrap().contains("could not be decrypted"));
+}
+
+#[test]
+fn prompted_multi_line_content_is_stored_exactly() {
+    let sandbox = Sandbox::new();
+    let typed = "first line\n\n  indented third\nlast, no newline";
+    let output = sandbox.command(&["new", "Typed", "--prompt"]).write_stdin(typed).assert().success().get_output().clone();
+    assert!(!String::from_utf8(output.stdout).unwrap().contains("first line"));
+    assert_eq!(sandbox.run(&["read", "Typed", "--raw"]), typed);
+
+    sandbox.command(&["edit", "Typed", "--prompt"]).write_stdin("retyped\nover two lines\n").assert().success();
+    assert_eq!(sandbox.run(&["read", "Typed", "--raw"]), "retyped\nover two lines\n");
+}
+
+#[test]
+fn failures_exit_with_their_class_code() {
+    let sandbox = Sandbox::new();
+    sandbox.run(&["new", "Present", "content"]);
+
+    sandbox.command(&["read", "Missing"]).assert().code(3);
+    write_private(&sandbox.path("wrong"), "not the password\n");
+    let wrong = sandbox.path("wrong");
+    let vault = sandbox.vault();
+    sandbox.bare(&["--vault", vault.to_str().unwrap(), "--password-file", wrong.to_str().unwrap(), "read", "Present"]).assert().code(2);
+    // Unknown flags are usage errors (1), not clap's usual 2, which here means a wrong password
+    sandbox.command(&["read", "Present", "--no-such-flag"]).assert().code(1);
+    sandbox.command(&["read", "Present"]).assert().code(0);
+}
+
+#[test]
+fn no_emoji_output_is_plain_ascii() {
+    let sandbox = Sandbox::new();
+    let commands: [&[&str]; 7] = [
+        &["new", "Note", "content", "--tag", "work"],
+        &["list"],
+        &["list", "--plain"],
+        &["read", "Note"],
+        &["info", "Note"],
+        &["stats"],
+        &["delete", "Note", "--yes"],
+    ];
+    for command in commands {
+        let output = sandbox.command(&[&["--no-emoji"], command].concat()).assert().success().get_output().clone();
+        let printed = [output.stdout, output.stderr].concat();
+        assert!(printed.is_ascii(), "{command:?}:\n{}", String::from_utf8_lossy(&printed));
+    }
+    let from_env = sandbox.command(&["list", "--plain", "--include-trashed"]).env("VAULT_NO_EMOJI", "1").assert().success().get_output().stdout.clone();
+    assert!(from_env.is_ascii() && !from_env.is_empty());
+}
+
+/// Titles printed by `find` with `args`
+fn found(sandbox: &Sandbox, args: &[&str]) -> Vec<String> {
+    let output = sandbox.run(&[&["find"], args].concat());
+    output.lines().filter_map(|line| line.strip_prefix("📌 ")).map(str::to_string).collect()
+}
+
+#[test]
+fn find_ands_tag_content_and_date_predicates() {
+    let sandbox = Sandbox::new();
+    let old = serde_json::json!([{ "title": "Old payslip", "content": "salary for 2021", "created_at": "2021-06-01T00:00:00Z" }]);
+    std::fs::write(sandbox.path("old.json"), old.to_string()).unwrap();
+    sandbox.run(&["import", sandbox.path("old.json").to_str().unwrap()]);
+    sandbox.run(&["edit", "Old payslip", "salary for 2021", "--tag", "work"]);
+    sandbox.run(&["new", "Payslip", "salary for this month", "--tag", "work"]);
+    sandbox.run(&["new", "Agenda", "no money talk", "--tag", "work"]);
+    sandbox.run(&["new", "Daydream", "a bigger salary"]);
+
+    assert_eq!(found(&sandbox, &["--tag", "work", "--contains", "SALARY"]), ["Old payslip", "Payslip"]);
+    assert_eq!(found(&sandbox, &["--tag", "work", "--contains", "salary", "--after", "2024-01-01"]), ["Payslip"]);
+    assert_eq!(found(&sandbox, &["--contains", "salary", "--before", "2024-01-01"]), ["Old payslip"]);
+    assert_eq!(found(&sandbox, &["--contains", "salary"]).len(), 3);
+    assert!(found(&sandbox, &["--tag", "home", "--contains", "salary"]).is_empty());
+}
+
+#[test]
+fn a_writer_fails_while_another_process_holds_the_lock() {
+    let sandbox = Sandbox::new();
+    let lock = std::fs::OpenOptions::new().write(true).create(true).truncate(true).open(sandbox.path("vault.json.lock")).unwrap();
+    lock.try_lock().unwrap();
+    std::fs::write(sandbox.path("vault.json.lock"), std::process::id().to_string()).unwrap();
+
+    // Writers wait for a while, then give up without touching the vault; readers don't wait
+    let before = std::fs::read(sandbox.vault()).unwrap();
+    let blocked = sandbox.command(&["new", "Blocked", "content"]).assert().code(1).get_output().stderr.clone();
+    let blocked = String::from_utf8(blocked).unwrap();
+    assert!(blocked.contains("Waiting for another process"), "{blocked}");
+    assert!(blocked.contains(&format!("Vault is locked by another process (PID {})", std::process::id())), "{blocked}");
+    assert_eq!(std::fs::read(sandbox.vault()).unwrap(), before);
+    sandbox.run(&["list"]);
+
+    // Once released, the lock file left behind is simply taken over
+    drop(lock);
+    sandbox.run(&["new", "Unblocked", "content"]);
+    assert_eq!(listed(&sandbox, &[]), ["Unblocked"]);
+}
+
+#[test]
+fn json_errors_describe_a_missing_note() {
+    let sandbox = Sandbox::new();
+    let output = sandbox.command(&["--json-errors", "read", "Nowhere"]).assert().code(3).get_output().clone();
+    assert!(output.stdout.is_empty());
+    let error: serde_json::Value = serde_json::from_slice(&output.stderr).unwrap();
+    assert_eq!(
+        error,
+        serde_json::json!({ "error": "note_not_found", "title": "Nowhere", "message": "Note 'Nowhere' not found.", "exit_code": 3 })
+    );
+    // Usage errors are reported the same way, though the flag itself was never parsed
+    let usage = sandbox.command(&["--json-errors", "read"]).assert().code(1).get_output().stderr.clone();
+    let usage: serde_json::Value = serde_json::from_slice(&usage).unwrap();
+    assert_eq!((usage["error"].as_str(), usage["exit_code"].as_u64()), (Some("invalid_arguments"), Some(1)));
+}
+
+/// The Argon2 costs recorded in the sandbox vault's header
+fn header_costs(sandbox: &Sandbox) -> serde_json::Value {
+    let header: serde_json::Value = serde_json::from_slice(&std::fs::read(sandbox.vault()).unwrap()).unwrap();
+    header["argon2"].clone()
+}
+
+#[test]
+fn header_argon2_costs_win_over_the_config_on_open() {
+    let sandbox = Sandbox::new();
+    sandbox.run(&["new", "Note", "content"]);
+    let created = serde_json::json!({ "memory_kib": 64, "iterations": 1, "parallelism": 1 });
+    assert_eq!(header_costs(&sandbox), created);
+
+    // Other costs in the config would derive another key; the vault keeps opening with its own
+    std::fs::write(sandbox.path("vault.toml"), "[argon2]\nmemory_kib = 128\niterations = 2\nparallelism = 1\n").unwrap();
+    assert_eq!(sandbox.run(&["read", "Note", "--raw"]), "content");
+    sandbox.run(&["new", "Another", "content"]);
+    assert_eq!(header_costs(&sandbox), created);
+
+    // ... until a rekey adopts them
+    sandbox.run(&["rekey"]);
+    assert_eq!(header_costs(&sandbox), serde_json::json!({ "memory_kib": 128, "iterations": 2, "parallelism": 1 }));
+    assert_eq!(sandbox.run(&["read", "Note", "--raw"]), "content");
+}
+
+#[test]
+fn since_and_until_list_the_notes_in_the_window() {
+    let sandbox = fruit_sandbox();
+    assert_eq!(listed(&sandbox, &["--since", "2022-01-01"]), ["Apple", "cherry"]);
+    assert_eq!(listed(&sandbox, &["--until", "2022-06-30T00:00:00Z"]), ["banana", "cherry"]);
+    assert_eq!(listed(&sandbox, &["--since", "2021-06-01", "--until", "2022-06-01"]), ["cherry"]);
+    assert!(listed(&sandbox, &["--since", "7d"]).is_empty());
+    // All three were imported just now
+    assert_eq!(listed(&sandbox, &["--since", "7d", "--by-updated"]).len(), 3);
+
+    let invalid = sandbox.command(&["list", "--since", "last tuesday"]).assert().code(1).get_output().stderr.clone();
+    assert!(String::from_utf8(invalid).unwrap().contains("last tuesday"));
+}
+
+#[test]
+fn cat_prints_several_notes_in_one_run() {
+    let sandbox = Sandbox::new();
+    sandbox.run(&["new", "One", "first"]);
+    sandbox.run(&["new", "Two", "second\n"]);
+    sandbox.run(&["new", "Three", "third"]);
+
+    let output = sandbox.command(&["cat", "Three", "One", "Missing", "Two"]).assert().success().get_output().clone();
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "==> Three <==\nthird\n\n==> One <==\nfirst\n\n==> Two <==\nsecond\n");
+    assert!(String::from_utf8(output.stderr).unwrap().contains("Skipping 'Missing': note not found."));
+}
+
+#[test]
+fn pinned_notes_come_first_and_stay_pinned() {
+    let sandbox = fruit_sandbox();
+    sandbox.run(&["pin", "cherry"]);
+    // Each command is a separate run, so the pin was saved
+    assert_eq!(listed(&sandbox, &[]), ["cherry", "banana", "Apple"]);
+    assert_eq!(listed(&sandbox, &["--sort", "title"]), ["cherry", "Apple", "banana"]);
+    let plain = sandbox.run(&["list", "--plain"]);
+    assert!(plain.contains("⭐ cherry"), "{plain}");
+    let ascii = sandbox.run(&["--no-emoji", "list", "--plain"]);
+    assert!(ascii.is_ascii() && ascii.lines().nth(1).is_some_and(|line| line.ends_with(" cherry")), "{ascii}");
+
+    sandbox.run(&["unpin", "cherry"]);
+    assert_eq!(listed(&sandbox, &[]), ["banana", "Apple", "cherry"]);
+}
+
+#[test]
+fn a_deleted_notes_ciphertext_is_gone_from_the_new_file() {
+    let sandbox = Sandbox::new();
+    sandbox.run(&["new", "Kept", "stays"]);
+    sandbox.run(&["new", "Doomed", "goes away"]);
+    let header: serde_json::Value = serde_json::from_slice(&std::fs::read(sandbox.vault()).unwrap()).unwrap();
+    let doomed = header["notes"][1].clone();
+    let sealed = [&doomed["content"], &doomed["meta"], &doomed["nonce"]].map(|field| field.as_str().unwrap().to_string());
+
+    sandbox.run(&["delete", "Doomed", "--yes"]);
+    sandbox.run(&["empty-trash", "--yes"]);
+    let file = String::from_utf8(std::fs::read(sandbox.vault()).unwrap()).unwrap();
+    for sealed in &sealed {
+        assert!(!file.contains(sealed.as_str()), "{sealed} is still in the vault file");
+    }
+    assert_eq!(listed(&sandbox, &["--include-trashed"]), ["Kept"]);
+    // Nothing else is left behind next to the vault either
+    let leftovers: Vec<_> = std::fs::read_dir(sandbox.dir.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .filter(|name| name.starts_with("vault.json") && name != "vault.json" && name != "vault.json.lock")
+        .collect();
+    assert!(leftovers.is_empty(), "{leftovers:?}");
+}
+
+#[test]
+fn lock_makes_the_file_opaque_and_unlock_restores_it() {
+    let sandbox = Sandbox::new();
+    sandbox.run(&["new", "Secret", "content"]);
+    let original = std::fs::read(sandbox.vault()).unwrap();
+    let header: serde_json::Value = serde_json::from_slice(&original).unwrap();
+
+    sandbox.run(&["lock"]);
+    let locked = std::fs::read(sandbox.vault()).unwrap();
+    assert!(serde_json::from_slice::<serde_json::Value>(&locked).is_err());
+    // Not even the header's structure or its salt shows through
+    let visible = String::from_utf8_lossy(&locked);
+    for text in ["notes", "argon2", "salt", header["salt"].as_str().unwrap(), header["notes"][0]["content"].as_str().unwrap()] {
+        assert!(!visible.contains(text), "'{text}' is readable in the locked file");
+    }
+    // Locked, it still opens with the password
+    assert_eq!(sandbox.run(&["read", "Secret", "--raw"]), "content");
+
+    sandbox.run(&["unlock"]);
+    assert_eq!(std::fs::read(sandbox.vault()).unwrap(), original);
+}
+
+#[test]
+fn grep_title_lists_only_matching_titles() {
+    let sandbox = Sandbox::new();
+    for title in ["SSH key: work", "Home ssh", "Wifi", "Bank"] {
+        sandbox.run(&["new", title, "content mentioning ssh"]);
+    }
+    assert_eq!(listed(&sandbox, &["--grep-title", "ssh"]), ["SSH key: work", "Home ssh"]);
+    assert_eq!(listed(&sandbox, &["--grep-title", "WIFI"]), ["Wifi"]);
+    // Only titles are looked at, never the content
+    assert!(listed(&sandbox, &["--grep-title", "mentioning"]).is_empty());
+}
+
+#[test]
+fn init_creates_the_header_and_refuses_an_existing_vault() {
+    let sandbox = Sandbox::uninitialized();
+    let missing = sandbox.command(&["list"]).assert().code(4).get_output().stderr.clone();
+    assert!(String::from_utf8(missing).unwrap().contains("create one with `vault init` first"));
+    assert!(!sandbox.vault().exists());
+
+    sandbox.run(&["--allow-weak", "init"]);
+    let header: serde_json::Value = serde_json::from_slice(&std::fs::read(sandbox.vault()).unwrap()).unwrap();
+    assert_eq!((header["version"].as_u64(), header["kdf"].as_str()), (Some(1), Some("argon2id")));
+    assert!(!header["salt"].as_str().unwrap().is_empty() && !header["check"].as_str().unwrap().is_empty());
+    assert_eq!(header["notes"], serde_json::json!([]));
+
+    sandbox.run(&["new", "Note", "content"]);
+    let before = std::fs::read(sandbox.vault()).unwrap();
+    let refused = sandbox.command(&["--allow-weak", "init"]).assert().code(1).get_output().stderr.clone();
+    assert!(String::from_utf8(refused).unwrap().contains("pass --force to replace it"));
+    assert_eq!(std::fs::read(sandbox.vault()).unwrap(), before);
+    sandbox.run(&["--allow-weak", "init", "--force"]);
+    assert!(listed(&sandbox, &[]).is_empty());
+}
+
+#[test]
+fn repeated_vault_flags_list_both_vaults_together() {
+    let sandbox = Sandbox::new();
+    sandbox.run(&["new", "Home note", "a"]);
+    let (first, second, password) = (sandbox.vault(), sandbox.path("work.json"), sandbox.path("password"));
+    let (first, second, password) = (first.to_str().unwrap(), second.to_str().unwrap(), password.to_str().unwrap());
+    sandbox.bare(&["--vault", second, "--password-file", password, "--allow-weak", "init"]).assert().success();
+    sandbox.bare(&["--vault", second, "--password-file", password, "new", "Work note", "b"]).assert().success();
+
+    let both = ["--vault", first, "--vault", second, "--password-file", password];
+    let output = sandbox.bare(&[&both[..], &["list", "--json"]].concat()).assert().success().get_output().stdout.clone();
+    let json: serde_json::Value = serde_json::from_slice(&output).unwrap();
+    let notes: Vec<(&str, &str)> = json.as_array().unwrap().iter().map(|note| (note["title"].as_str().unwrap(), note["vault"].as_str().unwrap())).collect();
+    assert_eq!(notes, [("Home note", first), ("Work note", second)]);
+    let found = sandbox.bare(&[&both[..], &["search", "b"]].concat()).assert().success().get_output().stdout.clone();
+    assert!(String::from_utf8(found).unwrap().contains(&format!("Work note [{second}]")));
+
+    // Changes still go to exactly one vault
+    sandbox.bare(&[&both[..], &["new", "Where?", "c"]].concat()).assert().code(1);
+}
+
+#[test]
+fn bulk_operations_past_the_progress_threshold_finish_without_a_bar() {
+    let sandbox = Sandbox::new();
+    // More notes than PROGRESS_THRESHOLD (100), so a terminal would get a bar
+    let notes: Vec<serde_json::Value> = (0..150).map(|n| serde_json::json!({ "title": format!("Note {n}"), "content": n.to_string() })).collect();
+    std::fs::write(sandbox.path("many.json"), serde_json::Value::from(notes).to_string()).unwrap();
+    let import = sandbox.path("many.json");
+
+    let quiet = sandbox.command(&["--quiet", "import", import.to_str().unwrap()]).assert().success().get_output().clone();
+    assert!(quiet.stdout.is_empty() && quiet.stderr.is_empty());
+    // Piped stderr never gets a bar either, though messages still appear
+    let rekey = sandbox.command(&["rekey"]).assert().success().get_output().clone();
+    assert!(!String::from_utf8(rekey.stderr).unwrap().contains("/150 notes"));
+    assert!(!rekey.stdout.is_empty());
+    assert_eq!(listed(&sandbox, &[]).len(), 150);
+    assert_eq!(sandbox.run(&["read", "Note 149", "--raw"]), "149");
+}
+
+#[test]
+fn per_tag_stats_count_each_tag_by_frequency() {
+    let sandbox = Sandbox::new();
+    sandbox.run(&["new", "Payslip", "12345", "--tag", "money", "--tag", "work"]);
+    sandbox.run(&["new", "Rent", "1234567890", "--tag", "money"]);
+    sandbox.run(&["new", "Invoice", "123", "--tag", "money", "--tag", "work"]);
+    sandbox.run(&["new", "Wifi", "x"]);
+    sandbox.run(&["new", "Trip", "1234567", "--tag", "travel"]);
+
+    let output = sandbox.run(&["stats", "--per-tag"]);
+    let table: Vec<Vec<&str>> = output.lines().skip_while(|line| !line.starts_with("Tag")).map(|line| line.split_whitespace().collect()).collect();
+    assert_eq!(
+        table,
+        [
+            vec!["Tag", "Notes", "Size"],
+            vec!["money", "3", "18", "B"],
+            vec!["work", "2", "8", "B"],
+            vec!["travel", "1", "7", "B"],
+            vec!["(untagged)", "1", "1", "B"],
+        ]
+    );
+}
+
+#[test]
+fn purge_needs_the_typed_phrase_or_both_flags() {
+    let sandbox = Sandbox::new();
+    sandbox.run(&["new", "Note", "content"]);
+
+    // Without a terminal to type DELETE on, nothing is removed
+    let refused = sandbox.command(&["purge"]).assert().code(1).get_output().stderr.clone();
+    let refused = String::from_utf8(refused).unwrap();
+    assert!(refused.contains("--yes"), "{refused}");
+    sandbox.command(&["purge", "--yes"]).assert().code(1);
+    sandbox.command(&["purge", "--force"]).assert().code(1);
+    assert!(sandbox.vault().exists());
+
+    sandbox.run(&["purge", "--yes", "--force"]);
+    assert!(!sandbox.vault().exists());
+}
+
+#[test]
+fn read_stats_counts_like_wc() {
+    let sandbox = Sandbox::new();
+    // Counted as `wc` would: newlines, whitespace-separated words, and characters, not bytes
+    sandbox.command(&["new", "Poem", "-"]).write_stdin("one two\nthree\n\nfünf  six\n").assert().success();
+    assert!(sandbox.run(&["read", "Poem", "--stats"]).ends_with("4 lines, 5 words, 25 characters (26 bytes)\n"));
+
+    std::fs::write(sandbox.path("blob"), [0xffu8, 0, 1, 2, 3]).unwrap();
+    sandbox.run(&["new", "Blob", "--from-file", sandbox.path("blob").to_str().unwrap()]);
+    let binary = sandbox.run(&["read", "Blob", "--stats", "--out", sandbox.path("copy").to_str().unwrap()]);
+    assert!(binary.trim_end().ends_with("5 bytes") && !binary.contains("words"), "{binary}");
+}
+
+#[test]
+fn diff_shows_the_changed_lines_between_two_notes() {
+    let sandbox = Sandbox::new();
+    sandbox.command(&["new", "Prod", "-"]).write_stdin("host=db\nport=5432\nuser=app\n").assert().success();
+    sandbox.command(&["new", "Staging", "-"]).write_stdin("host=db\nport=6543\nuser=app\ndebug=true\n").assert().success();
+
+    let diff = sandbox.run(&["diff", "Prod", "Staging"]);
+    let lines: Vec<&str> = diff.lines().collect();
+    assert_eq!(lines[..2], ["--- Prod", "+++ Staging"]);
+    assert!(lines.contains(&"-port=5432") && lines.contains(&"+port=6543") && lines.contains(&"+debug=true"), "{diff}");
+    assert!(lines.contains(&" host=db") && !lines.iter().any(|line| line.ends_with("user=app") && !line.starts_with(' ')), "{diff}");
+}
+
+#[test]
+fn ndjson_export_writes_one_valid_object_per_note() {
+    let sandbox = Sandbox::new();
+    for n in 0..5 {
+        sandbox.run(&["new", &format!("Note {n}"), &format!("line one\nline {n}")]);
+    }
+    sandbox.run(&["delete", "Note 4", "--yes"]); // Trashed notes aren't exported
+    let path = sandbox.path("export.ndjson");
+    sandbox.run(&["export", path.to_str().unwrap(), "--ndjson"]);
+
+    let text = std::fs::read_to_string(&path).unwrap();
+    let titles: Vec<String> = text
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap()["title"].as_str().unwrap().to_string())
+        .collect();
+    assert_eq!(titles, ["Note 0", "Note 1", "Note 2", "Note 3"]);
+}
+
+#[test]
+fn self_test_passes_without_touching_the_vault() {
+    let sandbox = Sandbox::new();
+    sandbox.run(&["new", "Real", "content"]);
+    let before = std::fs::read(sandbox.vault()).unwrap();
+
+    let output = sandbox.bare(&["self-test"]).assert().success().get_output().stdout.clone();
+    let output = String::from_utf8(output).unwrap();
+    assert!(output.contains("Self-test passed"), "{output}");
+    assert!(output.lines().filter(|line| line.trim_start().starts_with("PASS")).count() >= 6, "{output}");
+    assert!(!output.contains("FAIL"), "{output}");
+    assert_eq!(std::fs::read(sandbox.vault()).unwrap(), before);
+}
+
+#[test]
+fn fields_choose_the_columns_and_their_order() {
+    let sandbox = fruit_sandbox();
+    sandbox.run(&["edit", "cherry", "c", "--tag", "red", "--tag", "small"]);
+
+    let table = sandbox.run(&["list", "--fields", "size,title,created,tags"]);
+    let rows: Vec<Vec<&str>> = table.lines().map(|line| line.split_whitespace().collect()).collect();
+    assert_eq!(
+        rows,
+        [
+            vec!["Size", "Title", "Created", "Tags"],
+            vec!["100", "B", "banana", "2021-01-01", "0"],
+            vec!["10", "B", "Apple", "2023-01-01", "0"],
+            vec!["1", "B", "cherry", "2022-01-01", "2"],
+        ]
+    );
+    assert_eq!(sandbox.run(&["list"]).lines().next(), Some("Title"));
+
+    let unknown = sandbox.command(&["list", "--fields", "title,colour"]).assert().code(1).get_output().stderr.clone();
+    let unknown = String::from_utf8(unknown).unwrap();
+    assert!(unknown.contains("colour") && unknown.contains("title, id, created, updated, tags, size"), "{unknown}");
+}
+
+#[test]
+fn archived_notes_leave_the_default_list() {
+    let sandbox = fruit_sandbox();
+    sandbox.run(&["archive", "banana"]);
+    assert_eq!(listed(&sandbox, &[]), ["Apple", "cherry"]);
+    assert_eq!(listed(&sandbox, &["--archived"]), ["banana"]);
+    assert_eq!(listed(&sandbox, &["--all"]), ["banana", "Apple", "cherry"]);
+    // Unlike the trash, an archived note still reads as usual
+    assert_eq!(sandbox.run(&["read", "banana", "--raw"]), "b".repeat(100));
+
+    sandbox.run(&["unarchive", "banana"]);
+    assert_eq!(listed(&sandbox, &[]), ["banana", "Apple", "cherry"]);
+    assert!(listed(&sandbox, &["--archived"]).is_empty());
+}
+
+#[test]
+fn links_resolve_against_the_vault_and_show_as_backlinks() {
+    let sandbox = Sandbox::new();
+    sandbox.run(&["new", "Index", "Router: [[Wifi]], money: [[Bank]]"]);
+    sandbox.run(&["new", "Wifi", "hunter2, see [[Index]]"]);
+
+    let read = sandbox.run(&["read", "Index", "--resolve-links"]);
+    assert!(read.contains("✅ Wifi") && read.contains("❌ Bank (no such note)"), "{read}");
+    let backlinks = sandbox.run(&["backlinks", "Index"]);
+    assert!(backlinks.contains("Wifi") && !backlinks.contains("📌 Index"), "{backlinks}");
+}
+
+#[test]
+fn a_password_prompt_without_a_terminal_fails_fast() {
+    let sandbox = Sandbox::new();
+    let vault = sandbox.vault();
+    let started = std::time::Instant::now();
+    // The timeout only stops a hang from stalling the test run; the prompt must fail on its own
+    let output = sandbox
+        .bare(&["--vault", vault.to_str().unwrap(), "list"])
+        .write_stdin("")
+        .timeout(std::time::Duration::from_secs(30))
+        .assert()
+        .code(1)
+        .get_output()
+        .stderr
+        .clone();
+    assert!(started.elapsed() < std::time::Duration::from_secs(10));
+    assert!(String::from_utf8(output).unwrap().contains("no terminal to ask on; pass --password-stdin or --password-file"));
+}